@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use lightyear::prelude::ClientId;
+
+/// Limits the number of players in the world.
+/// Clients that connect while the world is full wait in a queue, and are admitted in the order
+/// they connected as slots free up
+#[derive(Resource, Default)]
+pub(crate) struct AdmissionQueue {
+    /// Maximum number of admitted clients, `None` for no limit
+    pub max_players: Option<usize>,
+    /// Maximum number of clients admitted in a single call to `admit_waiting`, so that a burst
+    /// of connections is spread over several frames. `None` for no limit
+    pub max_admissions_per_frame: Option<usize>,
+    admitted: HashSet<ClientId>,
+    waiting: VecDeque<ClientId>,
+}
+
+impl AdmissionQueue {
+    pub(crate) fn new(max_players: Option<usize>) -> Self {
+        Self {
+            max_players,
+            ..default()
+        }
+    }
+
+    /// A client connected: it waits in the queue until `admit_waiting` admits it
+    pub(crate) fn enqueue(&mut self, client_id: ClientId) {
+        if !self.admitted.contains(&client_id) && !self.waiting.contains(&client_id) {
+            self.waiting.push_back(client_id);
+        }
+    }
+
+    /// A client disconnected. Returns true if it was admitted, false if it was still waiting
+    pub(crate) fn remove(&mut self, client_id: ClientId) -> bool {
+        self.waiting.retain(|waiting| *waiting != client_id);
+        self.admitted.remove(&client_id)
+    }
+
+    /// Put all the admitted clients back at the front of the queue, for example when the world is
+    /// reset and they need a new player. They are admitted again before the clients that were
+    /// already waiting
+    pub(crate) fn readmit_all(&mut self) {
+        let mut admitted: Vec<ClientId> = self.admitted.drain().collect();
+        // keep the order stable from run to run
+        admitted.sort_by_key(|client_id| client_id.to_bits());
+        for client_id in admitted.into_iter().rev() {
+            self.waiting.push_front(client_id);
+        }
+    }
+
+    /// Admit as many waiting clients as there are free slots (up to `max_admissions_per_frame`),
+    /// returning them in admission order
+    pub(crate) fn admit_waiting(&mut self) -> Vec<ClientId> {
+        let free = self
+            .max_players
+            .map_or(usize::MAX, |max| max.saturating_sub(self.admitted.len()));
+        let count = free
+            .min(self.max_admissions_per_frame.unwrap_or(usize::MAX))
+            .min(self.waiting.len());
+        let admitted: Vec<ClientId> = self.waiting.drain(..count).collect();
+        self.admitted.extend(admitted.iter().copied());
+        admitted
+    }
+
+    /// Position of a waiting client in the queue, starting at 1 for the next client to be admitted
+    pub(crate) fn position(&self, client_id: ClientId) -> Option<usize> {
+        self.waiting
+            .iter()
+            .position(|waiting| *waiting == client_id)
+            .map(|index| index + 1)
+    }
+
+    /// The waiting clients, along with their position in the queue
+    pub(crate) fn waiting(&self) -> impl Iterator<Item = (ClientId, usize)> + '_ {
+        self.waiting
+            .iter()
+            .enumerate()
+            .map(|(index, client_id)| (*client_id, index + 1))
+    }
+
+    pub(crate) fn is_admitted(&self, client_id: ClientId) -> bool {
+        self.admitted.contains(&client_id)
+    }
+
+    pub(crate) fn num_admitted(&self) -> usize {
+        self.admitted.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(id: u64) -> ClientId {
+        ClientId::Netcode(id)
+    }
+
+    #[test]
+    fn clients_over_the_limit_wait_for_a_free_slot() {
+        let mut queue = AdmissionQueue::new(Some(2));
+        for id in 1..=4 {
+            queue.enqueue(client(id));
+        }
+        assert_eq!(queue.admit_waiting(), vec![client(1), client(2)]);
+        assert_eq!(queue.position(client(3)), Some(1));
+        assert_eq!(queue.position(client(4)), Some(2));
+        assert!(queue.admit_waiting().is_empty());
+
+        assert!(queue.remove(client(1)));
+        assert_eq!(queue.admit_waiting(), vec![client(3)]);
+        assert_eq!(queue.position(client(4)), Some(1));
+        assert_eq!(queue.num_admitted(), 2);
+    }
+
+    #[test]
+    fn at_most_max_admissions_per_frame_clients_are_admitted_at_once() {
+        let mut queue = AdmissionQueue {
+            max_admissions_per_frame: Some(2),
+            ..default()
+        };
+        for id in 1..=5 {
+            queue.enqueue(client(id));
+        }
+        assert_eq!(queue.admit_waiting(), vec![client(1), client(2)]);
+        assert_eq!(queue.admit_waiting(), vec![client(3), client(4)]);
+        assert_eq!(queue.admit_waiting(), vec![client(5)]);
+        assert_eq!(queue.num_admitted(), 5);
+    }
+
+    #[test]
+    fn removing_a_waiting_client_does_not_free_a_slot() {
+        let mut queue = AdmissionQueue::new(Some(1));
+        queue.enqueue(client(1));
+        queue.enqueue(client(2));
+        queue.admit_waiting();
+        assert!(!queue.remove(client(2)));
+        assert_eq!(queue.waiting().count(), 0);
+        assert!(queue.is_admitted(client(1)));
+    }
+
+    #[test]
+    fn enqueueing_twice_has_no_effect() {
+        let mut queue = AdmissionQueue::default();
+        queue.enqueue(client(1));
+        queue.enqueue(client(1));
+        assert_eq!(queue.admit_waiting(), vec![client(1)]);
+        queue.enqueue(client(1));
+        assert!(queue.admit_waiting().is_empty());
+    }
+
+    #[test]
+    fn readmitted_clients_are_admitted_before_the_waiting_ones() {
+        let mut queue = AdmissionQueue::new(Some(2));
+        for id in [2, 1, 3] {
+            queue.enqueue(client(id));
+        }
+        queue.admit_waiting();
+        queue.readmit_all();
+        assert_eq!(queue.num_admitted(), 0);
+        assert_eq!(
+            queue.waiting().collect::<Vec<_>>(),
+            vec![(client(1), 1), (client(2), 2), (client(3), 3)]
+        );
+        assert_eq!(queue.admit_waiting(), vec![client(1), client(2)]);
+    }
+}
@@ -1,20 +1,85 @@
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy::window::PrimaryWindow;
 use leafwing_input_manager::prelude::*;
 
 pub use lightyear::prelude::client::*;
 use lightyear::prelude::*;
 
 use crate::protocol::*;
-use crate::shared::shared_movement_behaviour;
+use crate::shared::{
+    next_velocity, shared_movement_behaviour, CircleFade, DrawSaturation, FixedTickPosition,
+    SmoothedPosition,
+};
+
+/// Number of ticks without any update after which `ShowStaleEntities` outlines an entity
+const STALE_AFTER_TICKS: u64 = 5 * 64;
 
 pub struct ExampleClientPlugin;
 
 impl Plugin for ExampleClientPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<ActionState<Inputs>>();
+        app.init_resource::<WorldOccupancy>();
+        app.init_resource::<PreferredInterestRadius>();
+        app.init_resource::<PreferredName>();
+        app.init_resource::<ServerWorldConfig>();
+        app.init_resource::<PredictedAxes>();
+        app.init_resource::<ShowInterestRadius>();
+        app.init_resource::<ShowPredictionGhosts>();
+        app.init_resource::<ShowStaleEntities>();
+        app.init_resource::<CircleFadeSettings>();
+        app.init_resource::<ClickToMove>();
+        app.init_resource::<ReplicationTickCounter>();
+        app.init_resource::<CorrectionBudget>();
+        app.init_resource::<ServerTimeEstimate>();
+        app.init_resource::<AudioRelevantSources>();
+        app.init_resource::<DestructionSettings>();
+        app.init_resource::<OutgoingChat>();
+        app.init_resource::<ServerHeartbeat>();
+        app.init_resource::<ExtrapolationLimit>();
+        app.init_resource::<LocalScore>();
+        app.init_resource::<LatestLeaderboard>();
         app.add_systems(Startup, init);
         app.add_systems(PreUpdate, handle_connection.after(MainSet::Receive));
-        app.add_systems(FixedUpdate, movement);
+        app.add_systems(
+            PreUpdate,
+            (
+                stamp_replicated::<PlayerId>,
+                stamp_replicated::<Position>,
+                stamp_replicated::<PlayerColor>,
+                stamp_replicated::<PlayerText>,
+                stamp_replicated::<PlayerName>,
+                stamp_replicated::<CurrentRoom>,
+                stamp_replicated::<InputSnapshot>,
+                stamp_replicated::<CircleMarker>,
+                stamp_replicated::<CircleActive>,
+                stamp_replicated::<PlayerParent>,
+                stamp_replicated::<Metadata>,
+                stamp_replicated::<Team>,
+                stamp_replicated::<Velocity>,
+                stamp_replicated::<Health>,
+                stamp_replicated::<Score>,
+                stamp_replicated::<Shielded>,
+            )
+                .after(MainSet::Receive),
+        );
+        app.add_systems(FixedFirst, count_replication_ticks);
+        app.add_systems(
+            FixedUpdate,
+            (movement, sample_interpolated_positions),
+        );
+        // the fixed schedules also run while re-simulating ticks during a rollback,
+        // but the smoothing must only happen for the real ticks
+        app.add_systems(
+            FixedPreUpdate,
+            detect_predicted_corrections.run_if(not(is_in_rollback)),
+        );
+        app.add_systems(
+            FixedPostUpdate,
+            smooth_predicted_corrections.run_if(not(is_in_rollback)),
+        );
         app.add_systems(
             Update,
             (
@@ -22,9 +87,40 @@ impl Plugin for ExampleClientPlugin {
                 handle_predicted_spawn,
                 handle_interpolated_spawn,
                 handle_spawn,
-                player_text_changed
+                receive_text_edits.before(player_text_changed),
+                player_text_changed,
+                (
+                    receive_occupancy,
+                    receive_server_time,
+                    receive_heartbeat,
+                    receive_world_config,
+                    receive_circle_grid,
+                    receive_queue_position,
+                    receive_audio_hints,
+                    receive_entity_destroyed,
+                    receive_chat,
+                    receive_leaderboard,
+                ),
+                animate_destructions,
+                (send_interest_radius, send_name, send_chat, send_debug_commands),
+                update_local_score,
+                animate_circle_fades,
+                send_move_target,
+                mark_owned_entities,
             ),
         );
+        app.add_systems(
+            Update,
+            draw_interest_radius.run_if(|show: Res<ShowInterestRadius>| show.0),
+        );
+        app.add_systems(
+            Update,
+            draw_prediction_ghosts.run_if(|show: Res<ShowPredictionGhosts>| show.0),
+        );
+        app.add_systems(
+            Update,
+            draw_stale_entities.run_if(|show: Res<ShowStaleEntities>| show.0),
+        );
     }
 }
 
@@ -41,6 +137,7 @@ pub(crate) fn handle_connection(
 ) {
     for event in connection_event.read() {
         let client_id = event.client_id();
+        commands.insert_resource(LocalClientId(client_id));
         commands.spawn(TextBundle::from_section(
             format!("Client {}", client_id),
             TextStyle {
@@ -52,15 +149,458 @@ pub(crate) fn handle_connection(
     }
 }
 
+/// Interest radius that we would like the server to use for us.
+/// `None` means that we use the server's default
+#[derive(Resource, Default)]
+pub(crate) struct PreferredInterestRadius(pub Option<f32>);
+
+/// Send our preferred interest radius when we connect, or whenever it changes
+pub(crate) fn send_interest_radius(
+    preferred: Res<PreferredInterestRadius>,
+    mut connection_events: EventReader<ConnectEvent>,
+    mut connection_manager: ResMut<ConnectionManager>,
+) {
+    let connected = connection_events.read().count() > 0;
+    // the resource is 'changed' when it is first added, but we are not connected yet at that point
+    let changed = preferred.is_changed() && !preferred.is_added();
+    if !connected && !changed {
+        return;
+    }
+    if let Some(radius) = preferred.0 {
+        if let Err(e) =
+            connection_manager.send_message::<Channel1, _>(&SetInterestRadius(radius))
+        {
+            error!("Failed to send the interest radius: {:?}", e);
+        }
+    }
+}
+
+/// Marker for the circles spawned locally from a `GridDescriptor`
+#[derive(Component, Clone, Copy, Debug)]
+pub(crate) struct LocalCircle;
+
+/// Spawn the circles of the grid described by the server, replacing the ones from a previous grid
+pub(crate) fn receive_circle_grid(
+    mut commands: Commands,
+    mut messages: EventReader<MessageEvent<GridDescriptor>>,
+    local_circles: Query<Entity, With<LocalCircle>>,
+) {
+    let Some(message) = messages.read().last() else {
+        return;
+    };
+    for entity in local_circles.iter() {
+        commands.entity(entity).despawn();
+    }
+    for position in message.message().positions() {
+        commands.spawn((Position(position), CircleMarker, CircleActive(true), LocalCircle));
+    }
+}
+
+/// The last `WorldConfig` sent by the server, `None` until our player is spawned
+#[derive(Resource, Default)]
+pub(crate) struct ServerWorldConfig(pub Option<WorldConfig>);
+
+pub(crate) fn receive_world_config(
+    mut config: ResMut<ServerWorldConfig>,
+    mut messages: EventReader<MessageEvent<WorldConfig>>,
+) {
+    if let Some(message) = messages.read().last() {
+        config.0 = Some(message.message().clone());
+    }
+}
+
+/// Debug toggle: draw the interest radius that the server uses for us around our player,
+/// to see why entities appear and disappear
+#[derive(Resource, Default)]
+pub(crate) struct ShowInterestRadius(pub bool);
+
+pub(crate) fn draw_interest_radius(
+    mut gizmos: Gizmos,
+    config: Res<ServerWorldConfig>,
+    players: Query<&Position, (With<IsOwned>, With<Predicted>)>,
+) {
+    let Some(config) = &config.0 else {
+        return;
+    };
+    for position in players.iter() {
+        gizmos.circle_2d(position.0, config.interest_radius, Color::WHITE.with_alpha(0.3));
+    }
+}
+
+/// Debug toggle: draw ghosts of our player at its current predicted position and at the position
+/// it will be predicted at on the next tick with the current inputs, to visualize prediction
+#[derive(Resource, Default)]
+pub(crate) struct ShowPredictionGhosts(pub bool);
+
+/// Where our predicted player will be on the next tick if the inputs don't change
+pub(crate) fn predict_next_position(
+    position: Vec2,
+    velocity: Vec2,
+    input: &ActionState<Inputs>,
+) -> Vec2 {
+    position + next_velocity(velocity, input, 1.0)
+}
+
+pub(crate) fn draw_prediction_ghosts(
+    mut gizmos: Gizmos,
+    players: Query<
+        (&Position, &Velocity, &ActionState<Inputs>),
+        (With<IsOwned>, With<Predicted>),
+    >,
+) {
+    for (position, velocity, input) in players.iter() {
+        let next = predict_next_position(position.0, velocity.0, input);
+        gizmos.rect_2d(position.0, 0.0, Vec2::ONE * 50.0, Color::WHITE.with_alpha(0.3));
+        gizmos.rect_2d(next, 0.0, Vec2::ONE * 50.0, Color::WHITE.with_alpha(0.6));
+    }
+}
+
+/// Display name that we would like to use. `None` means that we don't have a name
+#[derive(Resource, Default)]
+pub(crate) struct PreferredName(pub Option<String>);
+
+/// Send our preferred name when we connect, or whenever it changes
+pub(crate) fn send_name(
+    preferred: Res<PreferredName>,
+    mut connection_events: EventReader<ConnectEvent>,
+    mut connection_manager: ResMut<ConnectionManager>,
+) {
+    let connected = connection_events.read().count() > 0;
+    // the resource is 'changed' when it is first added, but we are not connected yet at that point
+    let changed = preferred.is_changed() && !preferred.is_added();
+    if !connected && !changed {
+        return;
+    }
+    if let Some(name) = &preferred.0 {
+        if let Err(e) = connection_manager.send_message::<Channel1, _>(&SetName(name.clone())) {
+            error!("Failed to send the name: {:?}", e);
+        }
+    }
+}
+
+/// Chat lines waiting to be sent to the server
+#[derive(Resource, Default)]
+pub(crate) struct OutgoingChat(pub Vec<String>);
+
+pub(crate) fn send_chat(
+    mut outgoing: ResMut<OutgoingChat>,
+    local_client_id: Option<Res<LocalClientId>>,
+    mut connection_manager: ResMut<ConnectionManager>,
+) {
+    // wait until we are connected
+    let Some(local_client_id) = local_client_id else {
+        return;
+    };
+    for text in outgoing.0.drain(..) {
+        let message = ChatMessage {
+            sender: local_client_id.0,
+            text,
+        };
+        if let Err(e) = connection_manager.send_message::<Channel1, _>(&message) {
+            error!("Failed to send the chat message: {:?}", e);
+        }
+    }
+}
+
+/// Log the chat messages of the players in our room
+pub(crate) fn receive_chat(mut messages: EventReader<MessageEvent<ChatMessage>>) {
+    for message in messages.read() {
+        let chat = message.message();
+        info!("Client {} says: {}", chat.sender, chat.text);
+    }
+}
+
+/// Debug keys: `P` pauses or resumes our replication and `F` follows the next player
+pub(crate) fn send_debug_commands(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut connection_manager: ResMut<ConnectionManager>,
+) {
+    let commands = [
+        (KeyCode::KeyP, DebugCommand::TogglePause),
+        (KeyCode::KeyF, DebugCommand::FollowNext),
+    ];
+    for (key, command) in commands {
+        if !keys.just_pressed(key) {
+            continue;
+        }
+        if let Err(e) = connection_manager.send_message::<Channel1, _>(&command) {
+            error!("Failed to send the debug command: {:?}", e);
+        }
+    }
+}
+
+/// When enabled, clicking somewhere makes the server move our player towards that position
+#[derive(Resource, Default)]
+pub(crate) struct ClickToMove(pub bool);
+
+pub(crate) fn send_move_target(
+    click_to_move: Res<ClickToMove>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mut connection_manager: ResMut<ConnectionManager>,
+) {
+    if !click_to_move.0 || !mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+    let Some(cursor) = windows.get_single().ok().and_then(|window| window.cursor_position()) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = cameras.get_single() else {
+        return;
+    };
+    if let Some(target) = camera.viewport_to_world_2d(camera_transform, cursor) {
+        if let Err(e) = connection_manager.send_message::<Channel1, _>(&MoveTarget(target)) {
+            error!("Failed to send the move target: {:?}", e);
+        }
+    }
+}
+
+/// Our own `ClientId`, known once we are connected
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct LocalClientId(pub ClientId);
+
+/// Marker for the replicated entities that are controlled by the local client
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct IsOwned;
+
+/// Tag with `IsOwned` the entities whose `PlayerId` is the local client
+/// (this covers the Confirmed entity as well as its Predicted copy)
+pub(crate) fn mark_owned_entities(
+    mut commands: Commands,
+    local_client_id: Option<Res<LocalClientId>>,
+    players: Query<(Entity, &PlayerId), Without<IsOwned>>,
+) {
+    let Some(local_client_id) = local_client_id else {
+        return;
+    };
+    for (entity, player_id) in players.iter() {
+        if player_id.0 == local_client_id.0 {
+            commands.entity(entity).insert(IsOwned);
+        }
+    }
+}
+
+/// Number of fixed ticks since the client started
+#[derive(Resource, Default)]
+pub(crate) struct ReplicationTickCounter(pub u64);
+
+pub(crate) fn count_replication_ticks(mut counter: ResMut<ReplicationTickCounter>) {
+    counter.0 += 1;
+}
+
+/// The last tick (from `ReplicationTickCounter`) at which one of the entity's components was
+/// inserted or updated from the network
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct LastReplicatedTick(pub u64);
+
+/// Stamp the entities that received an insert or an update for the component `C`
+pub(crate) fn stamp_replicated<C: Component>(
+    mut commands: Commands,
+    counter: Res<ReplicationTickCounter>,
+    mut inserts: EventReader<ComponentInsertEvent<C>>,
+    mut updates: EventReader<ComponentUpdateEvent<C>>,
+) {
+    let entities = inserts
+        .read()
+        .map(|event| event.entity())
+        .chain(updates.read().map(|event| event.entity()));
+    for entity in entities {
+        if let Some(mut entity_commands) = commands.get_entity(entity) {
+            entity_commands.insert(LastReplicatedTick(counter.0));
+        }
+    }
+}
+
+/// Find the replicated entities that haven't received any update recently
+#[derive(SystemParam)]
+pub(crate) struct StaleEntities<'w, 's> {
+    counter: Res<'w, ReplicationTickCounter>,
+    stamps: Query<'w, 's, (Entity, &'static LastReplicatedTick)>,
+}
+
+impl<'w, 's> StaleEntities<'w, 's> {
+    /// The entities that were last updated more than `max_age` ticks ago
+    pub(crate) fn iter(&self, max_age: u64) -> impl Iterator<Item = Entity> + '_ {
+        let current = self.counter.0;
+        self.stamps
+            .iter()
+            .filter(move |(_, stamp)| current.saturating_sub(stamp.0) > max_age)
+            .map(|(entity, _)| entity)
+    }
+}
+
+/// Debug toggle: outline the replicated entities that haven't received any update for
+/// `STALE_AFTER_TICKS` ticks, e.g. a `PlayerText` that stopped updating
+#[derive(Resource, Default)]
+pub(crate) struct ShowStaleEntities(pub bool);
+
+/// The updates are received on the confirmed entities, which have the last replicated `Position`
+pub(crate) fn draw_stale_entities(
+    mut gizmos: Gizmos,
+    stale: StaleEntities,
+    positions: Query<&Position>,
+    parents: Query<&PlayerParent>,
+) {
+    for entity in stale.iter(STALE_AFTER_TICKS) {
+        // the text entities are drawn on their player
+        let entity = parents.get(entity).map_or(entity, |parent| parent.0);
+        if let Ok(position) = positions.get(entity) {
+            gizmos.rect_2d(position.0, 0.0, Vec2::ONE * 60.0, Color::srgb(1.0, 0.2, 0.2));
+        }
+    }
+}
+
 // The client input only gets applied to predicted entities that we own
 // This works because we only predict the user's controlled entity.
 // If we were predicting more entities, we would have to only apply movement to the player owned one.
 pub(crate) fn movement(
+    axes: Res<PredictedAxes>,
     // TODO: maybe make prediction mode a separate component!!!
-    mut position_query: Query<(&mut Position, &ActionState<Inputs>), With<Predicted>>,
+    mut position_query: Query<(&mut Position, &mut Velocity, &ActionState<Inputs>), With<Predicted>>,
+) {
+    // the room properties are only known by the server, the corrections catch up with them
+    for (mut position, mut velocity, input) in position_query.iter_mut() {
+        let (position_before, velocity_before) = (position.0, velocity.0);
+        shared_movement_behaviour(position.reborrow(), velocity.reborrow(), input, 1.0);
+        // the local inputs don't move the authoritative axes, see `PredictedAxes`
+        if !axes.x {
+            position.x = position_before.x;
+            velocity.0.x = velocity_before.x;
+        }
+        if !axes.y {
+            position.y = position_before.y;
+            velocity.0.y = velocity_before.y;
+        }
+    }
+}
+
+/// Which axes of `Position` are predicted. The other axes are authoritative: `movement` doesn't
+/// change them, so the predicted entity only gets them from the confirmed server state when a
+/// rollback resets it, e.g. for a side-scroller where the server controls the vertical position.
+/// As long as the server doesn't change them either, the predicted history matches the confirmed
+/// state and they cause no rollbacks
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PredictedAxes {
+    pub x: bool,
+    pub y: bool,
+}
+
+impl Default for PredictedAxes {
+    fn default() -> Self {
+        Self { x: true, y: true }
+    }
+}
+
+/// How long an interpolated entity keeps moving with its last velocity when the server stops
+/// sending updates for it. After that it holds its position instead of drifting away from the truth
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub(crate) struct ExtrapolationLimit {
+    pub max_extrapolation: Duration,
+}
+
+impl Default for ExtrapolationLimit {
+    fn default() -> Self {
+        Self {
+            max_extrapolation: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Sample the interpolated positions at a fixed rate
+pub(crate) fn sample_interpolated_positions(
+    mut commands: Commands,
+    limit: Res<ExtrapolationLimit>,
+    fixed_time: Res<Time<Fixed>>,
+    mut interpolated: Query<
+        (
+            Entity,
+            &Position,
+            Option<&Velocity>,
+            Option<&mut FixedTickPosition>,
+        ),
+        With<Interpolated>,
+    >,
+) {
+    let max_ticks = (limit.max_extrapolation.as_secs_f32() / fixed_time.timestep().as_secs_f32())
+        .floor() as u32;
+    for (entity, position, velocity, fixed_position) in interpolated.iter_mut() {
+        match fixed_position {
+            Some(mut fixed_position) => {
+                // entities without a velocity (the circles) are never extrapolated
+                let velocity = velocity.map_or(Vec2::ZERO, |velocity| velocity.0);
+                fixed_position.sample(position.0, velocity, max_ticks);
+            }
+            None => {
+                commands
+                    .entity(entity)
+                    .insert(FixedTickPosition::new(position.0));
+            }
+        }
+    }
+}
+
+/// How server corrections of the predicted `Position` are displayed.
+/// Errors up to `snap_threshold` are applied immediately since they are imperceptible; larger
+/// errors are spread over several ticks, by at most `max_per_tick` per tick, to avoid teleports
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub(crate) struct CorrectionBudget {
+    pub snap_threshold: f32,
+    pub max_per_tick: f32,
+}
+
+impl Default for CorrectionBudget {
+    fn default() -> Self {
+        Self {
+            snap_threshold: 5.0,
+            max_per_tick: 20.0,
+        }
+    }
+}
+
+impl CorrectionBudget {
+    /// Reduce the remaining visual error by at most the budget for one tick
+    pub(crate) fn reduce(&self, error: Vec2) -> Vec2 {
+        if error.length() <= self.snap_threshold {
+            Vec2::ZERO
+        } else {
+            error - error.clamp_length_max(self.max_per_tick)
+        }
+    }
+}
+
+/// Rollbacks happen before the fixed update, so any change of the predicted position since the end
+/// of the previous tick is a correction: keep the entity where it was drawn and record the error
+pub(crate) fn detect_predicted_corrections(
+    mut predicted: Query<(&Position, &mut SmoothedPosition), With<Predicted>>,
+) {
+    for (position, mut smoothed) in predicted.iter_mut() {
+        let correction = position.0 - smoothed.last;
+        if correction != Vec2::ZERO {
+            smoothed.error -= correction;
+        }
+    }
+}
+
+/// The rendered position follows the predicted movement exactly, while the error from
+/// corrections is caught up according to the `CorrectionBudget`
+pub(crate) fn smooth_predicted_corrections(
+    mut commands: Commands,
+    budget: Res<CorrectionBudget>,
+    mut predicted: Query<(Entity, &Position, Option<&mut SmoothedPosition>), With<Predicted>>,
 ) {
-    for (position, input) in position_query.iter_mut() {
-        shared_movement_behaviour(position, input);
+    for (entity, position, smoothed) in predicted.iter_mut() {
+        let Some(mut smoothed) = smoothed else {
+            commands.entity(entity).insert(SmoothedPosition {
+                rendered: position.0,
+                error: Vec2::ZERO,
+                last: position.0,
+            });
+            continue;
+        };
+        smoothed.error = budget.reduce(smoothed.error);
+        smoothed.rendered = position.0 + smoothed.error;
+        smoothed.last = position.0;
     }
 }
 
@@ -79,12 +619,279 @@ pub(crate) fn add_input_map(
     }
 }
 
-// TODO - This isn't being called, but it does when not using Group Management?
+/// The latest occupancy grid received from the server
+#[derive(Resource, Default)]
+pub(crate) struct WorldOccupancy(pub Option<OccupancyGrid>);
+
+pub(crate) fn receive_occupancy(
+    mut occupancy: ResMut<WorldOccupancy>,
+    mut messages: EventReader<MessageEvent<OccupancyGrid>>,
+) {
+    if let Some(message) = messages.read().last() {
+        occupancy.0 = Some(message.message().clone());
+    }
+}
+
+/// How long it takes for a circle to fade in or out when its `CircleActive` state changes
+#[derive(Resource)]
+pub(crate) struct CircleFadeSettings {
+    pub duration: Duration,
+}
+
+impl Default for CircleFadeSettings {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_millis(300),
+        }
+    }
+}
+
+/// Crossfade circles towards their `CircleActive` state.
+/// The fade moves at a constant rate from its current value, so toggling again in the middle
+/// of a transition just reverses it instead of snapping
+pub(crate) fn animate_circle_fades(
+    mut commands: Commands,
+    settings: Res<CircleFadeSettings>,
+    time: Res<Time>,
+    mut circles: Query<(Entity, Ref<CircleActive>, Option<&mut CircleFade>)>,
+) {
+    let step = if settings.duration.is_zero() {
+        1.0
+    } else {
+        time.delta_seconds() / settings.duration.as_secs_f32()
+    };
+    for (entity, active, fade) in circles.iter_mut() {
+        let target = if active.0 { 1.0 } else { 0.0 };
+        match fade {
+            // don't animate circles that just became visible
+            None => {
+                commands.entity(entity).insert(CircleFade {
+                    value: target,
+                    target,
+                });
+            }
+            Some(mut fade) => {
+                if active.is_changed() {
+                    fade.target = target;
+                }
+                if fade.value != fade.target {
+                    let delta = (fade.target - fade.value).clamp(-step, step);
+                    fade.value += delta;
+                }
+            }
+        }
+    }
+}
+
+/// Estimate of the server's current tick, from the last `ServerTime` message
+#[derive(Resource, Default)]
+pub(crate) struct ServerTimeEstimate {
+    /// The tick contained in the last `ServerTime` message
+    pub last_tick: u64,
+    /// When we received it, from `Time::elapsed`
+    pub received_at: Duration,
+    /// Estimated time that the message took to reach us
+    pub one_way_delay: Duration,
+}
+
+impl ServerTimeEstimate {
+    /// The server tick that we estimate the server is at, at time `now`
+    pub(crate) fn current_tick(&self, now: Duration, tick_duration: Duration) -> f64 {
+        let elapsed = now.saturating_sub(self.received_at) + self.one_way_delay;
+        self.last_tick as f64 + elapsed.as_secs_f64() / tick_duration.as_secs_f64()
+    }
+}
+
+pub(crate) fn receive_server_time(
+    mut estimate: ResMut<ServerTimeEstimate>,
+    mut messages: EventReader<MessageEvent<ServerTime>>,
+    connection_manager: Res<ConnectionManager>,
+    time: Res<Time>,
+) {
+    if let Some(message) = messages.read().last() {
+        estimate.last_tick = message.message().tick;
+        estimate.received_at = time.elapsed();
+        // assume that the latency is symmetric
+        estimate.one_way_delay = connection_manager.rtt() / 2;
+    }
+}
+
+/// The last server tick received in a `Message1` heartbeat
+#[derive(Resource, Default)]
+pub(crate) struct ServerHeartbeat {
+    pub tick: Option<usize>,
+    /// When we received it, from `Time::elapsed`
+    pub received_at: Duration,
+}
+
+/// Record the server heartbeats. A heartbeat whose tick didn't advance means that the server's
+/// simulation stalled
+pub(crate) fn receive_heartbeat(
+    mut heartbeat: ResMut<ServerHeartbeat>,
+    mut messages: EventReader<MessageEvent<Message1>>,
+    time: Res<Time>,
+) {
+    for message in messages.read() {
+        let tick = message.message().0;
+        if heartbeat.tick.is_some_and(|last_tick| tick <= last_tick) {
+            warn!("The server is stalled at tick {}", tick);
+        }
+        heartbeat.tick = Some(tick);
+        heartbeat.received_at = time.elapsed();
+    }
+}
+
+/// Score of our own player, as last replicated by the server
+#[derive(Resource, Default)]
+pub(crate) struct LocalScore(pub u32);
+
+/// Read the score from the predicted copy of our player, since the server updates are copied there
+pub(crate) fn update_local_score(
+    mut local_score: ResMut<LocalScore>,
+    scores: Query<&Score, (Changed<Score>, With<Predicted>, With<PlayerId>)>,
+) {
+    for score in scores.iter() {
+        if score.0 != local_score.0 {
+            info!("Our score is now {}", score.0);
+        }
+        local_score.0 = score.0;
+    }
+}
+
+/// The last leaderboard broadcasted by the server
+#[derive(Resource, Default)]
+pub(crate) struct LatestLeaderboard(pub Vec<(ClientId, u32)>);
+
+pub(crate) fn receive_leaderboard(
+    mut leaderboard: ResMut<LatestLeaderboard>,
+    mut messages: EventReader<MessageEvent<Leaderboard>>,
+) {
+    for message in messages.read() {
+        leaderboard.0 = message.message().0.clone();
+    }
+}
+
+/// Sound emitters that we can hear but that are not replicated to us, as last sent by the server
+#[derive(Resource, Default)]
+pub(crate) struct AudioRelevantSources(pub Vec<AudioSourceHint>);
+
+pub(crate) fn receive_audio_hints(
+    mut sources: ResMut<AudioRelevantSources>,
+    mut messages: EventReader<MessageEvent<AudioRelevant>>,
+) {
+    if let Some(message) = messages.read().last() {
+        sources.0 = message.message().0.clone();
+    }
+}
+
+/// How long the destruction animation of an entity lasts, and how large it grows
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub(crate) struct DestructionSettings {
+    pub duration: Duration,
+    pub max_radius: f32,
+}
+
+impl Default for DestructionSettings {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_millis(400),
+            max_radius: 20.0,
+        }
+    }
+}
+
+/// A local effect drawn where an entity was destroyed
+#[derive(Component, Clone, Debug)]
+pub(crate) struct Destruction {
+    pub position: Vec2,
+    pub timer: Timer,
+}
+
+/// Start a destruction animation for each entity that the server destroyed.
+/// The entities that were only culled by interest management disappear without animation
+pub(crate) fn receive_entity_destroyed(
+    mut commands: Commands,
+    settings: Res<DestructionSettings>,
+    mut messages: EventReader<MessageEvent<EntityDestroyed>>,
+) {
+    for message in messages.read() {
+        commands.spawn(Destruction {
+            position: message.message().position,
+            timer: Timer::new(settings.duration, TimerMode::Once),
+        });
+    }
+}
+
+/// Draw the destructions as an expanding circle that fades out
+pub(crate) fn animate_destructions(
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+    settings: Res<DestructionSettings>,
+    time: Res<Time>,
+    mut destructions: Query<(Entity, &mut Destruction)>,
+) {
+    for (entity, mut destruction) in destructions.iter_mut() {
+        destruction.timer.tick(time.delta());
+        if destruction.timer.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let progress = destruction.timer.fraction();
+        gizmos.circle_2d(
+            destruction.position,
+            settings.max_radius * progress,
+            Color::WHITE.with_alpha(1.0 - progress),
+        );
+    }
+}
+
+/// The server is full and we are waiting to be admitted
+pub(crate) fn receive_queue_position(mut messages: EventReader<MessageEvent<QueuePosition>>) {
+    if let Some(message) = messages.read().last() {
+        info!("Waiting to join, position {} in the queue", message.message().0);
+    }
+}
+
+/// Apply the `TextEdit`s sent by the server to the confirmed `PlayerText`, and copy the new text to
+/// its predicted and interpolated entities.
+/// The edits don't go through replication, so lightyear's component sync doesn't copy them: with
+/// the `Simple` sync mode the displayed text never changed
+pub(crate) fn receive_text_edits(
+    mut messages: EventReader<MessageEvent<TextEdit>>,
+    mut texts: Query<(&mut PlayerText, &Confirmed)>,
+    mut copies: Query<&mut PlayerText, Without<Confirmed>>,
+) {
+    for message in messages.read() {
+        let edit = message.message();
+        let Ok((mut player_text, confirmed)) = texts.get_mut(edit.entity) else {
+            continue;
+        };
+        // the edit was computed from another text, e.g. we received the new text with the spawn
+        if !edit.apply(&mut player_text.0) {
+            debug!("Ignoring a text edit that doesn't match the text of {:?}", edit.entity);
+            continue;
+        }
+        for copy in [confirmed.predicted, confirmed.interpolated].into_iter().flatten() {
+            if let Ok(mut copy_text) = copies.get_mut(copy) {
+                copy_text.0.clone_from(&player_text.0);
+            }
+        }
+    }
+}
+
+/// Update the displayed text when the `PlayerText` changes.
+/// The `Text` is not on the text entity itself: `handle_spawn` inserts it on the parent player
 pub(crate) fn player_text_changed(
-    mut player_query: Query<(&PlayerText, &mut Text), Changed<PlayerText>>,
+    player_query: Query<
+        (&PlayerParent, &PlayerText),
+        (Changed<PlayerText>, Or<(With<Predicted>, With<Interpolated>)>),
+    >,
+    mut texts: Query<&mut Text>,
 ) {
-    for (player_text, mut text) in player_query.iter_mut() {
-        text.sections[0].value = player_text.0.clone();
+    for (parent, player_text) in player_query.iter() {
+        if let Ok(mut text) = texts.get_mut(parent.0) {
+            text.sections[0].value = player_text.0.clone();
+        }
     }
 }
 
@@ -111,27 +918,394 @@ pub(crate) fn handle_spawn(
 }
 
 // When the predicted copy of the client-owned entity is spawned, do stuff
-// - assign it a different saturation
-pub(crate) fn handle_predicted_spawn(mut predicted: Query<&mut PlayerColor, Added<Predicted>>) {
-    for mut color in predicted.iter_mut() {
-        let hsva = Hsva {
-            saturation: 0.4,
-            ..Hsva::from(color.0)
-        };
-        color.0 = Color::from(hsva);
+// - draw it with a different saturation
+pub(crate) fn handle_predicted_spawn(
+    mut commands: Commands,
+    predicted: Query<Entity, (Added<Predicted>, With<PlayerColor>)>,
+) {
+    for entity in predicted.iter() {
+        commands.entity(entity).insert(DrawSaturation(0.4));
     }
 }
 
-// When the predicted copy of the client-owned entity is spawned, do stuff
-// - assign it a different saturation
+// When the interpolated copy of the client-owned entity is spawned, do stuff
+// - draw it with a different saturation
 pub(crate) fn handle_interpolated_spawn(
-    mut interpolated: Query<&mut PlayerColor, Added<Interpolated>>,
+    mut commands: Commands,
+    interpolated: Query<Entity, (Added<Interpolated>, With<PlayerColor>)>,
 ) {
-    for mut color in interpolated.iter_mut() {
-        let hsva = Hsva {
-            saturation: 0.1,
-            ..Hsva::from(color.0)
+    for entity in interpolated.iter() {
+        commands.entity(entity).insert(DrawSaturation(0.1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::shared::color_from_team;
+    use crate::test_utils::Stepper;
+
+    #[test]
+    fn text_edits_update_the_predicted_and_interpolated_texts() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_event::<MessageEvent<TextEdit>>();
+        app.add_systems(Update, receive_text_edits);
+
+        let predicted = app.world_mut().spawn(PlayerText("Player 1".to_string())).id();
+        let interpolated = app.world_mut().spawn(PlayerText("Player 1".to_string())).id();
+        let confirmed = app
+            .world_mut()
+            .spawn((
+                PlayerText("Player 1".to_string()),
+                Confirmed {
+                    predicted: Some(predicted),
+                    interpolated: Some(interpolated),
+                    tick: Tick(0),
+                },
+            ))
+            .id();
+
+        let edit = TextEdit::diff(confirmed, "Player 1", "Server changed").unwrap();
+        app.world_mut().send_event(MessageEvent::new(edit.clone(), ()));
+        app.update();
+        for entity in [confirmed, predicted, interpolated] {
+            assert_eq!(app.world().get::<PlayerText>(entity).unwrap().0, "Server changed");
+        }
+
+        // the same edit received again is ignored
+        app.world_mut().send_event(MessageEvent::new(edit, ()));
+        app.update();
+        assert_eq!(app.world().get::<PlayerText>(confirmed).unwrap().0, "Server changed");
+    }
+
+    #[test]
+    fn local_inputs_only_move_the_predicted_axes() {
+        let mut world = World::new();
+        world.insert_resource(PredictedAxes { x: true, y: false });
+        let mut input = ActionState::<Inputs>::default();
+        input.press(&Inputs::Right);
+        input.press(&Inputs::Up);
+        let player = world
+            .spawn((
+                Position(Vec2::new(10.0, 20.0)),
+                Velocity(Vec2::ZERO),
+                input,
+                Predicted {
+                    confirmed_entity: None,
+                },
+            ))
+            .id();
+
+        for _ in 0..5 {
+            world.run_system_once(movement);
+        }
+        let position = world.get::<Position>(player).unwrap();
+        assert!(position.x > 10.0);
+        assert_eq!(position.y, 20.0);
+        assert_eq!(world.get::<Velocity>(player).unwrap().0.y, 0.0);
+    }
+
+    #[test]
+    fn entities_without_recent_updates_are_stale() {
+        let mut world = World::new();
+        world.insert_resource(ReplicationTickCounter(100));
+        world.spawn(LastReplicatedTick(90));
+        let stale = world.spawn(LastReplicatedTick(10)).id();
+
+        let stale_entities =
+            world.run_system_once(|stale: StaleEntities| stale.iter(50).collect::<Vec<_>>());
+        assert_eq!(stale_entities, vec![stale]);
+    }
+
+    #[test]
+    fn toggling_a_circle_during_its_fade_reverses_it() {
+        let mut world = World::new();
+        world.insert_resource(CircleFadeSettings {
+            duration: Duration::from_millis(100),
+        });
+        world.init_resource::<Time>();
+        // registered once, so that it keeps track of the `CircleActive` changes between runs
+        let animate = world.register_system(animate_circle_fades);
+        let circle = world.spawn(CircleActive(true)).id();
+        let run = |world: &mut World| {
+            world.resource_mut::<Time>().advance_by(Duration::from_millis(25));
+            world.run_system(animate).unwrap();
+            world.get::<CircleFade>(circle).unwrap().value
+        };
+        // circles that just became visible don't fade in
+        assert_eq!(run(&mut world), 1.0);
+
+        world.get_mut::<CircleActive>(circle).unwrap().0 = false;
+        assert_eq!(run(&mut world), 0.75);
+        assert_eq!(run(&mut world), 0.5);
+        world.get_mut::<CircleActive>(circle).unwrap().0 = true;
+        assert_eq!(run(&mut world), 0.75);
+        assert_eq!(run(&mut world), 1.0);
+        assert_eq!(run(&mut world), 1.0);
+    }
+
+    #[test]
+    fn the_entities_of_the_local_player_are_owned() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        stepper.frame_steps(20);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let world = stepper.client_app(a).world_mut();
+        world.insert_resource(LocalClientId(a));
+        world.run_system_once(mark_owned_entities);
+
+        let mut players = world.query::<(&PlayerId, Has<IsOwned>, Has<Confirmed>)>();
+        let players: Vec<_> = players.iter(world).collect();
+        assert!(players.iter().any(|(player_id, _, confirmed)| player_id.0 == b && *confirmed));
+        for (player_id, owned, _) in players.iter() {
+            assert_eq!(*owned, player_id.0 == a, "ownership of the player of {}", player_id.0);
+        }
+        // the confirmed entity and its predicted copy
+        assert_eq!(players.iter().filter(|(_, owned, _)| *owned).count(), 2);
+    }
+
+    #[test]
+    fn predicted_and_interpolated_players_have_the_color_of_their_team() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        stepper.frame_steps(20);
+        let clients = [ClientId::Netcode(1), ClientId::Netcode(2)];
+        for observer in clients {
+            let world = stepper.client_app(observer).world_mut();
+            let mut players =
+                world.query::<(&PlayerId, &Team, &PlayerColor, Has<Predicted>, Has<Interpolated>)>();
+            let players: Vec<_> = players.iter(world).collect();
+            for (player_id, team, color, ..) in players.iter() {
+                assert_eq!(
+                    *color,
+                    &PlayerColor::new(color_from_team(team.0)),
+                    "{} sees the wrong color for {}",
+                    observer,
+                    player_id.0
+                );
+            }
+            // the local player is predicted, the other one is interpolated
+            assert!(players.iter().any(|(id, _, _, predicted, _)| id.0 == observer && *predicted));
+            assert!(players
+                .iter()
+                .any(|(id, _, _, _, interpolated)| id.0 != observer && *interpolated));
+        }
+    }
+
+    #[test]
+    fn player_texts_can_be_replicated_with_the_full_sync_mode() {
+        let full = SyncModes::both(ComponentSyncMode::Full);
+        let mut stepper = Stepper::with(&[1, 2], |app| {
+            app.insert_resource(ProtocolConfig {
+                player_text: full,
+                ..default()
+            });
+        });
+        stepper.frame_steps(20);
+        let client_id = ClientId::Netcode(1);
+        assert_ne!(ProtocolConfig::default().player_text, full);
+        let server_config = stepper.server_app.world().resource::<ProtocolConfig>();
+        assert_eq!(server_config.player_text, full);
+        let client_config = stepper.client_app(client_id).world().resource::<ProtocolConfig>();
+        assert_eq!(client_config.player_text, full);
+
+        // the texts are still replicated, to the predicted and interpolated copies as well
+        let world = stepper.client_app(client_id).world_mut();
+        let mut texts = world.query::<(&PlayerText, Has<Predicted>, Has<Interpolated>)>();
+        let texts: Vec<_> = texts.iter(world).collect();
+        assert!(texts.iter().any(|(_, predicted, _)| *predicted));
+        assert!(texts.iter().any(|(_, _, interpolated)| *interpolated));
+    }
+
+    #[test]
+    fn large_corrections_are_spread_over_several_ticks() {
+        let mut world = World::new();
+        world.init_resource::<CorrectionBudget>();
+        let player = world
+            .spawn((
+                Position(Vec2::ZERO),
+                Predicted {
+                    confirmed_entity: None,
+                },
+                SmoothedPosition {
+                    rendered: Vec2::ZERO,
+                    error: Vec2::ZERO,
+                    last: Vec2::ZERO,
+                },
+            ))
+            .id();
+        let tick = |world: &mut World| {
+            world.run_system_once(detect_predicted_corrections);
+            world.run_system_once(smooth_predicted_corrections);
+            world.get::<SmoothedPosition>(player).unwrap().rendered
+        };
+
+        // a rollback moved the player by 50, more than the 20 allowed per tick
+        world.get_mut::<Position>(player).unwrap().0 = Vec2::new(50.0, 0.0);
+        assert_eq!(tick(&mut world), Vec2::new(20.0, 0.0));
+        assert_eq!(tick(&mut world), Vec2::new(40.0, 0.0));
+        // the remaining 10 fit in the budget of one tick
+        assert_eq!(tick(&mut world), Vec2::new(50.0, 0.0));
+
+        // small corrections snap
+        world.get_mut::<Position>(player).unwrap().0 = Vec2::new(53.0, 0.0);
+        assert_eq!(tick(&mut world), Vec2::new(53.0, 0.0));
+    }
+
+    #[test]
+    fn the_server_tick_estimate_accounts_for_the_rtt() {
+        let tick_duration = Duration::from_micros(15_625);
+        let rtt = Duration::from_millis(100);
+        // the server sends its tick 100 at `sent_at`, on its own clock
+        let sent_at = Duration::from_secs(10);
+        let server_tick_at = |time: Duration| 100.0 + (time - sent_at).as_secs_f64() / 0.015_625;
+        let received_at = sent_at + rtt / 2;
+        let estimate = ServerTimeEstimate {
+            last_tick: 100,
+            received_at,
+            one_way_delay: rtt / 2,
         };
-        color.0 = Color::from(hsva);
+        for since_reception in [0, 16, 250, 1000] {
+            let now = received_at + Duration::from_millis(since_reception);
+            let estimated = estimate.current_tick(now, tick_duration);
+            assert!(
+                (estimated - server_tick_at(now)).abs() < 0.5,
+                "estimated {} instead of {}",
+                estimated,
+                server_tick_at(now)
+            );
+        }
+        // ignoring the RTT would be more than 3 ticks late
+        let without_rtt = ServerTimeEstimate {
+            one_way_delay: Duration::ZERO,
+            ..estimate
+        };
+        assert!(
+            server_tick_at(received_at) - without_rtt.current_tick(received_at, tick_duration) > 3.0
+        );
+    }
+
+    #[test]
+    fn the_server_tick_is_received_as_a_heartbeat() {
+        use crate::server::{HeartbeatTimer, ServerTicks};
+
+        let client_id = ClientId::Netcode(1);
+        let mut stepper = Stepper::new(&[1]);
+        let client_app = stepper.client_app(client_id);
+        client_app.init_resource::<ServerHeartbeat>();
+        client_app.add_systems(Update, receive_heartbeat);
+        // the heartbeat is sent once per second
+        let mut sent_tick = None;
+        for _ in 0..100 {
+            stepper.frame_step();
+            let server = stepper.server_app.world();
+            if server.resource::<HeartbeatTimer>().0.just_finished() {
+                sent_tick = Some(server.resource::<ServerTicks>().tick as usize);
+                break;
+            }
+        }
+        let sent_tick = sent_tick.expect("a heartbeat was sent");
+        stepper.frame_steps(10);
+        let heartbeat = stepper.client_app(client_id).world().resource::<ServerHeartbeat>();
+        assert_eq!(heartbeat.tick, Some(sent_tick));
+    }
+
+    #[test]
+    fn interpolated_entities_stop_extrapolating_after_the_limit() {
+        let mut world = World::new();
+        // 4 ticks of extrapolation
+        world.insert_resource(ExtrapolationLimit {
+            max_extrapolation: Duration::from_millis(100),
+        });
+        world.insert_resource(Time::<Fixed>::from_duration(Duration::from_millis(25)));
+        let sample = world.register_system(sample_interpolated_positions);
+        let entity = world
+            .spawn((
+                Position(Vec2::ZERO),
+                Velocity(Vec2::new(10.0, 0.0)),
+                Interpolated {
+                    confirmed_entity: Entity::PLACEHOLDER,
+                },
+            ))
+            .id();
+        let run = |world: &mut World| {
+            world.run_system(sample).unwrap();
+            world.get::<FixedTickPosition>(entity).unwrap().current.x
+        };
+        assert_eq!(run(&mut world), 0.0);
+        // the updates stall: the entity keeps its velocity, then holds its position
+        let positions: Vec<f32> = (0..8).map(|_| run(&mut world)).collect();
+        assert_eq!(positions, [10.0, 20.0, 30.0, 40.0, 40.0, 40.0, 40.0, 40.0]);
+        // and snaps back to the interpolated position on the next update
+        world.get_mut::<Position>(entity).unwrap().0 = Vec2::new(5.0, 0.0);
+        assert_eq!(run(&mut world), 5.0);
+        assert_eq!(run(&mut world), 15.0);
+    }
+
+    #[test]
+    fn the_client_sees_its_incremented_score() {
+        let mut stepper = Stepper::new(&[1]);
+        let client_id = ClientId::Netcode(1);
+        stepper
+            .server_app
+            .world_mut()
+            .send_event(crate::server::ScoreEvent { client: client_id });
+        stepper.frame_steps(20);
+        stepper.assert_replicated::<Score>(client_id, client_id);
+
+        let world = stepper.client_app(client_id).world_mut();
+        world.init_resource::<LocalScore>();
+        world.run_system_once(update_local_score);
+        assert_eq!(world.resource::<LocalScore>().0, 1);
+    }
+
+    #[test]
+    fn texts_set_on_the_server_reach_the_confirmed_texts_of_the_clients() {
+        use crate::outbox::Outbox;
+        use crate::server::{set_player_text, Global, RoomViewers};
+
+        let mut stepper = Stepper::new(&[1, 2]);
+        for (_, client_app) in stepper.client_apps.iter_mut() {
+            client_app.add_systems(Update, receive_text_edits);
+        }
+        stepper.frame_steps(20);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let global = stepper.server_app.world().resource::<Global>();
+        let player = global.client_id_to_entity_id[&a];
+        let text = global.player_to_text_entity[&player];
+        stepper.server_app.world_mut().run_system_once(
+            move |mut texts: Query<&mut PlayerText>,
+                  rooms: Query<&CurrentRoom>,
+                  viewers: RoomViewers,
+                  mut outbox: ResMut<Outbox>| {
+                let room = rooms.get(player).unwrap().0;
+                let mut player_text = texts.get_mut(text).unwrap();
+                set_player_text(
+                    text,
+                    &mut player_text,
+                    "Server changed",
+                    room,
+                    &viewers,
+                    &mut outbox,
+                );
+            },
+        );
+        stepper.frame_steps(20);
+
+        for observer in [a, b] {
+            let world = stepper.client_app(observer).world_mut();
+            let mut players = world.query_filtered::<(Entity, &PlayerId), With<Confirmed>>();
+            let (player, _) = players
+                .iter(world)
+                .find(|(_, player_id)| player_id.0 == a)
+                .expect("the player is replicated");
+            let mut texts = world.query_filtered::<(&PlayerParent, &PlayerText), With<Confirmed>>();
+            let (_, text) = texts
+                .iter(world)
+                .find(|(parent, _)| parent.0 == player)
+                .expect("the text is replicated");
+            assert_eq!(text.0, "Server changed", "{} sees a different text", observer);
+        }
     }
 }
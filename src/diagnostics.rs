@@ -0,0 +1,465 @@
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, DiagnosticsStore, RegisterDiagnostic};
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use lightyear::prelude::*;
+
+use crate::protocol::{CircleMarker, GroupStrategy, PlayerId, PlayerParent};
+use crate::server::{Global, PausedClients, RelevantCircles};
+
+/// How many entities are kept in the relevance leaderboard
+const LEADERBOARD_SIZE: usize = 5;
+/// How often the relevance leaderboard is recomputed
+const LEADERBOARD_INTERVAL_SECS: f32 = 1.0;
+/// How often we check for replication group splits
+const GROUP_SPLITS_INTERVAL_SECS: f32 = 1.0;
+/// How often we count the replication groups that no client can see
+const DARK_GROUPS_INTERVAL_SECS: f32 = 5.0;
+/// How often the histogram of replication group sizes is recomputed
+const GROUP_SIZES_INTERVAL_SECS: f32 = 5.0;
+/// How often the relevance change counters are rolled over and logged
+const RELEVANCE_METRICS_INTERVAL_SECS: f32 = 1.0;
+/// Number of frames over which the time spent in interest management is averaged
+const INTEREST_TIME_HISTORY_LENGTH: usize = 120;
+
+/// Time spent in `interest_management` during a frame, in milliseconds
+pub(crate) const INTEREST_MANAGEMENT_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("interest_management/time");
+
+// Plugin for server-side diagnostics that help tune interest management
+pub struct ServerDiagnosticsPlugin;
+
+impl Plugin for ServerDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RelevanceLeaderboard>();
+        app.init_resource::<GroupSplits>();
+        app.init_resource::<DarkGroups>();
+        app.init_resource::<GroupSizeHistogram>();
+        app.init_resource::<RelevanceMetrics>();
+        app.register_diagnostic(
+            Diagnostic::new(INTEREST_MANAGEMENT_TIME)
+                .with_suffix("ms")
+                .with_max_history_length(INTEREST_TIME_HISTORY_LENGTH),
+        );
+        app.add_systems(
+            Update,
+            (
+                update_relevance_leaderboard,
+                detect_group_splits,
+                detect_dark_groups,
+                update_group_size_histogram,
+                log_relevance_metrics,
+            ),
+        );
+    }
+}
+
+/// The entities that are relevant to the most clients, sorted by descending number of clients.
+/// These are the replication hotspots
+#[derive(Resource)]
+pub(crate) struct RelevanceLeaderboard {
+    pub timer: Timer,
+    pub top: Vec<(Entity, usize)>,
+}
+
+impl Default for RelevanceLeaderboard {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(LEADERBOARD_INTERVAL_SECS, TimerMode::Repeating),
+            top: Vec::new(),
+        }
+    }
+}
+
+/// Count for each entity the number of clients it is relevant to, and return the `k` entities
+/// with the highest count
+pub(crate) fn top_k_relevant(relevant_circles: &RelevantCircles, k: usize) -> Vec<(Entity, usize)> {
+    let mut counts: HashMap<Entity, usize> = HashMap::default();
+    for entities in relevant_circles.0.values() {
+        for entity in entities {
+            *counts.entry(*entity).or_default() += 1;
+        }
+    }
+    let mut sorted: Vec<(Entity, usize)> = counts.into_iter().collect();
+    // ties are broken by entity so that the leaderboard is stable between runs
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    sorted.truncate(k);
+    sorted
+}
+
+pub(crate) fn update_relevance_leaderboard(
+    mut leaderboard: ResMut<RelevanceLeaderboard>,
+    relevant_circles: Res<RelevantCircles>,
+    time: Res<Time>,
+) {
+    leaderboard.timer.tick(time.delta());
+    if !leaderboard.timer.just_finished() {
+        return;
+    }
+    leaderboard.top = top_k_relevant(&relevant_circles, LEADERBOARD_SIZE);
+    if !leaderboard.top.is_empty() {
+        info!("Most relevant entities: {:?}", leaderboard.top);
+    }
+}
+
+/// Relevance churn caused by interest management: the `gain_relevance` and `lose_relevance` calls
+/// and the room transitions. The counters of the current second are accumulated, then moved to
+/// `last_second` once per second
+#[derive(Resource)]
+pub(crate) struct RelevanceMetrics {
+    pub timer: Timer,
+    current: RelevanceCounts,
+    pub last_second: RelevanceCounts,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RelevanceCounts {
+    pub gains: usize,
+    pub losses: usize,
+    pub room_transitions: usize,
+}
+
+impl Default for RelevanceMetrics {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(RELEVANCE_METRICS_INTERVAL_SECS, TimerMode::Repeating),
+            current: RelevanceCounts::default(),
+            last_second: RelevanceCounts::default(),
+        }
+    }
+}
+
+impl RelevanceMetrics {
+    pub(crate) fn record_gain(&mut self) {
+        self.current.gains += 1;
+    }
+
+    pub(crate) fn record_loss(&mut self) {
+        self.current.losses += 1;
+    }
+
+    pub(crate) fn record_room_transition(&mut self) {
+        self.current.room_transitions += 1;
+    }
+}
+
+/// Log the relevance churn of the last second, with the average time spent in
+/// `interest_management`
+pub(crate) fn log_relevance_metrics(
+    mut metrics: ResMut<RelevanceMetrics>,
+    diagnostics: Res<DiagnosticsStore>,
+    time: Res<Time>,
+) {
+    metrics.timer.tick(time.delta());
+    if !metrics.timer.just_finished() {
+        return;
+    }
+    metrics.last_second = std::mem::take(&mut metrics.current);
+    let counts = metrics.last_second;
+    if counts != RelevanceCounts::default() {
+        info!(
+            "Relevance churn: {} gains/s, {} losses/s, {} room transitions/s, interest management {:.3}ms",
+            counts.gains,
+            counts.losses,
+            counts.room_transitions,
+            interest_management_average_ms(&diagnostics).unwrap_or_default(),
+        );
+    }
+}
+
+/// Pairs of (child, parent) entities that should be in the same replication group but aren't.
+/// When that happens the child's updates aren't applied together with the parent's
+#[derive(Resource)]
+pub(crate) struct GroupSplits {
+    pub timer: Timer,
+    pub splits: Vec<(Entity, Entity)>,
+}
+
+impl Default for GroupSplits {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(GROUP_SPLITS_INTERVAL_SECS, TimerMode::Repeating),
+            splits: Vec::new(),
+        }
+    }
+}
+
+pub(crate) fn detect_group_splits(
+    mut group_splits: ResMut<GroupSplits>,
+    group_strategy: Res<GroupStrategy>,
+    children: Query<(Entity, &PlayerParent, &ReplicationGroup)>,
+    groups: Query<&ReplicationGroup>,
+    time: Res<Time>,
+) {
+    group_splits.timer.tick(time.delta());
+    if !group_splits.timer.just_finished() {
+        return;
+    }
+    // with this strategy every entity is expected to be in its own group
+    if *group_strategy == GroupStrategy::PerEntity {
+        group_splits.splits.clear();
+        return;
+    }
+    let splits: Vec<(Entity, Entity)> = children
+        .iter()
+        .filter_map(|(child, parent, child_group)| {
+            let parent_group = groups.get(parent.0).ok()?;
+            (child_group.group_id(Some(child)) != parent_group.group_id(Some(parent.0)))
+                .then_some((child, parent.0))
+        })
+        .collect();
+    for (child, parent) in splits.iter() {
+        warn!(
+            ?child,
+            ?parent,
+            "Entity is not in the same replication group as its parent"
+        );
+    }
+    group_splits.splits = splits;
+}
+
+/// Replication groups that are not relevant to any client: they are still tracked by lightyear
+/// but nothing gets sent for them
+#[derive(Resource)]
+pub(crate) struct DarkGroups {
+    pub timer: Timer,
+    pub groups: Vec<u64>,
+}
+
+impl Default for DarkGroups {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(DARK_GROUPS_INTERVAL_SECS, TimerMode::Repeating),
+            groups: Vec::new(),
+        }
+    }
+}
+
+pub(crate) fn detect_dark_groups(
+    mut dark_groups: ResMut<DarkGroups>,
+    global: Res<Global>,
+    relevant_circles: Res<RelevantCircles>,
+    paused_clients: Res<PausedClients>,
+    entities: Query<(
+        Entity,
+        &ReplicationGroup,
+        Option<&PlayerId>,
+        Option<&PlayerParent>,
+        Has<CircleMarker>,
+    )>,
+    players: Query<&PlayerId>,
+    time: Res<Time>,
+) {
+    dark_groups.timer.tick(time.delta());
+    if !dark_groups.timer.just_finished() {
+        return;
+    }
+    // players (and their text) are visible to the clients in their room,
+    // circles are visible to the clients they are relevant to
+    let occupied_rooms: HashSet<_> = global
+        .client_id_to_room_id
+        .iter()
+        .filter(|(client_id, _)| !paused_clients.0.contains(*client_id))
+        .map(|(_, room)| *room)
+        .collect();
+    let visible_circles: HashSet<Entity> = relevant_circles.0.values().flatten().copied().collect();
+    let player_is_visible = |player_id: &PlayerId| {
+        global
+            .client_id_to_room_id
+            .get(&player_id.0)
+            .is_some_and(|room| occupied_rooms.contains(room))
+    };
+
+    let mut groups: HashMap<u64, bool> = HashMap::default();
+    for (entity, group, player_id, parent, is_circle) in entities.iter() {
+        let visible = if is_circle {
+            visible_circles.contains(&entity)
+        } else if let Some(player_id) = player_id {
+            player_is_visible(player_id)
+        } else if let Some(parent) = parent {
+            players.get(parent.0).is_ok_and(player_is_visible)
+        } else {
+            // we don't know how this entity is replicated
+            true
+        };
+        *groups.entry(group.group_id(Some(entity)).0).or_default() |= visible;
+    }
+    dark_groups.groups = groups
+        .into_iter()
+        .filter_map(|(group, visible)| (!visible).then_some(group))
+        .collect();
+    if !dark_groups.groups.is_empty() {
+        info!(
+            "{} replication groups are not relevant to any client",
+            dark_groups.groups.len()
+        );
+    }
+}
+
+/// Distribution of the number of entities per replication group.
+/// `buckets[i]` is the number of groups with between `2^i` and `2^(i+1) - 1` entities,
+/// so oversized groups show up in the last buckets
+#[derive(Resource)]
+pub(crate) struct GroupSizeHistogram {
+    pub timer: Timer,
+    pub buckets: Vec<usize>,
+    /// The group with the most entities, and its size
+    pub largest: Option<(u64, usize)>,
+}
+
+impl Default for GroupSizeHistogram {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(GROUP_SIZES_INTERVAL_SECS, TimerMode::Repeating),
+            buckets: Vec::new(),
+            largest: None,
+        }
+    }
+}
+
+/// Count the groups of each size in power-of-two buckets
+pub(crate) fn group_size_buckets(group_sizes: impl Iterator<Item = usize>) -> Vec<usize> {
+    let mut buckets = Vec::new();
+    for size in group_sizes.filter(|size| *size > 0) {
+        let bucket = size.ilog2() as usize;
+        if buckets.len() <= bucket {
+            buckets.resize(bucket + 1, 0);
+        }
+        buckets[bucket] += 1;
+    }
+    buckets
+}
+
+pub(crate) fn update_group_size_histogram(
+    mut histogram: ResMut<GroupSizeHistogram>,
+    entities: Query<(Entity, &ReplicationGroup)>,
+    time: Res<Time>,
+) {
+    histogram.timer.tick(time.delta());
+    if !histogram.timer.just_finished() {
+        return;
+    }
+    let mut sizes: HashMap<u64, usize> = HashMap::default();
+    for (entity, group) in entities.iter() {
+        *sizes.entry(group.group_id(Some(entity)).0).or_default() += 1;
+    }
+    histogram.buckets = group_size_buckets(sizes.values().copied());
+    // ties are broken by group id so that the result is stable between runs
+    histogram.largest = sizes
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)));
+    if let Some((group, size)) = histogram.largest {
+        debug!(
+            "Replication group sizes: {:?}, largest is {} with {} entities",
+            histogram.buckets, group, size
+        );
+    }
+}
+
+/// Rolling average of the time spent in `interest_management`, in milliseconds, over the last
+/// frames where it ran
+pub(crate) fn interest_management_average_ms(diagnostics: &DiagnosticsStore) -> Option<f64> {
+    diagnostics
+        .get(&INTEREST_MANAGEMENT_TIME)
+        .and_then(|diagnostic| diagnostic.average())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    fn circles_relevant_to_many_clients_rank_highest() {
+        let (hotspot, shared, alone) = (
+            Entity::from_raw(1),
+            Entity::from_raw(2),
+            Entity::from_raw(3),
+        );
+        let mut relevant_circles = RelevantCircles::default();
+        for (client, circles) in [
+            (1, vec![alone, hotspot]),
+            (2, vec![hotspot, shared]),
+            (3, vec![shared, hotspot]),
+            (4, vec![hotspot]),
+        ] {
+            relevant_circles
+                .0
+                .insert(ClientId::Netcode(client), circles.into_iter().collect());
+        }
+        assert_eq!(
+            top_k_relevant(&relevant_circles, 2),
+            vec![(hotspot, 4), (shared, 2)]
+        );
+        assert_eq!(top_k_relevant(&relevant_circles, 10).len(), 3);
+        assert!(top_k_relevant(&RelevantCircles::default(), 5).is_empty());
+    }
+
+    /// A world where the diagnostics timers finish on the next run
+    fn world_after(secs: u64) -> World {
+        let mut world = World::new();
+        let mut time = Time::<()>::default();
+        time.advance_by(bevy::utils::Duration::from_secs(secs));
+        world.insert_resource(time);
+        world
+    }
+
+    #[test]
+    fn children_outside_of_their_parent_group_are_detected() {
+        let mut world = world_after(1);
+        world.init_resource::<GroupSplits>();
+        world.insert_resource(GroupStrategy::SharedWithParent);
+        let parent = world.spawn(ReplicationGroup::default()).id();
+        let joined = world
+            .spawn((PlayerParent(parent), ReplicationGroup::default().set_id(parent.to_bits())))
+            .id();
+        // forced into its own group
+        let split = world
+            .spawn((PlayerParent(parent), ReplicationGroup::default()))
+            .id();
+
+        world.run_system_once(detect_group_splits);
+        let splits = &world.resource::<GroupSplits>().splits;
+        assert_eq!(splits, &vec![(split, parent)]);
+        assert!(!splits.iter().any(|(child, _)| *child == joined));
+    }
+
+    #[test]
+    fn circles_that_no_client_can_see_are_dark() {
+        let mut world = world_after(5);
+        world.init_resource::<DarkGroups>();
+        world.init_resource::<Global>();
+        world.init_resource::<PausedClients>();
+        let seen = world.spawn((CircleMarker, ReplicationGroup::default())).id();
+        let unseen = world.spawn((CircleMarker, ReplicationGroup::default())).id();
+        let mut relevant_circles = RelevantCircles::default();
+        relevant_circles
+            .0
+            .insert(ClientId::Netcode(1), [seen].into_iter().collect());
+        world.insert_resource(relevant_circles);
+
+        world.run_system_once(detect_dark_groups);
+        assert_eq!(world.resource::<DarkGroups>().groups, vec![unseen.to_bits()]);
+    }
+
+    #[test]
+    fn group_sizes_are_counted_in_power_of_two_buckets() {
+        assert_eq!(group_size_buckets([1, 1, 2, 3, 4, 7, 8, 0].into_iter()), vec![2, 2, 2, 1]);
+        assert!(group_size_buckets(std::iter::empty()).is_empty());
+
+        let mut world = world_after(5);
+        world.init_resource::<GroupSizeHistogram>();
+        let room = ReplicationGroup::default().set_id(7);
+        for _ in 0..5 {
+            world.spawn(room.clone());
+        }
+        for _ in 0..2 {
+            world.spawn(ReplicationGroup::default().set_id(3));
+        }
+        world.spawn(ReplicationGroup::default());
+        world.run_system_once(update_group_size_histogram);
+        let histogram = world.resource::<GroupSizeHistogram>();
+        // the groups of 1, 2 and 5 entities
+        assert_eq!(histogram.buckets, vec![1, 1, 1]);
+        assert_eq!(histogram.largest, Some((7, 5)));
+    }
+}
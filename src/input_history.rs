@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::ClientId;
+
+use crate::protocol::InputSnapshot;
+
+/// Number of records kept for each client by default
+pub(crate) const DEFAULT_HISTORY_LEN: usize = 64;
+
+/// A state of the inputs of a player, held from `since_tick` until the next record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct InputRecord {
+    pub snapshot: InputSnapshot,
+    pub since_tick: u64,
+}
+
+/// History of the inputs of each player, for logging and analytics.
+/// Consecutive identical input states are coalesced into a single record, so holding a key for
+/// many ticks only produces one record. Only the last `max_len` records of each client are kept
+#[derive(Resource)]
+pub(crate) struct InputHistory {
+    pub max_len: usize,
+    records: HashMap<ClientId, VecDeque<InputRecord>>,
+}
+
+impl Default for InputHistory {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_HISTORY_LEN,
+            records: HashMap::default(),
+        }
+    }
+}
+
+impl InputHistory {
+    /// Record the inputs of a client at `tick`. Returns true if they differ from its last record
+    pub(crate) fn record(&mut self, client_id: ClientId, snapshot: InputSnapshot, tick: u64) -> bool {
+        let records = self.records.entry(client_id).or_default();
+        if records.back().is_some_and(|last| last.snapshot == snapshot) {
+            return false;
+        }
+        records.push_back(InputRecord {
+            snapshot,
+            since_tick: tick,
+        });
+        while records.len() > self.max_len {
+            records.pop_front();
+        }
+        true
+    }
+
+    /// The records of a client, oldest first
+    pub(crate) fn records(&self, client_id: ClientId) -> impl Iterator<Item = &InputRecord> {
+        self.records.get(&client_id).into_iter().flatten()
+    }
+
+    pub(crate) fn remove_client(&mut self, client_id: ClientId) {
+        self.records.remove(&client_id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Inputs;
+
+    #[test]
+    fn holding_a_key_produces_a_single_record() {
+        let client_id = ClientId::Netcode(1);
+        let right = InputSnapshot(1 << Inputs::Right as u8);
+        let mut history = InputHistory::default();
+        assert!(history.record(client_id, InputSnapshot::default(), 0));
+        assert!(history.record(client_id, right, 3));
+        for tick in 4..100 {
+            assert!(!history.record(client_id, right, tick));
+        }
+        assert!(history.record(client_id, InputSnapshot::default(), 100));
+        let records: Vec<InputRecord> = history.records(client_id).copied().collect();
+        assert_eq!(
+            records,
+            [
+                InputRecord {
+                    snapshot: InputSnapshot::default(),
+                    since_tick: 0,
+                },
+                InputRecord {
+                    snapshot: right,
+                    since_tick: 3,
+                },
+                InputRecord {
+                    snapshot: InputSnapshot::default(),
+                    since_tick: 100,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_last_records_are_kept() {
+        let client_id = ClientId::Netcode(1);
+        let mut history = InputHistory {
+            max_len: 2,
+            ..default()
+        };
+        for tick in 0..5 {
+            history.record(client_id, InputSnapshot(tick as u8), tick);
+        }
+        let ticks: Vec<u64> = history.records(client_id).map(|record| record.since_tick).collect();
+        assert_eq!(ticks, [3, 4]);
+    }
+}
@@ -0,0 +1,158 @@
+use bevy::prelude::*;
+
+use crate::shared::room_id_for_position;
+
+/// Whether an entity should be replicated to a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Relevance {
+    Relevant,
+    NotRelevant,
+}
+
+impl From<bool> for Relevance {
+    fn from(relevant: bool) -> Self {
+        if relevant {
+            Relevance::Relevant
+        } else {
+            Relevance::NotRelevant
+        }
+    }
+}
+
+/// Decides whether an entity is relevant to a client, from the position of the client's player.
+/// `room_size` is the size of the rooms used by the server (see `InterestConfig`).
+/// Implement this to plug a custom strategy (view cone, teams, etc.) into `interest_management`
+pub(crate) trait InterestStrategy: Send + Sync + 'static {
+    fn evaluate(
+        &self,
+        player_position: Vec2,
+        entity_position: Vec2,
+        interest_radius: f32,
+        room_size: f32,
+    ) -> Relevance;
+
+    /// Entities further than this from the player are never relevant, which lets
+    /// `interest_management` only evaluate the entities found by the spatial index.
+    /// `None` if entities can be relevant at any distance
+    fn max_distance(&self, interest_radius: f32) -> Option<f32> {
+        Some(interest_radius)
+    }
+}
+
+/// The built-in strategies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum CircleRelevanceMode {
+    /// The entity is within the client's interest radius of the player.
+    /// The radius is exclusive: an entity at exactly the interest radius is not relevant
+    #[default]
+    DistanceOnly,
+    /// The entity is in the same room as the player
+    RoomOnly,
+    /// The entity is within the client's interest radius of the player and in the same room
+    DistanceAndRoom,
+}
+
+impl InterestStrategy for CircleRelevanceMode {
+    fn evaluate(
+        &self,
+        player_position: Vec2,
+        entity_position: Vec2,
+        interest_radius: f32,
+        room_size: f32,
+    ) -> Relevance {
+        let in_radius = || player_position.distance(entity_position) < interest_radius;
+        let same_room = || {
+            room_id_for_position(player_position, room_size)
+                == room_id_for_position(entity_position, room_size)
+        };
+        match self {
+            CircleRelevanceMode::DistanceOnly => in_radius(),
+            CircleRelevanceMode::RoomOnly => same_room(),
+            CircleRelevanceMode::DistanceAndRoom => in_radius() && same_room(),
+        }
+        .into()
+    }
+
+    fn max_distance(&self, interest_radius: f32) -> Option<f32> {
+        match self {
+            CircleRelevanceMode::RoomOnly => None,
+            CircleRelevanceMode::DistanceOnly | CircleRelevanceMode::DistanceAndRoom => {
+                Some(interest_radius)
+            }
+        }
+    }
+}
+
+/// The strategy used by `interest_management` to decide which circles are relevant to each client
+#[derive(Resource)]
+pub(crate) struct CircleInterestStrategy(pub Box<dyn InterestStrategy>);
+
+impl CircleInterestStrategy {
+    pub(crate) fn new(strategy: impl InterestStrategy) -> Self {
+        Self(Box::new(strategy))
+    }
+}
+
+impl Default for CircleInterestStrategy {
+    fn default() -> Self {
+        Self::new(CircleRelevanceMode::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f32 = 150.0;
+    const ROOM_SIZE: f32 = 200.0;
+
+    fn evaluate(mode: CircleRelevanceMode, player: Vec2, entity: Vec2) -> Relevance {
+        CircleInterestStrategy::new(mode)
+            .0
+            .evaluate(player, entity, RADIUS, ROOM_SIZE)
+    }
+
+    #[test]
+    fn each_mode_combines_the_distance_and_the_room() {
+        let player = Vec2::new(190.0, 10.0);
+        // close, but in the next room
+        let next_room = Vec2::new(210.0, 10.0);
+        // in the same room, but out of the radius
+        let far = Vec2::new(10.0, 190.0);
+        let close = Vec2::new(150.0, 10.0);
+        for (mode, expected) in [
+            (CircleRelevanceMode::DistanceOnly, [true, false, true]),
+            (CircleRelevanceMode::RoomOnly, [false, true, true]),
+            (CircleRelevanceMode::DistanceAndRoom, [false, false, true]),
+        ] {
+            for (entity, expected) in [next_room, far, close].into_iter().zip(expected) {
+                assert_eq!(
+                    evaluate(mode, player, entity),
+                    Relevance::from(expected),
+                    "{:?} at {}",
+                    mode,
+                    entity
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_interest_radius_is_exclusive() {
+        let player = Vec2::new(10.0, 10.0);
+        for mode in [CircleRelevanceMode::DistanceOnly, CircleRelevanceMode::DistanceAndRoom] {
+            // both are in the player's room, for `DistanceAndRoom`
+            let at_radius = player + Vec2::new(RADIUS, 0.0);
+            let inside = player + Vec2::new(RADIUS - 0.01, 0.0);
+            assert_eq!(evaluate(mode, player, at_radius), Relevance::NotRelevant);
+            assert_eq!(evaluate(mode, player, inside), Relevance::Relevant);
+        }
+    }
+
+    #[test]
+    fn modes_limited_by_the_radius_report_it_as_max_distance() {
+        assert_eq!(CircleRelevanceMode::DistanceOnly.max_distance(RADIUS), Some(RADIUS));
+        assert_eq!(CircleRelevanceMode::DistanceAndRoom.max_distance(RADIUS), Some(RADIUS));
+        assert_eq!(CircleRelevanceMode::RoomOnly.max_distance(RADIUS), None);
+    }
+}
@@ -1,6 +1,8 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(dead_code)]
+// `cargo +nightly bench --features bench` runs the `#[bench]` functions of the tests
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
 use crate::client::ExampleClientPlugin;
 use crate::server::ExampleServerPlugin;
 use crate::shared::SharedPlugin;
@@ -8,12 +10,44 @@ use bevy::prelude::*;
 use lightyear_examples_common::app::Apps;
 use lightyear_examples_common::settings::{read_settings, Settings};
 
+mod admission;
 mod client;
+mod diagnostics;
+mod input_history;
+mod interest;
+mod names;
+mod offline;
+mod outbox;
 mod protocol;
+mod replay;
+mod rooms;
+mod send_rate;
 mod server;
 mod shared;
+mod spatial;
+mod stress;
+mod tap;
+#[cfg(test)]
+mod test_utils;
+
+#[cfg(all(test, feature = "bench"))]
+extern crate test;
 
 fn main() {
+    // `cargo run -- offline` runs the game locally, without any networking
+    if std::env::args().nth(1).as_deref() == Some("offline") {
+        offline::run();
+        return;
+    }
+    // `cargo run -- stress [num_clients]` runs a headless server with simulated clients
+    if std::env::args().nth(1).as_deref() == Some("stress") {
+        let num_clients = std::env::args()
+            .nth(2)
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(stress::DEFAULT_NUM_CLIENTS);
+        stress::run(num_clients);
+        return;
+    }
     let cli = lightyear_examples_common::app::cli();
     let settings_str = include_str!("../assets/settings.ron");
     let settings = read_settings::<Settings>(settings_str);
@@ -0,0 +1,131 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::ClientId;
+
+/// Maximum length of a display name by default, in characters
+pub(crate) const DEFAULT_MAX_NAME_LENGTH: usize = 16;
+
+/// Checks the names requested by the clients, for example against a list of banned words.
+/// Implement this to plug a custom filter into the `NameValidation`
+pub(crate) trait NameFilter: Send + Sync + 'static {
+    /// The name to use, possibly sanitized, or `None` to reject it
+    fn filter(&self, name: &str) -> Option<String>;
+}
+
+/// Accepts every name unchanged
+pub(crate) struct AllowAllNames;
+
+impl NameFilter for AllowAllNames {
+    fn filter(&self, name: &str) -> Option<String> {
+        Some(name.to_string())
+    }
+}
+
+/// Why a requested name was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NameRejection {
+    Empty,
+    TooLong,
+    Filtered,
+    /// Another player already uses this name, ignoring case
+    Taken,
+}
+
+/// How the names requested with `SetName` are validated
+#[derive(Resource)]
+pub(crate) struct NameValidation {
+    pub max_length: usize,
+    pub filter: Box<dyn NameFilter>,
+}
+
+impl Default for NameValidation {
+    fn default() -> Self {
+        Self::new(AllowAllNames)
+    }
+}
+
+impl NameValidation {
+    pub(crate) fn new(filter: impl NameFilter) -> Self {
+        Self {
+            max_length: DEFAULT_MAX_NAME_LENGTH,
+            filter: Box::new(filter),
+        }
+    }
+
+    /// Sanitize a requested name and check it against the filter and the names already `taken`.
+    /// Control characters and surrounding whitespace are removed before anything else
+    pub(crate) fn validate<'a>(
+        &self,
+        requested: &str,
+        taken: impl IntoIterator<Item = &'a str>,
+    ) -> Result<String, NameRejection> {
+        let name: String = requested.chars().filter(|c| !c.is_control()).collect();
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(NameRejection::Empty);
+        }
+        if name.chars().count() > self.max_length {
+            return Err(NameRejection::TooLong);
+        }
+        let name = self.filter.filter(name).ok_or(NameRejection::Filtered)?;
+        let lowercase = name.to_lowercase();
+        if taken.into_iter().any(|other| other.to_lowercase() == lowercase) {
+            return Err(NameRejection::Taken);
+        }
+        Ok(name)
+    }
+}
+
+/// The validated name of each client, kept even while the client doesn't have a player
+#[derive(Resource, Default)]
+pub(crate) struct PlayerNames(pub HashMap<ClientId, String>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rejects the names containing a banned word, and capitalizes the others
+    struct BannedWord(&'static str);
+
+    impl NameFilter for BannedWord {
+        fn filter(&self, name: &str) -> Option<String> {
+            if name.to_lowercase().contains(self.0) {
+                return None;
+            }
+            let mut chars = name.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+        }
+    }
+
+    #[test]
+    fn names_are_sanitized_before_being_checked() {
+        let validation = NameValidation::default();
+        assert_eq!(validation.validate("  alice\n", []), Ok("alice".to_string()));
+        assert_eq!(validation.validate("bo\u{7}b", []), Ok("bob".to_string()));
+        assert_eq!(validation.validate(" \t\r", []), Err(NameRejection::Empty));
+        // the length is counted in characters, not bytes
+        assert!(validation.validate(&"é".repeat(DEFAULT_MAX_NAME_LENGTH), []).is_ok());
+        assert_eq!(
+            validation.validate(&"a".repeat(DEFAULT_MAX_NAME_LENGTH + 1), []),
+            Err(NameRejection::TooLong)
+        );
+    }
+
+    #[test]
+    fn names_are_unique_ignoring_case() {
+        let validation = NameValidation::default();
+        assert_eq!(validation.validate("Alice", ["bob", "ALICE"]), Err(NameRejection::Taken));
+        assert_eq!(validation.validate("Alice", ["bob"]), Ok("Alice".to_string()));
+    }
+
+    #[test]
+    fn custom_filters_can_reject_or_change_the_names() {
+        let validation = NameValidation::new(BannedWord("admin"));
+        assert_eq!(validation.validate("TheAdmin", []), Err(NameRejection::Filtered));
+        assert_eq!(validation.validate("carol", []), Ok("Carol".to_string()));
+        // the filtered name is the one compared to the taken names
+        assert_eq!(validation.validate("carol", ["Carol"]), Err(NameRejection::Taken));
+    }
+}
@@ -0,0 +1,85 @@
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::*;
+use lightyear::prelude::ClientId;
+
+use crate::protocol::*;
+use crate::shared::{shared_movement_behaviour, SharedRenderPlugin};
+
+// Plugin to try out the movement and the rendering locally, without a server
+pub struct OfflinePlugin;
+
+impl Plugin for OfflinePlugin {
+    fn build(&self, app: &mut App) {
+        // in the networked modes this is added by lightyear's `LeafwingInputPlugin`
+        app.add_plugins(InputManagerPlugin::<Inputs>::default());
+        app.add_plugins(SharedRenderPlugin);
+        app.add_systems(Startup, spawn_local_player);
+        app.add_systems(FixedUpdate, movement);
+    }
+}
+
+/// Run the app in offline mode
+pub(crate) fn run() {
+    App::new()
+        .add_plugins((DefaultPlugins, OfflinePlugin))
+        .run();
+}
+
+/// Marker for the player that is simulated locally
+#[derive(Component)]
+pub(crate) struct OfflinePlayer;
+
+pub(crate) fn spawn_local_player(mut commands: Commands) {
+    let id = ClientId::Local(0);
+    commands.spawn((
+        OfflinePlayer,
+        PlayerId(id),
+        Position(Vec2::ZERO),
+        Velocity(Vec2::ZERO),
+        PlayerColor::new(crate::shared::color_from_id(id)),
+        InputManagerBundle::<Inputs> {
+            action_state: ActionState::default(),
+            input_map: PlayerBundle::get_input_map(),
+        },
+        Transform::default(),
+    ));
+}
+
+/// Same movement as the one predicted by the client and run by the server
+pub(crate) fn movement(
+    mut position_query: Query<
+        (&mut Position, &mut Velocity, &ActionState<Inputs>),
+        With<OfflinePlayer>,
+    >,
+) {
+    for (position, velocity, input) in position_query.iter_mut() {
+        shared_movement_behaviour(position, velocity, input, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::step;
+
+    #[test]
+    fn the_local_player_moves_from_its_inputs() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins);
+        app.add_systems(Startup, spawn_local_player);
+        app.add_systems(FixedUpdate, movement);
+        app.update();
+        let player = app
+            .world_mut()
+            .query_filtered::<Entity, With<OfflinePlayer>>()
+            .single(app.world());
+
+        app.world_mut()
+            .get_mut::<ActionState<Inputs>>(player)
+            .unwrap()
+            .press(&Inputs::Right);
+        step(&mut app, 10);
+        let position = app.world().get::<Position>(player).unwrap().0;
+        assert!(position.x > 0.0 && position.y == 0.0, "{}", position);
+    }
+}
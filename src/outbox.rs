@@ -0,0 +1,203 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use serde::Serialize;
+
+use crate::protocol::Channel1;
+use crate::server::ReplicationErrorEvent;
+
+/// Maximum number of messages sent on `Channel1` per frame by default
+const DEFAULT_SEND_BUDGET: usize = 64;
+
+/// Priority of an outgoing message. Under congestion, higher priorities are sent first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum MessagePriority {
+    Low,
+    Normal,
+    High,
+}
+
+type SendFn = Box<dyn FnOnce(&mut ConnectionManager) -> Result<(), String> + Send + Sync>;
+
+struct QueuedMessage {
+    priority: MessagePriority,
+    /// estimated size of the message on the wire
+    bytes: usize,
+    /// used to keep messages of the same priority in the order they were queued
+    order: u64,
+    action: &'static str,
+    send: SendFn,
+}
+
+/// Queue of the messages to send on `Channel1`.
+/// At most `budget` messages are sent every frame, by descending priority; the remaining
+/// messages stay in the queue until the next frame
+#[derive(Resource)]
+pub(crate) struct Outbox {
+    pub budget: usize,
+    queue: Vec<QueuedMessage>,
+    next_order: u64,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self {
+            budget: DEFAULT_SEND_BUDGET,
+            queue: Vec::new(),
+            next_order: 0,
+        }
+    }
+}
+
+impl Outbox {
+    /// Queue a message to be sent to `target`. `action` describes the message in case sending fails
+    pub(crate) fn send<M: Message + Serialize>(
+        &mut self,
+        message: M,
+        target: NetworkTarget,
+        priority: MessagePriority,
+        action: &'static str,
+    ) {
+        self.queue.push(QueuedMessage {
+            priority,
+            bytes: estimate_size(&message),
+            order: self.next_order,
+            action,
+            send: Box::new(move |connection_manager: &mut ConnectionManager| {
+                connection_manager
+                    .send_message_to_target::<Channel1, M>(&message, target)
+                    .map_err(|e| format!("{:?}", e))
+            }),
+        });
+        self.next_order += 1;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Remove the messages that fit in the budget, highest priority first
+    fn drain_budget(&mut self) -> Vec<QueuedMessage> {
+        self.queue
+            .sort_by(|a, b| b.priority.cmp(&a.priority).then(a.order.cmp(&b.order)));
+        let count = self.budget.min(self.queue.len());
+        self.queue.drain(..count).collect()
+    }
+}
+
+/// Estimate the number of bytes a message takes on the wire, from its serialized size
+pub(crate) fn estimate_size<M: Serialize>(message: &M) -> usize {
+    bincode::serde::encode_to_vec(message, bincode::config::standard())
+        .map_or(0, |bytes| bytes.len())
+}
+
+/// Bytes sent through the `Outbox`, accumulated over the current second.
+/// This is an estimate from the serialized message sizes: it doesn't include the packet headers
+/// nor the entity replication traffic handled by lightyear
+#[derive(Resource)]
+pub(crate) struct NetworkThroughput {
+    timer: Timer,
+    current: usize,
+    last_second: usize,
+}
+
+impl Default for NetworkThroughput {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(1.0, TimerMode::Repeating),
+            current: 0,
+            last_second: 0,
+        }
+    }
+}
+
+impl NetworkThroughput {
+    pub(crate) fn record(&mut self, bytes: usize) {
+        self.current += bytes;
+    }
+
+    /// Advance the timer. Every second, the accumulated bytes become the new `bytes_per_second`
+    pub(crate) fn tick(&mut self, delta: Duration) {
+        self.timer.tick(delta);
+        if self.timer.just_finished() {
+            self.last_second = std::mem::take(&mut self.current);
+        }
+    }
+
+    /// Number of bytes sent during the last full second
+    pub(crate) fn bytes_per_second(&self) -> usize {
+        self.last_second
+    }
+}
+
+/// Send the queued messages that fit in this frame's budget
+pub(crate) fn flush_outbox(
+    mut outbox: ResMut<Outbox>,
+    mut throughput: ResMut<NetworkThroughput>,
+    mut connection_manager: ResMut<ConnectionManager>,
+    mut errors: EventWriter<ReplicationErrorEvent>,
+    time: Res<Time>,
+) {
+    throughput.tick(time.delta());
+    for message in outbox.drain_budget() {
+        match (message.send)(&mut connection_manager) {
+            Ok(()) => throughput.record(message.bytes),
+            Err(e) => {
+                errors.send(ReplicationErrorEvent::new(message.action, e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Message1;
+
+    #[test]
+    fn the_budget_sends_the_highest_priorities_first_in_queue_order() {
+        let mut outbox = Outbox {
+            budget: 3,
+            ..default()
+        };
+        for (priority, action) in [
+            (MessagePriority::Low, "low 1"),
+            (MessagePriority::High, "high 1"),
+            (MessagePriority::Normal, "normal 1"),
+            (MessagePriority::High, "high 2"),
+            (MessagePriority::Low, "low 2"),
+        ] {
+            outbox.send(Message1(0), NetworkTarget::All, priority, action);
+        }
+        let sent: Vec<_> = outbox.drain_budget().iter().map(|m| m.action).collect();
+        assert_eq!(sent, ["high 1", "high 2", "normal 1"]);
+        // the rest waits for the next frame, still in order
+        assert_eq!(outbox.len(), 2);
+        let sent: Vec<_> = outbox.drain_budget().iter().map(|m| m.action).collect();
+        assert_eq!(sent, ["low 1", "low 2"]);
+        assert_eq!(outbox.len(), 0);
+    }
+
+    #[test]
+    fn sizes_are_estimated_from_the_serialized_message() {
+        // bincode writes small integers in a single byte
+        assert_eq!(estimate_size(&Message1(5)), 1);
+        assert_eq!(estimate_size(&Message1(100_000)), 5);
+        // strings are prefixed by their length
+        assert_eq!(estimate_size(&"hello".to_string()), 6);
+    }
+
+    #[test]
+    fn throughput_is_reported_for_the_last_full_second() {
+        let mut throughput = NetworkThroughput::default();
+        throughput.record(100);
+        throughput.tick(Duration::from_millis(500));
+        assert_eq!(throughput.bytes_per_second(), 0);
+        throughput.record(50);
+        throughput.tick(Duration::from_millis(500));
+        assert_eq!(throughput.bytes_per_second(), 150);
+        throughput.tick(Duration::from_secs(1));
+        assert_eq!(throughput.bytes_per_second(), 0);
+    }
+}
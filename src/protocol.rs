@@ -3,6 +3,7 @@ use std::ops::{Add, Mul};
 use bevy::ecs::entity::MapEntities;
 use bevy::math::Vec2;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
 use leafwing_input_manager::action_state::ActionState;
 use leafwing_input_manager::input_map::InputMap;
 use leafwing_input_manager::prelude::Actionlike;
@@ -11,34 +12,115 @@ use serde::{Deserialize, Serialize};
 use tracing::info;
 
 use lightyear::client::components::ComponentSyncMode;
-use lightyear::prelude::server::{ControlledBy, Replicate, SyncTarget};
+use lightyear::prelude::server::{
+    ControlledBy, OverrideTargetComponent, Replicate, RoomId, SyncTarget,
+};
 use lightyear::prelude::*;
+use lightyear::protocol::serialize::SerializeFns;
+use lightyear::serialize::SerializationError;
 use lightyear::shared::replication::components::NetworkRelevanceMode;
 use UserAction;
 
-use crate::shared::color_from_id;
+use crate::shared::{color_from_team, PLAYER_MAX_HEALTH};
+
+/// What kind of entity is replicated, so that interest management and diagnostics can treat them differently.
+/// This is a server-only component
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ReplicationCategory {
+    /// Always replicated to the clients in the same room
+    Player,
+    /// Replicated exactly like its parent player
+    Text,
+    /// Replicated to the clients that are close enough
+    Circle,
+    /// Replicated to the clients that are close enough
+    Effect,
+    /// Never replicated
+    Debug,
+}
+
+impl ReplicationCategory {
+    /// Whether the entity's relevance is computed from its distance to each player
+    pub(crate) fn is_distance_culled(&self) -> bool {
+        matches!(self, ReplicationCategory::Circle | ReplicationCategory::Effect)
+    }
+}
 
 // Player
 #[derive(Bundle)]
 pub(crate) struct PlayerBundle {
+    category: ReplicationCategory,
     id: PlayerId,
+    team: Team,
     position: Position,
+    velocity: Velocity,
+    health: Health,
+    score: Score,
     last_position: LastPosition,
     color: PlayerColor,
+    current_room: CurrentRoom,
+    current_room_target: OverrideTargetComponent<CurrentRoom>,
     replicate: Replicate,
     action_state: ActionState<Inputs>,
 }
 
 #[derive(Bundle)]
 pub(crate) struct PlayerTextBundle {
+    category: ReplicationCategory,
     parent: PlayerParent,
     replicate: Replicate,
     player_text: PlayerText,
 }
 
+/// How the `ReplicationGroup` of each replicated entity gets assigned.
+/// Entities in the same group are replicated together, in a single message
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum GroupStrategy {
+    /// Every entity is in its own group
+    PerEntity,
+    /// Entities with a parent share the parent's group, other entities are in their own group
+    #[default]
+    SharedWithParent,
+    /// All entities spawned in the same room share a group
+    PerRoom,
+    /// All entities owned by the same client share a group, un-owned entities are in their own group
+    PerClient,
+}
+
+impl GroupStrategy {
+    /// Compute the `ReplicationGroup` for an entity about to be spawned
+    pub(crate) fn replication_group(
+        &self,
+        parent: Option<Entity>,
+        room: RoomId,
+        owner: Option<ClientId>,
+    ) -> ReplicationGroup {
+        // the default is: the replication group id is a u64 value generated from the entity (`entity.to_bits()`)
+        let group = ReplicationGroup::default();
+        match self {
+            GroupStrategy::PerEntity => group,
+            GroupStrategy::SharedWithParent => match parent {
+                Some(parent) => group.set_id(parent.to_bits()),
+                None => group,
+            },
+            GroupStrategy::PerRoom => group.set_id(room.0),
+            GroupStrategy::PerClient => match owner {
+                Some(owner) => group.set_id(owner.to_bits()),
+                None => group,
+            },
+        }
+    }
+}
+
 impl PlayerBundle {
-    pub(crate) fn new(id: ClientId, position: Vec2) -> Self {
-        let color = color_from_id(id);
+    pub(crate) fn new(
+        id: ClientId,
+        position: Vec2,
+        room: RoomId,
+        team: u8,
+        group_strategy: GroupStrategy,
+    ) -> Self {
+        let color = color_from_team(team);
         let replicate = Replicate {
             sync: SyncTarget {
                 prediction: NetworkTarget::Single(id),
@@ -48,17 +130,24 @@ impl PlayerBundle {
                 target: NetworkTarget::Single(id),
                 ..default()
             },
-            // the default is: the replication group id is a u64 value generated from the entity (`entity.to_bits()`)
-            group: ReplicationGroup::default(),
+            group: group_strategy.replication_group(None, room, Some(id)),
             // use network relevance for replication
             relevance_mode: NetworkRelevanceMode::InterestManagement,
             ..default()
         };
         Self {
+            category: ReplicationCategory::Player,
             id: PlayerId(id),
+            team: Team(team),
             position: Position(position),
+            velocity: Velocity(Vec2::ZERO),
+            health: Health::full(PLAYER_MAX_HEALTH),
+            score: Score(0),
             last_position: LastPosition(position),
-            color: PlayerColor(color),
+            color: PlayerColor::new(color),
+            current_room: CurrentRoom(room),
+            // only the owning client needs to know which room its player is in
+            current_room_target: OverrideTargetComponent::new(NetworkTarget::Single(id)),
             replicate,
             action_state: ActionState::default(),
         }
@@ -80,8 +169,14 @@ impl PlayerBundle {
 }
 
 impl PlayerTextBundle {
-    pub(crate) fn new(id: ClientId, parent: Entity) -> Self {
+    pub(crate) fn new(
+        id: ClientId,
+        parent: Entity,
+        room: RoomId,
+        group_strategy: GroupStrategy,
+    ) -> Self {
         Self {
+            category: ReplicationCategory::Text,
             parent: PlayerParent(parent),
             player_text: PlayerText("Server should change this...".to_string()),
             replicate: Replicate {
@@ -93,8 +188,11 @@ impl PlayerTextBundle {
                     target: NetworkTarget::Single(id),
                     ..default()
                 },
-                // replicate this entity within the same replication group as the parent
-                group: ReplicationGroup::default().set_id(parent.to_bits()),
+                // by default, replicate this entity within the same replication group as the parent
+                group: group_strategy.replication_group(Some(parent), room, Some(id)),
+                // the text must only be replicated to clients that the parent is relevant to,
+                // so it uses the same relevance mode and is moved between rooms with the parent
+                relevance_mode: NetworkRelevanceMode::InterestManagement,
                 ..default()
             },
         }
@@ -120,15 +218,192 @@ impl MapEntities for PlayerParent {
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerId(pub ClientId);
 
+/// The team of a player. Players of the same team have the same `PlayerColor`
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Team(pub u8);
+
+/// Position of an entity, in the game's Y-up coordinates.
+/// On the wire it is written in the `CoordinateSystem` of the `ProtocolConfig`, so that
+/// external tools reading the serialized messages get the axes they expect
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Deref, DerefMut)]
 pub struct Position(pub(crate) Vec2);
 
+/// Convention used for the y axis of the positions written on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum CoordinateSystem {
+    /// Same as the game: y grows upwards
+    #[default]
+    YUp,
+    /// y grows downwards, like screen coordinates
+    YDown,
+}
+
+impl CoordinateSystem {
+    /// Convert a position between the game's coordinates and this coordinate system
+    pub(crate) fn convert(self, position: Vec2) -> Vec2 {
+        match self {
+            CoordinateSystem::YUp => position,
+            CoordinateSystem::YDown => Vec2::new(position.x, -position.y),
+        }
+    }
+}
+
+impl CoordinateSystem {
+    /// The functions that replicate `Position` in this coordinate system. The `ProtocolPlugin`
+    /// registers the ones of its `ProtocolConfig`, so each app keeps its own coordinate system
+    pub(crate) fn position_serialize_fns(self) -> SerializeFns<Position> {
+        match self {
+            CoordinateSystem::YUp => SerializeFns {
+                serialize: |position, writer| {
+                    write_position(CoordinateSystem::YUp, position, writer)
+                },
+                deserialize: |reader| read_position(CoordinateSystem::YUp, reader),
+            },
+            CoordinateSystem::YDown => SerializeFns {
+                serialize: |position, writer| {
+                    write_position(CoordinateSystem::YDown, position, writer)
+                },
+                deserialize: |reader| read_position(CoordinateSystem::YDown, reader),
+            },
+        }
+    }
+}
+
+/// Write a `Position` on the wire in the given coordinate system
+fn write_position(
+    coordinate_system: CoordinateSystem,
+    position: &Position,
+    mut writer: impl std::io::Write,
+) -> Result<(), SerializationError> {
+    let wire = coordinate_system.convert(position.0);
+    bincode::serde::encode_into_std_write(wire, &mut writer, bincode::config::standard())?;
+    Ok(())
+}
+
+/// Read a `Position` written by `write_position` with the same coordinate system
+fn read_position(
+    coordinate_system: CoordinateSystem,
+    mut reader: impl std::io::Read,
+) -> Result<Position, SerializationError> {
+    let wire: Vec2 =
+        bincode::serde::decode_from_std_read(&mut reader, bincode::config::standard())?;
+    // the conversion is its own inverse
+    Ok(Position(coordinate_system.convert(wire)))
+}
+
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Deref, DerefMut)]
 pub struct LastPosition(pub(crate) Vec2);
 
+/// Distance travelled by a player per fixed tick. Updated from the inputs by `shared_movement_behaviour`
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Deref, DerefMut)]
+pub struct Velocity(pub Vec2);
+
+/// Health of a player. It goes down while the player overlaps other players, and never below 0
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub(crate) fn full(max: f32) -> Self {
+        Self { current: max, max }
+    }
+
+    /// Returns true if this damage brought the health to 0
+    pub(crate) fn damage(&mut self, amount: f32) -> bool {
+        let alive = self.current > 0.0;
+        self.current = (self.current - amount).max(0.0);
+        alive && self.current == 0.0
+    }
+
+    /// Interpolate the current health, the maximum is taken from the most recent value
+    pub(crate) fn lerp(start: &Health, other: &Health, t: f32) -> Health {
+        Health {
+            current: start.current + (other.current - start.current) * t,
+            max: other.max,
+        }
+    }
+}
+
+/// Points of a player. Only the server's `award_scores` changes it
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Score(pub u32);
+
+/// Text displayed above a player.
+/// On the server, only change it with `set_player_text`. A direct write is replicated as a full
+/// component update, which is not ordered with the `TextEdit` messages of `set_player_text`: an
+/// edit that arrives before the update it was computed from doesn't match the client's text and is
+/// dropped, so the client keeps a stale text
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerText(pub String);
 
+/// Display name of a player, chosen by its client and validated by the server
+#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlayerName(pub String);
+
+/// Marker for a shielded player, which neither takes nor deals collision damage.
+/// Like any replicated component, removing it on the server removes it on the clients as well
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Shielded;
+
+/// The room the player is currently in. Only replicated to the client that owns the player
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CurrentRoom(pub RoomId);
+
+/// Maximum total size of the keys and values of a `Metadata` component, in bytes
+pub(crate) const METADATA_MAX_BYTES: usize = 1024;
+
+/// Arbitrary small key/value data attached to an entity, to replicate ad-hoc data without
+/// defining a new component. Mutations are refused if they would go over `METADATA_MAX_BYTES`
+#[derive(Component, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Metadata(HashMap<String, String>);
+
+/// A `Metadata` mutation was refused because the component would have been too large
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetadataTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl Metadata {
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Set `key` to `value`, unless the total size would go over `METADATA_MAX_BYTES`
+    pub(crate) fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), MetadataTooLarge> {
+        let (key, value) = (key.into(), value.into());
+        let replaced = self.0.get(&key).map_or(0, |old| key.len() + old.len());
+        let size = self.size() - replaced + key.len() + value.len();
+        if size > METADATA_MAX_BYTES {
+            return Err(MetadataTooLarge {
+                size,
+                max: METADATA_MAX_BYTES,
+            });
+        }
+        self.0.insert(key, value);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Total size of the keys and values, in bytes
+    pub(crate) fn size(&self) -> usize {
+        self.0.iter().map(|(key, value)| key.len() + value.len()).sum()
+    }
+}
+
 impl Add for Position {
     type Output = Position;
     #[inline]
@@ -145,13 +420,85 @@ impl Mul<f32> for &Position {
     }
 }
 
-#[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
+impl Add for Velocity {
+    type Output = Velocity;
+    #[inline]
+    fn add(self, rhs: Velocity) -> Velocity {
+        Velocity(self.0.add(rhs.0))
+    }
+}
+
+impl Mul<f32> for &Velocity {
+    type Output = Velocity;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Velocity(self.0 * rhs)
+    }
+}
+
+/// Compact snapshot of the inputs currently pressed by a player, one bit per `Inputs` variant.
+/// This is replicated to spectators so that they can display what the watched player is pressing
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputSnapshot(pub u8);
+
+impl InputSnapshot {
+    pub(crate) fn from_action_state(action_state: &ActionState<Inputs>) -> Self {
+        let mut bits = 0;
+        for input in Inputs::ALL {
+            if action_state.pressed(&input) {
+                bits |= 1 << input as u8;
+            }
+        }
+        Self(bits)
+    }
+
+    pub(crate) fn is_pressed(&self, input: Inputs) -> bool {
+        self.0 & (1 << input as u8) != 0
+    }
+}
+
+/// Color of a player. On the wire it is packed in a u32 with 8 bits per sRGBA channel,
+/// which is plenty for a palette of player colors.
+/// Build it with `PlayerColor::new`, so that the server holds exactly the color that the clients decode
+#[derive(Component, Clone, Debug, PartialEq)]
 pub struct PlayerColor(pub(crate) Color);
 
+impl PlayerColor {
+    /// Quantize `color` to its wire representation: an sRGBA color with 8 bits per channel
+    pub(crate) fn new(color: Color) -> Self {
+        Self::unpack(PlayerColor(color).pack())
+    }
+
+    pub(crate) fn pack(&self) -> u32 {
+        u32::from_be_bytes(self.0.to_srgba().to_u8_array())
+    }
+
+    pub(crate) fn unpack(packed: u32) -> Self {
+        let [r, g, b, a] = packed.to_be_bytes();
+        PlayerColor(Color::srgba_u8(r, g, b, a))
+    }
+}
+
+impl Serialize for PlayerColor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.pack().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PlayerColor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(PlayerColor::unpack)
+    }
+}
+
 #[derive(Component, Deserialize, Serialize, Clone, Debug, PartialEq)]
 // Marker component
 pub struct CircleMarker;
 
+/// Whether a circle is currently active. Clients crossfade the circle when this changes
+#[derive(Component, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct CircleActive(pub bool);
+
 // Channels
 
 #[derive(Channel)]
@@ -162,6 +509,235 @@ pub struct Channel1;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message1(pub usize);
 
+/// Sent by a client to request a different interest radius.
+/// The server clamps it to the bounds it allows
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SetInterestRadius(pub f32);
+
+/// A line of text chat. Sent by a client, then relayed by the server to the other clients in the
+/// sender's room. The server overwrites `sender` with the client it received the message from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub sender: ClientId,
+    pub text: String,
+}
+
+/// Sent by a client to request a display name. The server may sanitize or reject it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SetName(pub String);
+
+/// Compact description of a static grid of circles, sent instead of replicating each circle.
+/// The circle at (`column`, `row`) is at `origin + spacing * (column, row)`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GridDescriptor {
+    pub origin: Vec2,
+    pub spacing: f32,
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl GridDescriptor {
+    /// The positions of all the circles of the grid, column by column
+    pub(crate) fn positions(&self) -> impl Iterator<Item = Vec2> + '_ {
+        (0..self.columns).flat_map(move |column| {
+            (0..self.rows).map(move |row| {
+                self.origin + self.spacing * Vec2::new(column as f32, row as f32)
+            })
+        })
+    }
+}
+
+/// Sent by the server to a client when its player spawns, and whenever one of these values changes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorldConfig {
+    /// The interest radius that the server uses for this client, after clamping
+    pub interest_radius: f32,
+}
+
+/// Periodically broadcasted by the server so that clients can estimate the server's current tick
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerTime {
+    /// Number of fixed ticks since the server started
+    pub tick: u64,
+}
+
+/// Sent by a client in click-to-move mode: the server moves the player towards this position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoveTarget(pub Vec2);
+
+/// Sent by a client to test relevance interactively, see `receive_debug_commands` on the server.
+/// Ignored unless the server allows them with `AllowDebugCommands`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum DebugCommand {
+    /// Pause the replication to the sender, or resume it if it was paused
+    TogglePause,
+    /// Follow the next player, or go back to our own player after the last one
+    FollowNext,
+}
+
+/// A sound-emitting entity that is close enough to be heard, but not close enough to be replicated
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct AudioSourceHint {
+    /// Identifies the entity across messages. It is the server entity, which is not mapped
+    /// since the entity doesn't exist on the client
+    pub id: u64,
+    pub position: Vec2,
+}
+
+/// Sent by the server whenever the set of audio-relevant entities of a client changes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AudioRelevant(pub Vec<AudioSourceHint>);
+
+/// Sent by the server to the clients that can see an entity right before it gets destroyed, so that
+/// they can animate the destruction. Entities that stop being replicated without this message
+/// were only culled by interest management
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EntityDestroyed {
+    pub position: Vec2,
+}
+
+/// The players with the highest `Score`, sorted by descending score. Broadcast by the server
+/// every second
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Leaderboard(pub Vec<(ClientId, u32)>);
+
+/// Sent by the server to a client waiting to be admitted, whenever its position in the queue changes.
+/// Position 1 is the next client to be admitted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QueuePosition(pub u32);
+
+/// Edit of the `PlayerText` of an entity, sent instead of replicating the whole string again.
+/// The bytes in `start..start + removed` are replaced by `inserted`, if the text is still the one
+/// the edit was computed from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TextEdit {
+    pub entity: Entity,
+    /// `text_hash` of the text the edit applies to
+    pub base: u64,
+    pub start: u32,
+    pub removed: u32,
+    pub inserted: String,
+}
+
+impl MapEntities for TextEdit {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        self.entity = entity_mapper.map_entity(self.entity);
+    }
+}
+
+impl TextEdit {
+    /// The smallest edit that turns `old` into `new`, found by skipping their common prefix and suffix.
+    /// Returns `None` if the texts are identical
+    pub(crate) fn diff(entity: Entity, old: &str, new: &str) -> Option<Self> {
+        if old == new {
+            return None;
+        }
+        let prefix: usize = old
+            .chars()
+            .zip(new.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        // the suffix must not overlap the prefix in either string
+        let suffix: usize = old[prefix..]
+            .chars()
+            .rev()
+            .zip(new[prefix..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        Some(Self {
+            entity,
+            base: text_hash(old),
+            start: prefix as u32,
+            removed: (old.len() - prefix - suffix) as u32,
+            inserted: new[prefix..new.len() - suffix].to_string(),
+        })
+    }
+
+    /// Apply the edit to `text`. Returns false, leaving `text` unchanged, if `text` is not the text
+    /// the edit was computed from: the edit was already applied, or `text` is stale or newer
+    pub(crate) fn apply(&self, text: &mut String) -> bool {
+        let start = self.start as usize;
+        let end = start + self.removed as usize;
+        if text_hash(text) != self.base
+            || end > text.len()
+            || !text.is_char_boundary(start)
+            || !text.is_char_boundary(end)
+        {
+            return false;
+        }
+        text.replace_range(start..end, &self.inserted);
+        true
+    }
+}
+
+/// FNV-1a hash of a text, the same on the server and the clients
+pub(crate) fn text_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Low-resolution view of which cells of the world contain at least one player.
+/// Cells are stored row by row, one bit per cell
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OccupancyGrid {
+    pub origin: Vec2,
+    pub cell_size: f32,
+    pub width: u32,
+    pub height: u32,
+    pub cells: Vec<u8>,
+}
+
+impl OccupancyGrid {
+    pub(crate) fn new(origin: Vec2, cell_size: f32, width: u32, height: u32) -> Self {
+        let num_cells = (width * height) as usize;
+        Self {
+            origin,
+            cell_size,
+            width,
+            height,
+            cells: vec![0; num_cells.div_ceil(8)],
+        }
+    }
+
+    /// Get the (x, y) coordinates of the cell that contains `position`, if it is inside the grid.
+    /// The far edges of the grid belong to the last row and column
+    pub(crate) fn cell_of(&self, position: Vec2) -> Option<(u32, u32)> {
+        let local = (position - self.origin) / self.cell_size;
+        if self.width == 0
+            || self.height == 0
+            || local.x < 0.0
+            || local.y < 0.0
+            || local.x > self.width as f32
+            || local.y > self.height as f32
+        {
+            return None;
+        }
+        // a position exactly on the far edge would otherwise fall into cell `width` (or `height`)
+        let x = (local.x as u32).min(self.width - 1);
+        let y = (local.y as u32).min(self.height - 1);
+        Some((x, y))
+    }
+
+    /// Mark the cell containing `position` as occupied. Positions outside the grid are ignored
+    pub(crate) fn occupy(&mut self, position: Vec2) {
+        if let Some((x, y)) = self.cell_of(position) {
+            let index = (y * self.width + x) as usize;
+            self.cells[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub(crate) fn is_occupied(&self, x: u32, y: u32) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+        let index = (y * self.width + x) as usize;
+        self.cells[index / 8] & (1 << (index % 8)) != 0
+    }
+}
+
 // Inputs
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Reflect, Clone, Copy, Actionlike)]
@@ -174,45 +750,463 @@ pub enum Inputs {
     Spawn,
 }
 
+impl Inputs {
+    pub(crate) const ALL: [Inputs; 6] = [
+        Inputs::Up,
+        Inputs::Down,
+        Inputs::Left,
+        Inputs::Right,
+        Inputs::Delete,
+        Inputs::Spawn,
+    ];
+}
+
 // Protocol
+
+/// Prediction and interpolation sync modes of a component
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SyncModes {
+    pub prediction: ComponentSyncMode,
+    pub interpolation: ComponentSyncMode,
+}
+
+impl SyncModes {
+    pub(crate) const fn both(mode: ComponentSyncMode) -> Self {
+        Self {
+            prediction: mode,
+            interpolation: mode,
+        }
+    }
+}
+
+/// Sync modes used by the `ProtocolPlugin` to register the replicated components.
+/// Insert it as a resource before adding the plugins to override the defaults,
+/// for example to make `PlayerText` use `ComponentSyncMode::Full`.
+/// `ComponentSyncMode::None` keeps the component on the confirmed entity only
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub(crate) struct ProtocolConfig {
+    pub player_id: SyncModes,
+    pub team: SyncModes,
+    pub position: SyncModes,
+    pub velocity: SyncModes,
+    pub health: SyncModes,
+    pub score: SyncModes,
+    pub player_color: SyncModes,
+    pub player_text: SyncModes,
+    pub player_name: SyncModes,
+    pub shielded: SyncModes,
+    pub current_room: SyncModes,
+    pub input_snapshot: SyncModes,
+    pub circle_marker: SyncModes,
+    pub circle_active: SyncModes,
+    pub player_parent: SyncModes,
+    pub metadata: SyncModes,
+    /// Coordinate system of the `Position`s written on the wire. The server and the clients must agree on it
+    pub coordinate_system: CoordinateSystem,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            player_id: SyncModes::both(ComponentSyncMode::Once),
+            team: SyncModes::both(ComponentSyncMode::Once),
+            position: SyncModes::both(ComponentSyncMode::Full),
+            velocity: SyncModes::both(ComponentSyncMode::Full),
+            health: SyncModes::both(ComponentSyncMode::Full),
+            // every server update is copied to the predicted and interpolated copies
+            score: SyncModes::both(ComponentSyncMode::Simple),
+            player_color: SyncModes::both(ComponentSyncMode::Once),
+            player_text: SyncModes::both(ComponentSyncMode::Simple),
+            player_name: SyncModes::both(ComponentSyncMode::Simple),
+            // `Simple` so that the removal also gets applied to the predicted and interpolated copies
+            shielded: SyncModes::both(ComponentSyncMode::Simple),
+            current_room: SyncModes {
+                prediction: ComponentSyncMode::Simple,
+                interpolation: ComponentSyncMode::None,
+            },
+            input_snapshot: SyncModes {
+                prediction: ComponentSyncMode::None,
+                interpolation: ComponentSyncMode::Simple,
+            },
+            circle_marker: SyncModes::both(ComponentSyncMode::Once),
+            circle_active: SyncModes::both(ComponentSyncMode::None),
+            player_parent: SyncModes::both(ComponentSyncMode::Once),
+            // there is no meaningful way to interpolate between two maps
+            metadata: SyncModes {
+                prediction: ComponentSyncMode::Full,
+                interpolation: ComponentSyncMode::Simple,
+            },
+            coordinate_system: CoordinateSystem::default(),
+        }
+    }
+}
+
+/// Expected `protocol_fingerprint` of the registrations of the `ProtocolPlugin`.
+/// Lightyear identifies the messages, components and channels by their registration order, so
+/// apps that register them in a different order can't understand each other.
+/// Update it when the registrations change on purpose
+pub(crate) const PROTOCOL_FINGERPRINT: u64 = 0xf96c390385ec17c2;
+
+/// Names of the types registered by the `ProtocolPlugin`, in registration order
+#[derive(Resource, Default, Debug)]
+pub(crate) struct ProtocolRegistrations(pub Vec<&'static str>);
+
+impl ProtocolRegistrations {
+    pub(crate) fn fingerprint(&self) -> u64 {
+        protocol_fingerprint(&self.0)
+    }
+}
+
+/// Order-sensitive FNV-1a hash of the registered type names. Unlike the std hashers, it is
+/// guaranteed to give the same value on every platform and compiler version
+pub(crate) fn protocol_fingerprint(names: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for name in names {
+        // the terminating 0 keeps ["ab", "c"] apart from ["a", "bc"]
+        for byte in name.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Record `T` in the `ProtocolRegistrations`, before registering it.
+/// Only the last segment of the type path is kept, which doesn't depend on the crate name
+fn recorded<T>(app: &mut App) -> &mut App {
+    let name = std::any::type_name::<T>().rsplit("::").next().unwrap_or_default();
+    app.world_mut().resource_mut::<ProtocolRegistrations>().0.push(name);
+    app
+}
+
 pub(crate) struct ProtocolPlugin;
 
 impl Plugin for ProtocolPlugin {
     fn build(&self, app: &mut App) {
+        // keep the config in the app, so that the sync modes in use can be inspected
+        app.init_resource::<ProtocolConfig>();
+        let config = app.world().resource::<ProtocolConfig>().clone();
+        app.init_resource::<ProtocolRegistrations>();
         // messages
-        app.register_message::<Message1>(ChannelDirection::Bidirectional);
+        recorded::<Message1>(app)
+            .register_message::<Message1>(ChannelDirection::Bidirectional);
+        recorded::<OccupancyGrid>(app)
+            .register_message::<OccupancyGrid>(ChannelDirection::ServerToClient);
+        recorded::<SetInterestRadius>(app)
+            .register_message::<SetInterestRadius>(ChannelDirection::ClientToServer);
+        recorded::<MoveTarget>(app)
+            .register_message::<MoveTarget>(ChannelDirection::ClientToServer);
+        recorded::<DebugCommand>(app)
+            .register_message::<DebugCommand>(ChannelDirection::ClientToServer);
+        recorded::<SetName>(app)
+            .register_message::<SetName>(ChannelDirection::ClientToServer);
+        recorded::<ChatMessage>(app)
+            .register_message::<ChatMessage>(ChannelDirection::Bidirectional);
+        recorded::<ServerTime>(app)
+            .register_message::<ServerTime>(ChannelDirection::ServerToClient);
+        recorded::<WorldConfig>(app)
+            .register_message::<WorldConfig>(ChannelDirection::ServerToClient);
+        recorded::<GridDescriptor>(app)
+            .register_message::<GridDescriptor>(ChannelDirection::ServerToClient);
+        recorded::<AudioRelevant>(app)
+            .register_message::<AudioRelevant>(ChannelDirection::ServerToClient);
+        recorded::<QueuePosition>(app)
+            .register_message::<QueuePosition>(ChannelDirection::ServerToClient);
+        recorded::<EntityDestroyed>(app)
+            .register_message::<EntityDestroyed>(ChannelDirection::ServerToClient);
+        recorded::<Leaderboard>(app)
+            .register_message::<Leaderboard>(ChannelDirection::ServerToClient);
+        recorded::<TextEdit>(app)
+            .register_message::<TextEdit>(ChannelDirection::ServerToClient)
+            .add_map_entities();
         // inputs
-        app.add_plugins(LeafwingInputPlugin::<Inputs>::default());
+        recorded::<Inputs>(app).add_plugins(LeafwingInputPlugin::<Inputs>::default());
         // components
-        app.register_component::<PlayerId>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
+        recorded::<PlayerId>(app)
+            .register_component::<PlayerId>(ChannelDirection::ServerToClient)
+            .add_prediction(config.player_id.prediction)
+            .add_interpolation(config.player_id.interpolation);
 
-        app.register_component::<Position>(ChannelDirection::Bidirectional)
-            .add_prediction(ComponentSyncMode::Full)
-            .add_interpolation(ComponentSyncMode::Full)
+        recorded::<Team>(app)
+            .register_component::<Team>(ChannelDirection::ServerToClient)
+            .add_prediction(config.team.prediction)
+            .add_interpolation(config.team.interpolation);
+
+        recorded::<Position>(app)
+            .register_component_custom_serde::<Position>(
+                ChannelDirection::Bidirectional,
+                config.coordinate_system.position_serialize_fns(),
+            )
+            .add_prediction(config.position.prediction)
+            .add_interpolation(config.position.interpolation)
+            .add_linear_interpolation_fn();
+
+        recorded::<Velocity>(app)
+            .register_component::<Velocity>(ChannelDirection::ServerToClient)
+            .add_prediction(config.velocity.prediction)
+            .add_interpolation(config.velocity.interpolation)
             .add_linear_interpolation_fn();
 
-        app.register_component::<PlayerColor>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
+        recorded::<Health>(app)
+            .register_component::<Health>(ChannelDirection::ServerToClient)
+            .add_prediction(config.health.prediction)
+            .add_interpolation(config.health.interpolation)
+            .add_interpolation_fn(Health::lerp);
+
+        recorded::<Score>(app)
+            .register_component::<Score>(ChannelDirection::ServerToClient)
+            .add_prediction(config.score.prediction)
+            .add_interpolation(config.score.interpolation);
 
-        app.register_component::<PlayerText>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Simple)
-            .add_interpolation(ComponentSyncMode::Simple);
+        recorded::<PlayerColor>(app)
+            .register_component::<PlayerColor>(ChannelDirection::ServerToClient)
+            .add_prediction(config.player_color.prediction)
+            .add_interpolation(config.player_color.interpolation);
 
-        app.register_component::<CircleMarker>(ChannelDirection::ServerToClient)
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
+        recorded::<PlayerText>(app)
+            .register_component::<PlayerText>(ChannelDirection::ServerToClient)
+            .add_prediction(config.player_text.prediction)
+            .add_interpolation(config.player_text.interpolation);
 
-        app.register_component::<PlayerParent>(ChannelDirection::ServerToClient)
+        recorded::<PlayerName>(app)
+            .register_component::<PlayerName>(ChannelDirection::ServerToClient)
+            .add_prediction(config.player_name.prediction)
+            .add_interpolation(config.player_name.interpolation);
+
+        recorded::<Shielded>(app)
+            .register_component::<Shielded>(ChannelDirection::ServerToClient)
+            .add_prediction(config.shielded.prediction)
+            .add_interpolation(config.shielded.interpolation);
+
+        recorded::<CurrentRoom>(app)
+            .register_component::<CurrentRoom>(ChannelDirection::ServerToClient)
+            .add_prediction(config.current_room.prediction)
+            .add_interpolation(config.current_room.interpolation);
+
+        recorded::<InputSnapshot>(app)
+            .register_component::<InputSnapshot>(ChannelDirection::ServerToClient)
+            .add_prediction(config.input_snapshot.prediction)
+            .add_interpolation(config.input_snapshot.interpolation);
+
+        recorded::<CircleMarker>(app)
+            .register_component::<CircleMarker>(ChannelDirection::ServerToClient)
+            .add_prediction(config.circle_marker.prediction)
+            .add_interpolation(config.circle_marker.interpolation);
+
+        recorded::<CircleActive>(app)
+            .register_component::<CircleActive>(ChannelDirection::ServerToClient)
+            .add_prediction(config.circle_active.prediction)
+            .add_interpolation(config.circle_active.interpolation);
+
+        recorded::<Metadata>(app)
+            .register_component::<Metadata>(ChannelDirection::ServerToClient)
+            .add_prediction(config.metadata.prediction)
+            .add_interpolation(config.metadata.interpolation);
+
+        recorded::<PlayerParent>(app)
+            .register_component::<PlayerParent>(ChannelDirection::ServerToClient)
             .add_map_entities()
-            .add_prediction(ComponentSyncMode::Once)
-            .add_interpolation(ComponentSyncMode::Once);
+            .add_prediction(config.player_parent.prediction)
+            .add_interpolation(config.player_parent.interpolation);
         // channels
-        app.add_channel::<Channel1>(ChannelSettings {
+        recorded::<Channel1>(app).add_channel::<Channel1>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
             ..default()
         });
+
+        let fingerprint = app.world().resource::<ProtocolRegistrations>().fingerprint();
+        if fingerprint != PROTOCOL_FINGERPRINT {
+            warn!(
+                "The protocol fingerprint is {:#x} instead of {:#x}: the registrations changed or \
+                were reordered, so this app can't talk to the ones built with the expected order. \
+                Update PROTOCOL_FINGERPRINT if the change is intended",
+                fingerprint, PROTOCOL_FINGERPRINT
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::estimate_size;
+    use crate::shared::color_from_team;
+    use crate::test_utils::{player_of, Stepper};
+
+    fn round_trip(old: &str, new: &str) {
+        let edit = TextEdit::diff(Entity::PLACEHOLDER, old, new).unwrap();
+        let mut text = old.to_string();
+        assert!(edit.apply(&mut text));
+        assert_eq!(text, new);
+    }
+
+    #[test]
+    fn text_edits_turn_the_old_text_into_the_new_one() {
+        round_trip("Hello world", "Hello brave world");
+        round_trip("Hello world", "Hello");
+        round_trip("", "Server changed");
+        round_trip("aaaa", "aaa");
+        round_trip("héllo wörld", "héllo wôrld");
+        assert_eq!(TextEdit::diff(Entity::PLACEHOLDER, "same", "same"), None);
+    }
+
+    #[test]
+    fn text_edits_only_apply_to_the_text_they_were_computed_from() {
+        let edit = TextEdit::diff(Entity::PLACEHOLDER, "Player 1", "Player 12").unwrap();
+        let mut text = "Player 1".to_string();
+        assert!(edit.apply(&mut text));
+        // applying it twice would give "Player 122"
+        assert!(!edit.apply(&mut text));
+        assert_eq!(text, "Player 12");
+
+        let mut stale = "Player".to_string();
+        assert!(!edit.apply(&mut stale));
+        assert_eq!(stale, "Player");
+    }
+
+    #[test]
+    fn a_single_character_edit_is_smaller_than_the_full_text() {
+        let old = "a".repeat(200);
+        let new = format!("{}b", &old[..199]);
+        let edit = TextEdit::diff(Entity::PLACEHOLDER, &old, &new).unwrap();
+        assert_eq!(edit.inserted, "b");
+        assert!(estimate_size(&edit) * 4 < estimate_size(&PlayerText(new)));
+    }
+
+    #[test]
+    fn positions_are_written_on_the_wire_in_the_configured_coordinate_system() {
+        let position = Position(Vec2::new(3.0, 4.0));
+        let encode_wire = |wire: Vec2| {
+            bincode::serde::encode_to_vec(wire, bincode::config::standard()).unwrap()
+        };
+        for (coordinate_system, wire) in [
+            (CoordinateSystem::YUp, Vec2::new(3.0, 4.0)),
+            (CoordinateSystem::YDown, Vec2::new(3.0, -4.0)),
+        ] {
+            let mut bytes = Vec::new();
+            write_position(coordinate_system, &position, &mut bytes).unwrap();
+            assert_eq!(bytes, encode_wire(wire), "{:?}", coordinate_system);
+            let decoded = read_position(coordinate_system, bytes.as_slice()).unwrap();
+            assert_eq!(decoded, position, "{:?}", coordinate_system);
+        }
+    }
+
+    #[test]
+    fn apps_with_another_coordinate_system_still_replicate_the_positions() {
+        // the coordinate system belongs to each app, this doesn't affect the tests running
+        // concurrently with the default one
+        let mut stepper = Stepper::with(&[1, 2], |app| {
+            app.insert_resource(ProtocolConfig {
+                coordinate_system: CoordinateSystem::YDown,
+                ..default()
+            });
+        });
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let player = player_of(&stepper.server_app, b).unwrap();
+        stepper
+            .server_app
+            .world_mut()
+            .get_mut::<Position>(player)
+            .unwrap()
+            .0 = Vec2::new(20.0, 30.0);
+        stepper.frame_steps(20);
+        stepper.assert_replicated::<Position>(a, b);
+    }
+
+    #[test]
+    fn metadata_mutations_over_the_size_cap_are_refused() {
+        let mut metadata = Metadata::default();
+        // each key and value takes half of the budget
+        let half = "a".repeat(METADATA_MAX_BYTES / 2 - 2);
+        assert_eq!(metadata.insert("k1", half.clone()), Ok(()));
+        assert_eq!(metadata.insert("k2", half.clone()), Ok(()));
+        assert_eq!(metadata.size(), METADATA_MAX_BYTES);
+        assert_eq!(
+            metadata.insert("k3", "b"),
+            Err(MetadataTooLarge {
+                size: METADATA_MAX_BYTES + 2,
+                max: METADATA_MAX_BYTES,
+            })
+        );
+        assert_eq!(metadata.get("k3"), None);
+        // replacing a value only counts the new value
+        assert_eq!(metadata.insert("k1", "short"), Ok(()));
+        assert_eq!(metadata.insert("k3", "b"), Ok(()));
+        assert_eq!(metadata.get("k1"), Some("short"));
+    }
+
+    #[test]
+    fn player_colors_are_the_same_after_a_round_trip() {
+        for team in 0..8 {
+            let color = PlayerColor::new(color_from_team(team));
+            assert_eq!(PlayerColor::unpack(color.pack()), color);
+            let bytes = bincode::serde::encode_to_vec(&color, bincode::config::standard()).unwrap();
+            let (decoded, _): (PlayerColor, usize) =
+                bincode::serde::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+            assert_eq!(decoded, color);
+        }
+    }
+
+    #[test]
+    fn player_colors_are_quantized_to_8_bits_per_channel() {
+        let original = color_from_team(3).to_srgba();
+        let quantized = PlayerColor::new(color_from_team(3)).0.to_srgba();
+        let channels = [
+            (original.red, quantized.red),
+            (original.green, quantized.green),
+            (original.blue, quantized.blue),
+            (original.alpha, quantized.alpha),
+        ];
+        for (a, b) in channels {
+            assert!((a - b).abs() <= 0.5 / 255.0, "{} and {} are too far apart", a, b);
+        }
+    }
+
+    #[test]
+    fn occupancy_grid_far_edges_are_in_the_last_cells() {
+        let mut grid = OccupancyGrid::new(Vec2::splat(-400.0), 400.0, 2, 2);
+        assert_eq!(grid.cell_of(Vec2::splat(-400.0)), Some((0, 0)));
+        assert_eq!(grid.cell_of(Vec2::new(400.0, -400.0)), Some((1, 0)));
+        assert_eq!(grid.cell_of(Vec2::new(-400.0, 400.0)), Some((0, 1)));
+        assert_eq!(grid.cell_of(Vec2::new(400.1, 0.0)), None);
+        assert_eq!(grid.cell_of(Vec2::new(0.0, -400.1)), None);
+
+        grid.occupy(Vec2::splat(400.0));
+        assert!(grid.is_occupied(1, 1));
+        assert!(!grid.is_occupied(0, 0));
+    }
+
+    #[test]
+    fn each_group_strategy_assigns_the_expected_group() {
+        let (entity, parent) = (Entity::from_raw(7), Entity::from_raw(3));
+        let room = RoomId(42);
+        let owner = ClientId::Netcode(5);
+        let group_id = |strategy: GroupStrategy, parent, owner| {
+            strategy
+                .replication_group(parent, room, owner)
+                .group_id(Some(entity))
+                .0
+        };
+        // by default, the group id is generated from the entity
+        assert_eq!(group_id(GroupStrategy::PerEntity, Some(parent), Some(owner)), entity.to_bits());
+        assert_eq!(group_id(GroupStrategy::SharedWithParent, Some(parent), None), parent.to_bits());
+        assert_eq!(group_id(GroupStrategy::SharedWithParent, None, None), entity.to_bits());
+        assert_eq!(group_id(GroupStrategy::PerRoom, Some(parent), Some(owner)), room.0);
+        assert_eq!(group_id(GroupStrategy::PerClient, Some(parent), Some(owner)), owner.to_bits());
+        assert_eq!(group_id(GroupStrategy::PerClient, Some(parent), None), entity.to_bits());
+    }
+
+    #[test]
+    fn the_fingerprint_depends_on_the_registration_order() {
+        let app = crate::test_utils::server_app();
+        let registrations = &app.world().resource::<ProtocolRegistrations>().0;
+        assert_eq!(registrations.first(), Some(&"Message1"));
+        assert_eq!(registrations.last(), Some(&"Channel1"));
+        assert_eq!(protocol_fingerprint(registrations), PROTOCOL_FINGERPRINT);
+
+        let mut reordered = registrations.clone();
+        reordered.swap(0, 1);
+        assert_ne!(protocol_fingerprint(&reordered), PROTOCOL_FINGERPRINT);
+        assert_ne!(protocol_fingerprint(&["ab", "c"]), protocol_fingerprint(&["a", "bc"]));
     }
 }
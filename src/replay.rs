@@ -0,0 +1,160 @@
+use std::fmt::Write;
+
+use lightyear::prelude::ClientId;
+
+use crate::input_history::InputHistory;
+use crate::protocol::{InputSnapshot, Inputs};
+
+/// A change of the inputs held by one of the clients of a `Replay`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ReplayEvent {
+    /// Number of ticks since the start of the replay
+    pub tick: u64,
+    /// Index of the client, in the order the clients connected
+    pub client: usize,
+    pub snapshot: InputSnapshot,
+}
+
+/// A recorded input stream, that can be played back against a server to reproduce a bug.
+///
+/// The text format has one event per line: `<tick> <client> <inputs>`, where the inputs are the
+/// names of the held `Inputs` joined with `+`, or `-` when nothing is held. Empty lines and lines
+/// starting with `#` are ignored. The clients are numbered instead of using their `ClientId`, so
+/// that a replay doesn't depend on the ids of the session it was recorded in
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Replay {
+    /// Sorted by tick, then by client
+    pub events: Vec<ReplayEvent>,
+}
+
+impl Replay {
+    /// Build a replay from the `InputHistory` of the given clients, which are numbered in the
+    /// given order. The first recorded tick becomes the start of the replay
+    pub(crate) fn from_history(history: &InputHistory, clients: &[ClientId]) -> Self {
+        let mut events: Vec<ReplayEvent> = clients
+            .iter()
+            .enumerate()
+            .flat_map(|(client, client_id)| {
+                history.records(*client_id).map(move |record| ReplayEvent {
+                    tick: record.since_tick,
+                    client,
+                    snapshot: record.snapshot,
+                })
+            })
+            .collect();
+        let start = events.iter().map(|event| event.tick).min().unwrap_or(0);
+        for event in events.iter_mut() {
+            event.tick -= start;
+        }
+        events.sort_by_key(|event| (event.tick, event.client));
+        Self { events }
+    }
+
+    /// Number of clients taking part in the replay
+    pub(crate) fn num_clients(&self) -> usize {
+        self.events.iter().map(|event| event.client + 1).max().unwrap_or(0)
+    }
+
+    /// The tick of the last event
+    pub(crate) fn last_tick(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.tick)
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = String::from("# <tick> <client> <inputs>\n");
+        for event in self.events.iter() {
+            let inputs: Vec<String> = Inputs::ALL
+                .iter()
+                .filter(|input| event.snapshot.is_pressed(**input))
+                .map(|input| format!("{:?}", input))
+                .collect();
+            let inputs = if inputs.is_empty() {
+                "-".to_string()
+            } else {
+                inputs.join("+")
+            };
+            writeln!(text, "{} {} {}", event.tick, event.client, inputs).unwrap();
+        }
+        text
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |reason: &str| format!("line {}: {}: {:?}", number + 1, reason, line);
+            let mut fields = line.split_whitespace();
+            let (Some(tick), Some(client), Some(inputs), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(error("expected <tick> <client> <inputs>"));
+            };
+            let tick = tick.parse().map_err(|_| error("invalid tick"))?;
+            let client = client.parse().map_err(|_| error("invalid client"))?;
+            let mut bits = 0;
+            if inputs != "-" {
+                for name in inputs.split('+') {
+                    let input = Inputs::ALL
+                        .iter()
+                        .find(|input| format!("{:?}", input) == name)
+                        .ok_or_else(|| error("unknown input"))?;
+                    bits |= 1 << *input as u8;
+                }
+            }
+            events.push(ReplayEvent {
+                tick,
+                client,
+                snapshot: InputSnapshot(bits),
+            });
+        }
+        if events
+            .windows(2)
+            .any(|pair| (pair[0].tick, pair[0].client) >= (pair[1].tick, pair[1].client))
+        {
+            return Err("the events must be sorted by tick, then by client".to_string());
+        }
+        Ok(Self { events })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(inputs: &[Inputs]) -> InputSnapshot {
+        InputSnapshot(inputs.iter().fold(0, |bits, input| bits | 1 << *input as u8))
+    }
+
+    #[test]
+    fn replays_are_recorded_from_the_input_history() {
+        let (a, b) = (ClientId::Netcode(7), ClientId::Netcode(3));
+        let mut history = InputHistory::default();
+        history.record(a, snapshot(&[]), 100);
+        history.record(b, snapshot(&[]), 100);
+        history.record(a, snapshot(&[Inputs::Up, Inputs::Left]), 104);
+        history.record(b, snapshot(&[Inputs::Spawn]), 102);
+
+        let replay = Replay::from_history(&history, &[a, b]);
+        assert_eq!(replay.num_clients(), 2);
+        assert_eq!(replay.last_tick(), 4);
+        assert_eq!(
+            replay.to_text(),
+            "# <tick> <client> <inputs>\n0 0 -\n0 1 -\n2 1 Spawn\n4 0 Up+Left\n"
+        );
+        assert_eq!(Replay::parse(&replay.to_text()), Ok(replay));
+    }
+
+    #[test]
+    fn invalid_replays_are_rejected() {
+        assert!(Replay::parse("0 0").is_err());
+        assert!(Replay::parse("0 0 Jump").is_err());
+        assert!(Replay::parse("x 0 -").is_err());
+        // out of order, or two events for the same client and tick
+        assert!(Replay::parse("2 0 -\n1 0 Up").is_err());
+        assert!(Replay::parse("1 0 -\n1 0 Up").is_err());
+        assert_eq!(Replay::parse("# nothing\n\n"), Ok(Replay::default()));
+    }
+}
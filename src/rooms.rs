@@ -0,0 +1,287 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use lightyear::prelude::server::{RoomId, RoomManager};
+use lightyear::prelude::ClientId;
+
+use crate::shared::{room_cell, room_id_for_cell};
+
+/// Maximum number of rooms that are kept alive by default
+pub(crate) const DEFAULT_MAX_ROOMS: usize = 64;
+
+#[derive(Default)]
+struct LiveRoom {
+    /// Number of players currently in the room
+    occupants: usize,
+    /// Number of other entities added to the room, the ones with a `RoomMember`
+    members: usize,
+    /// Last time a player entered the room or an entity was added to it, used to evict the least
+    /// recently used rooms first
+    last_used: u64,
+}
+
+impl LiveRoom {
+    fn is_empty(&self) -> bool {
+        self.occupants == 0 && self.members == 0
+    }
+}
+
+/// Keeps track of the rooms that are currently alive.
+/// When there are more than `max_rooms` live rooms, the least recently used empty rooms are
+/// evicted: whatever is left in them is removed from the `RoomManager`, then they are forgotten.
+/// Rooms with players or members are never evicted, even if that means going over the cap
+#[derive(Resource)]
+pub(crate) struct RoomRegistry {
+    pub max_rooms: usize,
+    rooms: HashMap<RoomId, LiveRoom>,
+    clock: u64,
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ROOMS)
+    }
+}
+
+impl RoomRegistry {
+    pub(crate) fn new(max_rooms: usize) -> Self {
+        Self {
+            max_rooms,
+            rooms: HashMap::default(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, room: RoomId) -> &mut LiveRoom {
+        self.clock += 1;
+        let live_room = self.rooms.entry(room).or_default();
+        live_room.last_used = self.clock;
+        live_room
+    }
+
+    /// A player entered `room`. Returns the rooms that got evicted to stay under the cap
+    pub(crate) fn enter(&mut self, room: RoomId, room_manager: &mut RoomManager) -> Vec<RoomId> {
+        self.touch(room).occupants += 1;
+        self.evict(room_manager)
+    }
+
+    /// A player left `room`. The room stays alive until it gets evicted
+    pub(crate) fn leave(&mut self, room: RoomId) {
+        if let Some(live_room) = self.rooms.get_mut(&room) {
+            live_room.occupants = live_room.occupants.saturating_sub(1);
+        }
+    }
+
+    /// An entity with a `RoomMember` was added to `room`, which keeps the room alive
+    pub(crate) fn add_member(&mut self, room: RoomId) {
+        self.touch(room).members += 1;
+    }
+
+    /// An entity with a `RoomMember` was removed from `room`
+    pub(crate) fn remove_member(&mut self, room: RoomId) {
+        if let Some(live_room) = self.rooms.get_mut(&room) {
+            live_room.members = live_room.members.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn contains(&self, room: RoomId) -> bool {
+        self.rooms.contains_key(&room)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.rooms.clear();
+    }
+
+    fn evict(&mut self, room_manager: &mut RoomManager) -> Vec<RoomId> {
+        let mut evicted = Vec::new();
+        while self.rooms.len() > self.max_rooms {
+            let Some(room) = self
+                .rooms
+                .iter()
+                .filter(|(_, live_room)| live_room.is_empty())
+                .min_by_key(|(_, live_room)| live_room.last_used)
+                .map(|(room, _)| *room)
+            else {
+                break;
+            };
+            // lightyear drops a room once it has no clients nor entities left
+            if let Some(lightyear_room) = room_manager.get_room(room) {
+                let clients: Vec<ClientId> = lightyear_room.clients.iter().copied().collect();
+                let entities: Vec<Entity> = lightyear_room.entities.iter().copied().collect();
+                for client_id in clients {
+                    room_manager.remove_client(client_id, room);
+                }
+                for entity in entities {
+                    room_manager.remove_entity(entity, room);
+                }
+            }
+            self.rooms.remove(&room);
+            evicted.push(room);
+        }
+        evicted
+    }
+}
+
+/// Set on the ids of the zone rooms, so that they never collide with the ids of the rooms
+const ZONE_ID_BIT: u64 = 1 << 63;
+
+/// The zone that contains `room`: zones are squares of `zone_size` rooms along each axis.
+/// A zone is itself a lightyear room, with an id that can't be the id of a regular room
+pub(crate) fn zone_for_room(room: RoomId, zone_size: i32) -> RoomId {
+    let zone = room_cell(room).div_euclid(IVec2::splat(zone_size.max(1)));
+    RoomId(room_id_for_cell(zone).0 | ZONE_ID_BIT)
+}
+
+/// The zones that each client and each player entity are currently in.
+/// Zones group rooms for coarse replication: the player entities are replicated to every
+/// client in the same zone, while the finer details stay in the rooms
+#[derive(Resource, Default)]
+pub(crate) struct ZoneMembership {
+    clients: HashMap<ClientId, RoomId>,
+    entities: HashMap<Entity, RoomId>,
+}
+
+impl ZoneMembership {
+    /// Move the clients and entities to the given zones in the `RoomManager`.
+    /// The clients and entities that are not listed are removed from their zone
+    pub(crate) fn sync(
+        &mut self,
+        room_manager: &mut RoomManager,
+        clients: HashMap<ClientId, RoomId>,
+        entities: HashMap<Entity, RoomId>,
+    ) {
+        for (client_id, zone) in self.clients.iter() {
+            if clients.get(client_id) != Some(zone) {
+                room_manager.remove_client(*client_id, *zone);
+            }
+        }
+        for (client_id, zone) in clients.iter() {
+            if self.clients.get(client_id) != Some(zone) {
+                room_manager.add_client(*client_id, *zone);
+            }
+        }
+        for (entity, zone) in self.entities.iter() {
+            if entities.get(entity) != Some(zone) {
+                room_manager.remove_entity(*entity, *zone);
+            }
+        }
+        for (entity, zone) in entities.iter() {
+            if self.entities.get(entity) != Some(zone) {
+                room_manager.add_entity(*entity, *zone);
+            }
+        }
+        self.clients = clients;
+        self.entities = entities;
+    }
+}
+
+/// Environmental properties of a room, applied to the players in it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RoomProperty {
+    /// Multiplies the speed of the players, below 1.0 for a slow room
+    pub speed_multiplier: f32,
+}
+
+impl Default for RoomProperty {
+    fn default() -> Self {
+        Self {
+            speed_multiplier: 1.0,
+        }
+    }
+}
+
+/// The properties of the rooms. Rooms that are not listed use the default properties
+#[derive(Resource, Default)]
+pub(crate) struct RoomProperties(pub HashMap<RoomId, RoomProperty>);
+
+impl RoomProperties {
+    pub(crate) fn get(&self, room: RoomId) -> RoomProperty {
+        self.0.get(&room).copied().unwrap_or_default()
+    }
+}
+
+/// Number of clients and of entities in each room, as `(clients, entities)`, refreshed every frame.
+/// Rooms that contain neither are absent. The zone rooms are not counted
+#[derive(Resource, Default, Debug, PartialEq)]
+pub(crate) struct RoomOccupancy(pub HashMap<RoomId, (usize, usize)>);
+
+impl RoomOccupancy {
+    /// Count the rooms of the clients and the rooms of the entities
+    pub(crate) fn count(
+        clients: impl Iterator<Item = RoomId>,
+        entities: impl Iterator<Item = RoomId>,
+    ) -> Self {
+        let mut rooms: HashMap<RoomId, (usize, usize)> = HashMap::default();
+        for room in clients {
+            rooms.entry(room).or_default().0 += 1;
+        }
+        for room in entities {
+            rooms.entry(room).or_default().1 += 1;
+        }
+        Self(rooms)
+    }
+
+    /// Number of clients and of entities in `room`
+    pub(crate) fn get(&self, room: RoomId) -> (usize, usize) {
+        self.0.get(&room).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_least_recently_used_empty_room_is_evicted() {
+        let mut room_manager = RoomManager::default();
+        let mut registry = RoomRegistry::new(2);
+        let (a, b, c) = (RoomId(1), RoomId(2), RoomId(3));
+        assert!(registry.enter(a, &mut room_manager).is_empty());
+        assert!(registry.enter(b, &mut room_manager).is_empty());
+        registry.leave(a);
+        registry.leave(b);
+        // `b` was used more recently than `a`
+        assert_eq!(registry.enter(c, &mut room_manager), vec![a]);
+        assert!(!registry.contains(a));
+        assert!(registry.contains(b));
+        assert!(registry.contains(c));
+    }
+
+    #[test]
+    fn rooms_with_players_or_members_are_not_evicted() {
+        let mut room_manager = RoomManager::default();
+        let mut registry = RoomRegistry::new(1);
+        let (a, b, c, d) = (RoomId(1), RoomId(2), RoomId(3), RoomId(4));
+        registry.enter(a, &mut room_manager);
+        registry.add_member(b);
+        assert!(registry.enter(c, &mut room_manager).is_empty());
+        assert_eq!(registry.len(), 3);
+
+        registry.remove_member(b);
+        registry.leave(c);
+        assert_eq!(registry.enter(d, &mut room_manager), vec![b, c]);
+        assert!(registry.contains(a));
+        assert!(registry.contains(d));
+    }
+
+    #[test]
+    fn evicted_rooms_are_emptied_in_the_room_manager() {
+        let mut room_manager = RoomManager::default();
+        let mut registry = RoomRegistry::new(1);
+        let (a, b) = (RoomId(1), RoomId(2));
+        let client_id = ClientId::Netcode(1);
+        let entity = Entity::from_raw(1);
+        registry.enter(a, &mut room_manager);
+        room_manager.add_client(client_id, a);
+        room_manager.add_entity(entity, a);
+        registry.leave(a);
+
+        assert_eq!(registry.enter(b, &mut room_manager), vec![a]);
+        assert!(room_manager
+            .get_room(a)
+            .map_or(true, |room| room.clients.is_empty() && room.entities.is_empty()));
+    }
+}
@@ -0,0 +1,132 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+
+/// Weight of the last frame in the smoothed frame time
+const SMOOTHING: f32 = 0.1;
+/// The send interval shrinks again once the smoothed frame time is below this fraction of the target
+const RECOVERY_RATIO: f32 = 0.8;
+/// How often the send interval can change
+const ADJUST_INTERVAL_SECS: f32 = 0.5;
+
+/// Sheds bandwidth when the server can't keep up, by only sending every `interval` frames.
+/// The interval grows while the smoothed frame time is above `target_frame_time` (up to
+/// `max_interval`) and shrinks back once it is comfortably below it. The simulation keeps
+/// running every frame, only the sends are spread out
+#[derive(Resource)]
+pub(crate) struct AdaptiveSendRate {
+    pub enabled: bool,
+    pub target_frame_time: Duration,
+    pub max_interval: u32,
+    smoothed_frame_time: f32,
+    interval: u32,
+    frame: u32,
+    adjust_timer: Timer,
+}
+
+impl Default for AdaptiveSendRate {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target_frame_time: Duration::from_millis(20),
+            max_interval: 4,
+            smoothed_frame_time: 0.0,
+            interval: 1,
+            frame: 0,
+            adjust_timer: Timer::from_seconds(ADJUST_INTERVAL_SECS, TimerMode::Repeating),
+        }
+    }
+}
+
+impl AdaptiveSendRate {
+    /// Record the duration of the last frame, and adjust the send interval if needed
+    pub(crate) fn update(&mut self, frame_time: Duration) {
+        self.frame = self.frame.wrapping_add(1);
+        self.smoothed_frame_time +=
+            (frame_time.as_secs_f32() - self.smoothed_frame_time) * SMOOTHING;
+        if !self.enabled {
+            self.interval = 1;
+            return;
+        }
+        self.adjust_timer.tick(frame_time);
+        if !self.adjust_timer.just_finished() {
+            return;
+        }
+        let target = self.target_frame_time.as_secs_f32();
+        let interval = if self.smoothed_frame_time > target {
+            (self.interval + 1).min(self.max_interval.max(1))
+        } else if self.smoothed_frame_time < target * RECOVERY_RATIO {
+            (self.interval - 1).max(1)
+        } else {
+            self.interval
+        };
+        if interval != self.interval {
+            info!(
+                "Smoothed frame time is {:.1}ms, sending every {} frames",
+                self.smoothed_frame_time * 1000.0,
+                interval
+            );
+            self.interval = interval;
+        }
+    }
+
+    /// The server currently sends once every `interval` frames
+    pub(crate) fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub(crate) fn is_send_frame(&self) -> bool {
+        self.frame % self.interval == 0
+    }
+}
+
+/// Measure the frame time with the real clock, which is not affected by the virtual time speed
+pub(crate) fn update_send_rate(mut send_rate: ResMut<AdaptiveSendRate>, time: Res<Time<Real>>) {
+    send_rate.update(time.delta());
+}
+
+/// Run condition: true on the frames where the server sends
+pub(crate) fn is_send_frame(send_rate: Res<AdaptiveSendRate>) -> bool {
+    send_rate.is_send_frame()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `frames` frames of `frame_time`, returning how many of them sent
+    fn run(send_rate: &mut AdaptiveSendRate, frames: usize, frame_time: Duration) -> usize {
+        (0..frames)
+            .filter(|_| {
+                send_rate.update(frame_time);
+                send_rate.is_send_frame()
+            })
+            .count()
+    }
+
+    #[test]
+    fn the_send_rate_drops_under_overload_and_recovers_after() {
+        let mut send_rate = AdaptiveSendRate {
+            enabled: true,
+            ..default()
+        };
+        let (overloaded, idle) = (Duration::from_millis(40), Duration::from_millis(5));
+        assert_eq!(run(&mut send_rate, 10, idle), 10);
+
+        // 4 seconds of overload
+        run(&mut send_rate, 100, overloaded);
+        assert_eq!(send_rate.interval(), send_rate.max_interval);
+        assert_eq!(run(&mut send_rate, 8, overloaded), 2);
+
+        // the interval shrinks back once per adjustment, after the smoothed frame time went down
+        run(&mut send_rate, 1000, idle);
+        assert_eq!(send_rate.interval(), 1);
+        assert_eq!(run(&mut send_rate, 10, idle), 10);
+    }
+
+    #[test]
+    fn the_send_rate_never_drops_when_disabled() {
+        let mut send_rate = AdaptiveSendRate::default();
+        assert_eq!(run(&mut send_rate, 100, Duration::from_millis(100)), 100);
+        assert_eq!(send_rate.interval(), 1);
+    }
+}
@@ -1,39 +1,272 @@
+use std::collections::VecDeque;
+
+use bevy::diagnostic::Diagnostics;
+use bevy::ecs::system::{RunSystemOnce, SystemParam};
 use bevy::prelude::*;
-use bevy::utils::Duration;
-use bevy::utils::HashMap;
+use bevy::tasks::{ComputeTaskPool, ParallelSlice, TaskPool};
+use bevy::utils::{Duration, Instant};
+use bevy::utils::{HashMap, HashSet};
 use leafwing_input_manager::prelude::{ActionState, InputMap};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 use lightyear::prelude::server::*;
 use lightyear::prelude::*;
 
+use crate::admission::AdmissionQueue;
+use crate::diagnostics::{RelevanceMetrics, ServerDiagnosticsPlugin, INTEREST_MANAGEMENT_TIME};
+use crate::input_history::InputHistory;
+use crate::interest::{CircleInterestStrategy, Relevance};
+use crate::names::{NameValidation, PlayerNames};
+use crate::outbox::{estimate_size, flush_outbox, MessagePriority, NetworkThroughput, Outbox};
 use crate::protocol::*;
+use crate::rooms::{zone_for_room, RoomOccupancy, RoomProperties, RoomRegistry, ZoneMembership};
+use crate::send_rate::{is_send_frame, update_send_rate, AdaptiveSendRate};
 use crate::shared;
-use crate::shared::{color_from_id, shared_movement_behaviour};
+use crate::shared::{
+    color_from_id, has_movement_input, move_towards, room_cell, room_id_for_position,
+    shared_movement_behaviour, MOVE_SPEED,
+};
+use crate::spatial::{update_circle_index, CircleSpatialIndex};
+use crate::tap::ReplicationTapPlugin;
 
-const GRID_SIZE: f32 = 200.0;
-const NUM_CIRCLES: i32 = 10;
-const INTEREST_RADIUS: f32 = 150.0;
+/// Where new players are spawned
+const SPAWN_POSITION: Vec2 = Vec2::ZERO;
+/// Bounds for the interest radius that clients can request
+const MIN_INTEREST_RADIUS: f32 = 50.0;
+const MAX_INTEREST_RADIUS: f32 = 1000.0;
+/// Size of a cell of the occupancy grid that is broadcasted to clients
+const OCCUPANCY_CELL_SIZE: f32 = 400.0;
+/// How often the occupancy grid is broadcasted
+const OCCUPANCY_INTERVAL_SECS: f32 = 1.0;
+/// How often the server time is broadcasted
+const SERVER_TIME_INTERVAL_SECS: f32 = 1.0;
+/// How often the leaderboard is broadcasted
+const LEADERBOARD_INTERVAL_SECS: f32 = 1.0;
+/// How often the server tick is broadcasted as a heartbeat in `Message1`
+const HEARTBEAT_INTERVAL_SECS: f32 = 1.0;
+/// Window over which the relevance changes of each client are counted by the `ChurnBreaker`
+const CHURN_WINDOW_SECS: f32 = 1.0;
+/// Number of relevance changes in a window above which a client's relevance gets frozen
+const CHURN_MAX_CHANGES_PER_WINDOW: usize = 500;
+/// How long a client's relevance stays frozen
+const CHURN_FREEZE_SECS: f32 = 5.0;
+/// How often the replication groups are rebalanced
+const GROUP_REBALANCE_INTERVAL_SECS: f32 = 10.0;
+/// Two players whose positions are closer than this overlap, and damage each other
+const COLLISION_RADIUS: f32 = 50.0;
+/// Health lost by each player of an overlapping pair, per fixed tick
+const COLLISION_DAMAGE_PER_TICK: f32 = 0.5;
+/// How long the players are `Shielded` after spawning or respawning
+const SPAWN_PROTECTION_SECS: f32 = 3.0;
+/// `Metadata` key of the number of times a player died
+const DEATHS_KEY: &str = "deaths";
+/// The circles of a test burst are spawned within this distance of the client's player
+const TEST_BURST_RADIUS: f32 = 100.0;
+/// Seed of the positions of the test bursts
+const TEST_BURST_SEED: u64 = 7;
+/// Set on the server to accept the `DebugCommand`s of the clients
+const ALLOW_DEBUG_COMMANDS_VAR: &str = "ALLOW_DEBUG_COMMANDS";
 
 // Plugin for server-specific logic
 pub struct ExampleServerPlugin;
 
 impl Plugin for ExampleServerPlugin {
     fn build(&self, app: &mut App) {
+        // insert a different `InterestConfig` before adding the plugin to override the default
+        app.init_resource::<InterestConfig>();
+        let config = *app.world().resource::<InterestConfig>();
         app.init_resource::<Global>();
-        app.add_systems(Startup, init);
+        // insert a different `GroupStrategy` before adding the plugin to override the default
+        app.init_resource::<GroupStrategy>();
+        app.init_resource::<RelevantCircles>();
+        // insert a different `CircleInterestStrategy` before adding the plugin to override the default
+        app.init_resource::<CircleInterestStrategy>();
+        app.init_resource::<ClientInterestRadius>();
+        // insert a `NameValidation` with a custom `NameFilter` before adding the plugin to filter names
+        app.init_resource::<NameValidation>();
+        app.init_resource::<PlayerNames>();
+        // insert a `TeamAssignment` with a different number of teams before adding the plugin
+        app.init_resource::<TeamAssignment>();
+        app.init_resource::<PausedClients>();
+        app.init_resource::<SimulationPaused>();
+        app.init_resource::<FollowTargets>();
+        app.init_resource::<MaxRelevantCircles>();
+        // insert a `RelevanceBudget` with a cap before adding the plugin to bound the updates per tick
+        app.init_resource::<RelevanceBudget>();
+        app.init_resource::<ChurnBreaker>();
+        app.init_resource::<PlayerCulling>();
+        app.init_resource::<CulledPlayers>();
+        // insert a different `VisibilityPrecedence` before adding the plugin to override the default
+        app.init_resource::<VisibilityPrecedence>();
+        app.init_resource::<ReconciledVisibility>();
+        // insert `CompactCircleGrid(true)` before adding the plugin to send the grid as a single message
+        app.init_resource::<CompactCircleGrid>();
+        // insert a `GroupRebalancing` with a group size cap before adding the plugin to split large groups
+        app.init_resource::<GroupRebalancing>();
+        app.init_resource::<AudioRadius>();
+        app.init_resource::<AudioRelevantEntities>();
+        app.init_resource::<Outbox>();
+        app.init_resource::<NetworkThroughput>();
+        // enable the `AdaptiveSendRate` before adding the plugin to send less often under load
+        app.init_resource::<AdaptiveSendRate>();
+        app.add_systems(First, update_send_rate);
+        app.configure_sets(PostUpdate, MainSet::Send.run_if(is_send_frame));
+        app.insert_resource(ServerTicks {
+            tick: 0,
+            timer: Timer::from_seconds(SERVER_TIME_INTERVAL_SECS, TimerMode::Repeating),
+        });
+        app.add_systems(FixedFirst, count_server_ticks);
+        // insert a `RoomRegistry` with a different cap before adding the plugin to override the default
+        app.init_resource::<RoomRegistry>();
+        app.init_resource::<ZoneMembership>();
+        app.init_resource::<RoomOccupancy>();
+        // insert `RoomProperties` before adding the plugin to make some rooms slower or faster
+        app.init_resource::<RoomProperties>();
+        app.insert_resource(CircleSpatialIndex::new(config.room_cell_size));
+        app.insert_resource(HeartbeatTimer(Timer::from_seconds(
+            HEARTBEAT_INTERVAL_SECS,
+            TimerMode::Repeating,
+        )));
+        app.insert_resource(OccupancyTimer(Timer::from_seconds(
+            OCCUPANCY_INTERVAL_SECS,
+            TimerMode::Repeating,
+        )));
+        app.add_event::<ReplicationErrorEvent>();
+        app.add_event::<RoomEnter>();
+        app.add_event::<RoomLeave>();
+        app.add_event::<RelevanceChanged>();
+        app.add_event::<ScoreEvent>();
+        app.add_plugins(ServerDiagnosticsPlugin);
+        app.add_plugins(ReplicationTapPlugin);
+        app.init_resource::<Spectators>();
+        app.init_resource::<SpawnedCircles>();
+        app.init_resource::<TestBurstRng>();
+        // insert `AllowDebugCommands(true)` before adding the plugin, or set the
+        // `ALLOW_DEBUG_COMMANDS` environment variable, to accept the `DebugCommand`s
+        app.init_resource::<AllowDebugCommands>();
+        // insert a different `LeaderboardConfig` before adding the plugin to override the default
+        app.init_resource::<LeaderboardConfig>();
+        app.init_resource::<InputHistory>();
+        // insert an `AdmissionQueue` with a player limit before adding the plugin to cap the
+        // number of players, or with a per-frame limit to spread bursts of connections
+        app.init_resource::<AdmissionQueue>();
+        app.add_systems(Startup, (init, spawn_circles));
         // the physics/FixedUpdates systems that consume inputs should be run in this set
-        app.add_systems(FixedUpdate, movement);
+        app.add_systems(
+            FixedUpdate,
+            (
+                movement.run_if(has_players).run_if(simulation_running),
+                // before interest management, so that the rooms are computed from the clamped positions
+                clamp_positions,
+                expire_spawn_protection.run_if(simulation_running),
+                combat.run_if(simulation_running),
+                award_scores,
+                respawn,
+                handle_spawn_input.run_if(simulation_running),
+                handle_delete_input.run_if(simulation_running),
+                update_input_snapshots,
+                track_input_history,
+                update_spectator_targets,
+            )
+                .chain(),
+        );
         app.add_systems(
             Update,
             (
-                handle_connections,
+                (handle_disconnections, handle_connections).chain(),
+                forget_disconnected_clients,
                 // we don't have to run interest management every tick, only every time
                 // we are buffering replication messages
-                interest_management.in_set(ReplicationSet::SendMessages),
-                receive_message,
-                check_timers,
+                update_circle_index.before(interest_management),
+                tick_churn_breaker.before(interest_management),
+                expire_newly_spawned.before(interest_management),
+                hide_debug_entities.before(interest_management),
+                migrate_room_groups.before(interest_management),
+                rebalance_groups.before(interest_management),
+                (room_assignment, interest_management, cull_distant_players)
+                    .chain()
+                    .run_if(has_players)
+                    .run_if(simulation_running)
+                    .run_if(is_send_frame)
+                    .in_set(ReplicationSet::SendMessages),
+                update_audio_relevance.after(interest_management),
+                update_room_occupancy.after(interest_management),
+                reconcile_visibility
+                    .after(interest_management)
+                    .in_set(ReplicationSet::SendMessages),
+                log_room_transitions.after(interest_management),
+                update_zones
+                    .after(handle_connections)
+                    .after(interest_management)
+                    .in_set(ReplicationSet::SendMessages),
+                (
+                    receive_message,
+                    relay_chat_messages,
+                    receive_interest_radius,
+                    receive_set_name,
+                    receive_move_target,
+                    receive_debug_commands,
+                ),
+                check_timers.run_if(simulation_running),
+                (
+                    broadcast_occupancy,
+                    broadcast_server_time,
+                    broadcast_tick,
+                    broadcast_leaderboard,
+                ),
             ),
         );
+        app.add_systems(PostUpdate, flush_outbox.before(MainSet::Send));
+        // errors can be reported from any schedule, so we drain them at the very end of the frame
+        app.add_systems(Last, log_replication_errors);
+    }
+}
+
+/// Settings of the interest management that can be chosen at startup
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct InterestConfig {
+    /// Interest radius of the clients that didn't request one
+    pub interest_radius: f32,
+    /// Size of a room along both axes. The circles are spawned with the same spacing, one per room
+    pub room_cell_size: f32,
+    /// The grid of circles spans this many rooms on each side of the origin, along both axes
+    pub grid_half_extent: i32,
+    /// When set, rooms are grouped into zones of this many rooms along both axes. The players and
+    /// their text are then replicated to every client in their zone
+    pub zone_size: Option<i32>,
+    /// A relevant circle only loses relevance once it is outside of the interest radius multiplied
+    /// by this factor, so that a player hovering at the boundary doesn't make it flicker
+    pub lose_relevance_factor: f32,
+    /// Circles spawned less than `spawn_expansion_secs` ago are evaluated with the interest radius
+    /// multiplied by this factor, so that a fast player doesn't miss them
+    pub spawn_expansion_factor: f32,
+    pub spawn_expansion_secs: f32,
+}
+
+impl Default for InterestConfig {
+    fn default() -> Self {
+        Self {
+            interest_radius: 150.0,
+            room_cell_size: shared::ROOM_SIZE,
+            grid_half_extent: 10,
+            zone_size: None,
+            lose_relevance_factor: 1.15,
+            spawn_expansion_factor: 1.5,
+            spawn_expansion_secs: 0.5,
+        }
+    }
+}
+
+impl InterestConfig {
+    /// Get the room that contains the given position
+    pub(crate) fn room_id_for_position(&self, position: Vec2) -> RoomId {
+        room_id_for_position(position, self.room_cell_size)
+    }
+
+    /// Distance from the origin to the edges of the grid of circles, along both axes
+    pub(crate) fn half_extent(&self) -> f32 {
+        self.grid_half_extent as f32 * self.room_cell_size
     }
 }
 
@@ -41,8 +274,31 @@ impl Plugin for ExampleServerPlugin {
 pub(crate) struct Global {
     pub client_id_to_entity_id: HashMap<ClientId, Entity>,
     pub client_id_to_room_id: HashMap<ClientId, RoomId>,
+    /// The `PlayerText` entity attached to each player entity
+    pub player_to_text_entity: HashMap<Entity, Entity>,
+}
+
+/// Run condition: true if at least one client has a player.
+/// The systems that only do work for players use this to skip their queries entirely otherwise
+pub(crate) fn has_players(global: Res<Global>) -> bool {
+    !global.client_id_to_entity_id.is_empty()
+}
+
+/// When true, the world is frozen: players don't move, don't change rooms and the timers are
+/// stopped. Clients stay connected and new clients can still join
+#[derive(Resource, Default)]
+pub(crate) struct SimulationPaused(pub bool);
+
+/// Run condition: true unless the simulation is paused with `SimulationPaused`
+pub(crate) fn simulation_running(paused: Res<SimulationPaused>) -> bool {
+    !paused.0
 }
 
+/// Keeps track of which circles are currently relevant to each client.
+/// This mirrors the calls we make to the `RelevanceManager`, which doesn't let us read them back
+#[derive(Resource, Default)]
+pub(crate) struct RelevantCircles(pub HashMap<ClientId, HashSet<Entity>>);
+
 pub(crate) fn init(mut commands: Commands) {
     commands.start_server();
     commands.spawn(
@@ -59,114 +315,4429 @@ pub(crate) fn init(mut commands: Commands) {
             ..default()
         }),
     );
+}
 
-    // spawn dots in a grid
-    for x in -NUM_CIRCLES..NUM_CIRCLES {
-        for y in -NUM_CIRCLES..NUM_CIRCLES {
-            commands.spawn((
-                Position(Vec2::new(x as f32 * GRID_SIZE, y as f32 * GRID_SIZE)),
-                CircleMarker,
-                Replicate {
-                    // use rooms for replication
-                    relevance_mode: NetworkRelevanceMode::InterestManagement,
-                    ..default()
-                },
-            ));
+/// The grid of circles spawned by `spawn_circles`
+pub(crate) fn circle_grid(config: &InterestConfig) -> GridDescriptor {
+    GridDescriptor {
+        origin: Vec2::splat(-config.half_extent()),
+        spacing: config.room_cell_size,
+        columns: 2 * config.grid_half_extent as u32,
+        rows: 2 * config.grid_half_extent as u32,
+    }
+}
+
+/// When enabled, the static grid of circles is not replicated entity by entity: clients receive
+/// a `GridDescriptor` when their player spawns and spawn the circles locally.
+/// Only the circles spawned later, which can move, are replicated
+#[derive(Resource, Default)]
+pub(crate) struct CompactCircleGrid(pub bool);
+
+/// Marker for the circles of the static grid, when they are not replicated
+#[derive(Component, Clone, Copy, Debug)]
+pub(crate) struct StaticCircle;
+
+/// Spawn dots in a grid
+pub(crate) fn spawn_circles(
+    mut commands: Commands,
+    config: Res<InterestConfig>,
+    group_strategy: Res<GroupStrategy>,
+    compact_grid: Res<CompactCircleGrid>,
+) {
+    for position in circle_grid(&config).positions() {
+        if compact_grid.0 {
+            commands.spawn((Position(position), CircleMarker, CircleActive(true), StaticCircle));
+            continue;
+        }
+        commands.spawn((
+            Position(position),
+            CircleMarker,
+            CircleActive(true),
+            ReplicationCategory::Circle,
+            Replicate {
+                group: group_strategy.replication_group(
+                    None,
+                    config.room_id_for_position(position),
+                    None,
+                ),
+                // use rooms for replication
+                relevance_mode: NetworkRelevanceMode::InterestManagement,
+                ..default()
+            },
+        ));
+    }
+}
+
+/// Bring the world back to its initial state: despawn all players and circles, clear the
+/// room and relevance state, and spawn the grid of circles again.
+/// Connected clients stay connected: they go back to the front of the `AdmissionQueue`, so that
+/// `handle_connections` gives them a new player. Can be queued with `commands.add(reset_world)`
+pub(crate) fn reset_world(world: &mut World) {
+    let global = std::mem::take(&mut *world.resource_mut::<Global>());
+    let relevant_circles = std::mem::take(&mut *world.resource_mut::<RelevantCircles>());
+    world.resource_mut::<PausedClients>().0.clear();
+    world.resource_mut::<AudioRelevantEntities>().0.clear();
+    world.resource_mut::<RelevanceBudget>().clear();
+    world.resource_mut::<RoomRegistry>().clear();
+    world.resource_mut::<SpawnedCircles>().0.clear();
+    world.resource_mut::<AdmissionQueue>().readmit_all();
+    world.resource_mut::<ReconciledVisibility>().clear();
+    world.resource_mut::<ChurnBreaker>().clear();
+    world.resource_mut::<CulledPlayers>().clear();
+    world.resource_mut::<InputHistory>().clear();
+    world.resource_mut::<TeamAssignment>().reset();
+    let follow_targets = std::mem::take(&mut *world.resource_mut::<FollowTargets>());
+    let mut zones = std::mem::take(&mut *world.resource_mut::<ZoneMembership>());
+
+    let mut room_manager = world.resource_mut::<RoomManager>();
+    // leave every zone
+    zones.sync(&mut room_manager, HashMap::default(), HashMap::default());
+    for (follower, follow) in follow_targets.0.iter() {
+        if let Some(room) = follow.room {
+            room_manager.remove_client(*follower, room);
+        }
+    }
+    for (client_id, room) in global.client_id_to_room_id.iter() {
+        if !follow_targets.0.contains_key(client_id) {
+            room_manager.remove_client(*client_id, *room);
+        }
+        if let Some(entity) = global.client_id_to_entity_id.get(client_id) {
+            remove_player_from_room(&mut room_manager, &global, *entity, *room);
         }
     }
+    let mut relevance_manager = world.resource_mut::<RelevanceManager>();
+    for (client_id, circles) in relevant_circles.0.iter() {
+        for circle in circles {
+            relevance_manager.lose_relevance(*client_id, *circle);
+        }
+    }
+
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<PlayerId>, With<PlayerParent>, With<CircleMarker>)>>()
+        .iter(world)
+        .collect();
+    for entity in entities {
+        world.despawn(entity);
+    }
+    world.run_system_once(spawn_circles);
 }
 
-/// Server connection system, create a player upon connection
+/// Add a player entity to a room, along with its text entity.
+/// The text entity uses interest management like its parent, so if it wasn't in the parent's
+/// room it would never be replicated; being in the same room, it is relevant exactly when the
+/// parent is
+pub(crate) fn add_player_to_room(
+    room_manager: &mut RoomManager,
+    global: &Global,
+    entity: Entity,
+    room: RoomId,
+) {
+    room_manager.add_entity(entity, room);
+    if let Some(text_entity) = global.player_to_text_entity.get(&entity) {
+        room_manager.add_entity(*text_entity, room);
+    }
+}
+
+/// Remove a player entity from a room, along with its text entity
+pub(crate) fn remove_player_from_room(
+    room_manager: &mut RoomManager,
+    global: &Global,
+    entity: Entity,
+    room: RoomId,
+) {
+    room_manager.remove_entity(entity, room);
+    if let Some(text_entity) = global.player_to_text_entity.get(&entity) {
+        room_manager.remove_entity(*text_entity, room);
+    }
+}
+
+fn log_evicted_rooms(evicted: Vec<RoomId>) {
+    for room in evicted {
+        info!("Evicted empty room {}", room.0);
+    }
+}
+
+/// Server connection system: connecting clients wait in the `AdmissionQueue`, and a player is
+/// created for each client once it gets admitted
 pub(crate) fn handle_connections(
+    mut global: ResMut<Global>,
     mut room_manager: ResMut<RoomManager>,
+    mut room_registry: ResMut<RoomRegistry>,
+    mut admission_queue: ResMut<AdmissionQueue>,
+    mut team_assignment: ResMut<TeamAssignment>,
+    mut outbox: ResMut<Outbox>,
+    config: Res<InterestConfig>,
+    player_names: Res<PlayerNames>,
+    interest_radius: Res<ClientInterestRadius>,
+    compact_grid: Res<CompactCircleGrid>,
     mut connections: EventReader<ConnectEvent>,
     mut commands: Commands,
+    group_strategy: Res<GroupStrategy>,
 ) {
+    let mut queue_changed = false;
     for connection in connections.read() {
-        let client_id = connection.client_id;
-        let entity = commands.spawn(PlayerBundle::new(client_id, Vec2::ZERO)).id();
-        let text_entity = commands.spawn(PlayerTextBundle::new(client_id, entity)).id();
-
-        // we can control the player visibility in a more static manner by using rooms
-        // we add all clients to a room, as well as all player entities
-        // this means that all clients will be able to see all player entities
-        room_manager.add_client(client_id, RoomId(0));
-        room_manager.add_entity(entity, RoomId(0));
+        admission_queue.enqueue(connection.client_id);
+        queue_changed = true;
+    }
+    // slots can also have been freed by `handle_disconnections`
+    for client_id in admission_queue.admit_waiting() {
+        queue_changed = true;
+        let room = config.room_id_for_position(SPAWN_POSITION);
+        let team = team_assignment.assign();
+        let entity = commands
+            .spawn((
+                PlayerBundle::new(client_id, SPAWN_POSITION, room, team, *group_strategy),
+                Shielded,
+                SpawnProtection::default(),
+            ))
+            .id();
+        let text_entity = commands
+            .spawn(PlayerTextBundle::new(
+                client_id,
+                entity,
+                room,
+                *group_strategy,
+            ))
+            .id();
+
+        // we control the player visibility in a more static manner by using rooms:
+        // a client and its player entity are always in exactly one room, the one that contains
+        // the player's position. There is no 'lobby' room: the spawn room (room 0) is left as
+        // soon as the player walks out of it, like any other room (see `room_assignment`).
+        // This means that clients only see the players that are in the same room as them
+        global.client_id_to_entity_id.insert(client_id, entity);
+        global.client_id_to_room_id.insert(client_id, room);
+        global.player_to_text_entity.insert(entity, text_entity);
+        room_manager.add_client(client_id, room);
+        add_player_to_room(&mut room_manager, &global, entity, room);
+        log_evicted_rooms(room_registry.enter(room, &mut room_manager));
         commands.entity(text_entity).insert(TimerComponent(Timer::from_seconds(5.0, TimerMode::Once)));
+        // the client may have chosen its name while it was waiting in the queue
+        if let Some(name) = player_names.0.get(&client_id) {
+            commands.entity(entity).insert(PlayerName(name.clone()));
+        }
+        send_world_config(&mut outbox, &interest_radius, &config, client_id);
+        if compact_grid.0 {
+            outbox.send(
+                circle_grid(&config),
+                NetworkTarget::Single(client_id),
+                MessagePriority::High,
+                "send circle grid",
+            );
+        }
+    }
+    if queue_changed {
+        for (client_id, position) in admission_queue.waiting() {
+            outbox.send(
+                QueuePosition(position as u32),
+                NetworkTarget::Single(client_id),
+                MessagePriority::Normal,
+                "send queue position",
+            );
+        }
+    }
+}
+
+/// Puts the players in teams in turn, in the order they are admitted
+#[derive(Resource)]
+pub(crate) struct TeamAssignment {
+    pub num_teams: u8,
+    next: u8,
+}
+
+impl Default for TeamAssignment {
+    fn default() -> Self {
+        Self {
+            num_teams: 2,
+            next: 0,
+        }
+    }
+}
+
+impl TeamAssignment {
+    /// The team of the next player
+    pub(crate) fn assign(&mut self) -> u8 {
+        let team = self.next;
+        self.next = (self.next + 1) % self.num_teams.max(1);
+        team
+    }
+
+    /// Start again from the first team
+    pub(crate) fn reset(&mut self) {
+        self.next = 0;
+    }
+}
+
+/// Remove the player of each disconnected client, freeing its slot for the next waiting client.
+/// The player and its text are despawned, and the client and its entities are removed from
+/// their rooms so that they stop counting towards relevance
+pub(crate) fn handle_disconnections(
+    mut global: ResMut<Global>,
+    mut room_manager: ResMut<RoomManager>,
+    mut room_registry: ResMut<RoomRegistry>,
+    mut admission_queue: ResMut<AdmissionQueue>,
+    mut follow_targets: ResMut<FollowTargets>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut commands: Commands,
+) {
+    for disconnection in disconnections.read() {
+        let client_id = disconnection.client_id;
+        if let Some(FollowTarget { room: Some(room), .. }) = follow_targets.0.remove(&client_id) {
+            room_manager.remove_client(client_id, room);
+        }
+        // a client that was still waiting doesn't have a player yet
+        if !admission_queue.remove(client_id) {
+            continue;
+        }
+        let Some(entity) = global.client_id_to_entity_id.remove(&client_id) else {
+            continue;
+        };
+        if let Some(room) = global.client_id_to_room_id.remove(&client_id) {
+            room_manager.remove_client(client_id, room);
+            remove_player_from_room(&mut room_manager, &global, entity, room);
+            room_registry.leave(room);
+        }
+        if let Some(text_entity) = global.player_to_text_entity.remove(&entity) {
+            commands.entity(text_entity).despawn();
+        }
+        commands.entity(entity).despawn();
     }
 }
 
+/// Forget the per-client state of each disconnected client, and despawn the circles it spawned
+pub(crate) fn forget_disconnected_clients(
+    mut commands: Commands,
+    mut room_manager: ResMut<RoomManager>,
+    mut room_registry: ResMut<RoomRegistry>,
+    mut relevant_circles: ResMut<RelevantCircles>,
+    mut audio_relevant: ResMut<AudioRelevantEntities>,
+    mut player_names: ResMut<PlayerNames>,
+    mut relevance_budget: ResMut<RelevanceBudget>,
+    mut churn_breaker: ResMut<ChurnBreaker>,
+    mut culled_players: ResMut<CulledPlayers>,
+    mut interest_radius: ResMut<ClientInterestRadius>,
+    mut paused_clients: ResMut<PausedClients>,
+    mut spectators: ResMut<Spectators>,
+    mut input_history: ResMut<InputHistory>,
+    mut spawned_circles: ResMut<SpawnedCircles>,
+    mut disconnections: EventReader<DisconnectEvent>,
+) {
+    for disconnection in disconnections.read() {
+        let client_id = disconnection.client_id;
+        relevant_circles.0.remove(&client_id);
+        audio_relevant.0.remove(&client_id);
+        player_names.0.remove(&client_id);
+        relevance_budget.remove_client(client_id);
+        churn_breaker.remove_client(client_id);
+        culled_players.remove_client(client_id);
+        interest_radius.0.remove(&client_id);
+        paused_clients.0.remove(&client_id);
+        input_history.remove_client(client_id);
+        // nobody could delete the circles of the client anymore
+        for circle in spawned_circles.0.remove(&client_id).unwrap_or_default() {
+            despawn_spawned_circle(
+                &mut commands,
+                &circle,
+                &mut room_manager,
+                &mut room_registry,
+                &mut relevant_circles,
+                &mut relevance_budget,
+            );
+        }
+        // only touch `Spectators` if needed, its change detection updates the input snapshot targets
+        if spectators.0.contains(&client_id) {
+            spectators.0.remove(&client_id);
+        }
+    }
+}
 
 #[derive(Component)]
 pub struct TimerComponent(Timer);
 pub(crate) fn check_timers(mut commands: Commands,
-    mut timers: Query<(Entity, &mut PlayerText, &mut TimerComponent)>,
+    mut timers: Query<(Entity, &PlayerParent, &mut PlayerText, &mut TimerComponent)>,
+    players: Query<&CurrentRoom>,
+    viewers: RoomViewers,
+    mut outbox: ResMut<Outbox>,
     time: Res<Time>
 ) {
-    for (entity, mut player_text, mut timer) in &mut timers {
+    for (entity, parent, mut player_text, mut timer) in &mut timers {
         timer.0.tick(time.delta());
 
         if timer.0.finished() {
             info!("Timer finished");
-            // TODO - Why is this not replicating to the client?
-            player_text.0 = "Server changed".to_string();
+            if let Ok(room) = players.get(parent.0) {
+                set_player_text(entity, &mut player_text, "Server changed", room.0, &viewers, &mut outbox);
+            }
             commands.entity(entity).remove::<TimerComponent>();
         }
     }
 }
 
+/// Who can see the entities of a room: the clients in the room, or in its zone when zones are
+/// enabled. Paused clients are in no room, and followers are in the room of the player they follow
+#[derive(SystemParam)]
+pub(crate) struct RoomViewers<'w> {
+    config: Res<'w, InterestConfig>,
+    global: Res<'w, Global>,
+    paused_clients: Res<'w, PausedClients>,
+    follow_targets: Res<'w, FollowTargets>,
+}
+
+impl RoomViewers<'_> {
+    pub(crate) fn clients(&self, room: RoomId) -> Vec<ClientId> {
+        let sees = |client_room: RoomId| match self.config.zone_size {
+            Some(zone_size) => zone_for_room(client_room, zone_size) == zone_for_room(room, zone_size),
+            None => client_room == room,
+        };
+        self.global
+            .client_id_to_room_id
+            .iter()
+            .filter(|(client_id, _)| !self.paused_clients.0.contains(*client_id))
+            .filter_map(|(client_id, own_room)| {
+                let client_room = match self.follow_targets.0.get(client_id) {
+                    Some(follow) => follow.room?,
+                    None => *own_room,
+                };
+                sees(client_room).then_some(*client_id)
+            })
+            .collect()
+    }
+}
+
+/// Change the text of a `PlayerText` entity by sending a `TextEdit` with only the part that changed
+/// to the clients that can see the entity in `room`, instead of replicating the whole string again.
+/// Change detection is bypassed so that lightyear doesn't also send the full component; clients
+/// that start seeing the entity later still receive its current text with the entity spawn.
+/// The edit carries the hash of the text it applies to, so a client that received the new text with
+/// the spawn, or that missed an earlier edit, ignores it instead of corrupting its text.
+/// Every change of a `PlayerText` on the server must go through here: mixing in direct writes
+/// makes the edits miss, see `PlayerText`
+pub(crate) fn set_player_text(
+    entity: Entity,
+    player_text: &mut Mut<PlayerText>,
+    text: &str,
+    room: RoomId,
+    viewers: &RoomViewers,
+    outbox: &mut Outbox,
+) {
+    let Some(edit) = TextEdit::diff(entity, &player_text.0, text) else {
+        return;
+    };
+    debug!(
+        "Sending a text edit of {} bytes instead of {} bytes",
+        estimate_size(&edit),
+        estimate_size(&PlayerText(text.to_string())),
+    );
+    let clients = viewers.clients(room);
+    player_text.bypass_change_detection().0 = text.to_string();
+    outbox.send(
+        edit,
+        NetworkTarget::Only(clients),
+        MessagePriority::Normal,
+        "send text edit",
+    );
+}
+
 pub(crate) fn receive_message(mut messages: EventReader<MessageEvent<Message1>>) {
     for message in messages.read() {
         info!("recv message");
     }
 }
 
-/// Here we perform more "immediate" interest management: we will make a circle visible to a client
-/// depending on the distance to the client's entity
-pub(crate) fn interest_management(
-    mut relevance_manager: ResMut<RelevanceManager>,
-    mut room_manager: ResMut<RoomManager>,
-    mut player_query: Query<
-        (&PlayerId, Entity, Ref<Position>, &mut LastPosition),
-        (Without<CircleMarker>, With<ReplicationTarget>),
-    >,
-    circle_query: Query<(Entity, &Position), (With<CircleMarker>, With<ReplicationTarget>)>,
-) {
-    for (client_id, entity, position, last_position) in player_query.iter_mut() {
-        if position.is_changed() {
-            let last_room = RoomId((last_position.0.x / 200.0) as i32 as u64);
-            let new_room = RoomId((position.0.x / 200.0) as i32 as u64);
-
-            // TODO - Leaving the room and coming back breaks the replication?
-            if last_room != new_room {
-                info!("Client {} moved to room {} from room {}", client_id.0, new_room.0, last_room.0);
-                room_manager.remove_client(client_id.0, last_room);
-                room_manager.remove_entity(entity, last_room);
-                room_manager.add_client(client_id.0, new_room);
-                room_manager.add_entity(entity, new_room);
-            }
-            
-            // in real game, you would have a spatial index (kd-tree) to only find entities within a certain radius
-            for (circle_entity, circle_position) in circle_query.iter() {
-                let distance = position.distance(**circle_position);
-                if distance < INTEREST_RADIUS {
-                    relevance_manager.gain_relevance(client_id.0, circle_entity);
-                } else {
-                    relevance_manager.lose_relevance(client_id.0, circle_entity);
+/// Relay the chat messages to the other clients in the sender's room
+pub(crate) fn relay_chat_messages(
+    global: Res<Global>,
+    mut outbox: ResMut<Outbox>,
+    mut messages: EventReader<MessageEvent<ChatMessage>>,
+) {
+    for message in messages.read() {
+        let sender = *message.context();
+        // a client that doesn't have a player yet is not in any room
+        let Some(room) = global.client_id_to_room_id.get(&sender) else {
+            continue;
+        };
+        let text = message.message().text.clone();
+        info!("Client {} says: {}", sender, text);
+        let clients: Vec<ClientId> = global
+            .client_id_to_room_id
+            .iter()
+            .filter(|(client_id, client_room)| **client_id != sender && *client_room == room)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        outbox.send(
+            ChatMessage { sender, text },
+            NetworkTarget::Only(clients),
+            MessagePriority::Normal,
+            "relay chat message",
+        );
+    }
+}
+
+/// The interest radius requested by each client, already clamped to the allowed bounds
+#[derive(Resource, Default)]
+pub(crate) struct ClientInterestRadius(pub HashMap<ClientId, f32>);
+
+impl ClientInterestRadius {
+    /// The interest radius to use for a client, the configured default if it didn't request one
+    pub(crate) fn radius(&self, client_id: ClientId, config: &InterestConfig) -> f32 {
+        self.0.get(&client_id).copied().unwrap_or(config.interest_radius)
+    }
+
+    /// Store the radius requested by a client, clamped to the allowed bounds
+    pub(crate) fn set(&mut self, client_id: ClientId, requested: f32, config: &InterestConfig) -> f32 {
+        // NaN would go through `clamp` unchanged
+        let radius = if requested.is_nan() {
+            config.interest_radius
+        } else {
+            requested.clamp(MIN_INTEREST_RADIUS, MAX_INTEREST_RADIUS)
+        };
+        self.0.insert(client_id, radius);
+        radius
+    }
+}
+
+/// Tell a client the values that the server uses for it
+fn send_world_config(
+    outbox: &mut Outbox,
+    interest_radius: &ClientInterestRadius,
+    config: &InterestConfig,
+    client_id: ClientId,
+) {
+    outbox.send(
+        WorldConfig {
+            interest_radius: interest_radius.radius(client_id, config),
+        },
+        NetworkTarget::Single(client_id),
+        MessagePriority::Normal,
+        "send world config",
+    );
+}
+
+pub(crate) fn receive_interest_radius(
+    global: Res<Global>,
+    config: Res<InterestConfig>,
+    mut interest_radius: ResMut<ClientInterestRadius>,
+    mut outbox: ResMut<Outbox>,
+    mut messages: EventReader<MessageEvent<SetInterestRadius>>,
+    mut positions: Query<&mut Position>,
+) {
+    for message in messages.read() {
+        let client_id = *message.context();
+        let radius = interest_radius.set(client_id, message.message().0, &config);
+        info!("Client {} uses an interest radius of {}", client_id, radius);
+        send_world_config(&mut outbox, &interest_radius, &config, client_id);
+        // mark the player as changed so that its relevance gets re-evaluated with the new radius
+        if let Some(mut position) = global
+            .client_id_to_entity_id
+            .get(&client_id)
+            .and_then(|entity| positions.get_mut(*entity).ok())
+        {
+            position.set_changed();
+        }
+    }
+}
+
+/// Validate the names requested by the clients, and give them to their player once accepted
+pub(crate) fn receive_set_name(
+    global: Res<Global>,
+    validation: Res<NameValidation>,
+    mut player_names: ResMut<PlayerNames>,
+    mut messages: EventReader<MessageEvent<SetName>>,
+    mut commands: Commands,
+) {
+    for message in messages.read() {
+        let client_id = *message.context();
+        let taken = player_names
+            .0
+            .iter()
+            .filter(|(other, _)| **other != client_id)
+            .map(|(_, name)| name.as_str());
+        match validation.validate(&message.message().0, taken) {
+            Ok(name) => {
+                info!("Client {} is named {:?}", client_id, name);
+                if let Some(entity) = global.client_id_to_entity_id.get(&client_id) {
+                    commands.entity(*entity).insert(PlayerName(name.clone()));
                 }
+                player_names.0.insert(client_id, name);
+            }
+            Err(rejection) => {
+                warn!("Rejected the name {:?} of client {}: {:?}", message.message().0, client_id, rejection);
             }
         }
     }
-    for (client_id, entity, position, mut last_position) in player_query.iter_mut() {
-        last_position.0 = position.0;
+}
+
+/// Maximum number of circles that can be relevant to a client at the same time.
+/// When more circles are in range, only the nearest ones are replicated
+#[derive(Resource)]
+pub(crate) struct MaxRelevantCircles(pub usize);
+
+impl Default for MaxRelevantCircles {
+    fn default() -> Self {
+        Self(64)
     }
 }
 
-/// Read client inputs and move players
-pub(crate) fn movement(
-    mut position_query: Query<(&mut Position, &ActionState<Inputs>), Without<InputMap<Inputs>>>,
+/// With `GroupStrategy::PerRoom`, the replication group of an entity must change when it moves
+/// to another room. The text entities follow their parent
+pub(crate) fn migrate_room_groups(
+    group_strategy: Res<GroupStrategy>,
+    config: Res<InterestConfig>,
+    global: Res<Global>,
+    mut moved: Query<(Entity, &Position, &mut ReplicationGroup), Changed<Position>>,
+    mut texts: Query<&mut ReplicationGroup, (With<PlayerParent>, Without<Position>)>,
+) {
+    if *group_strategy != GroupStrategy::PerRoom {
+        return;
+    }
+    for (entity, position, mut group) in moved.iter_mut() {
+        let room = config.room_id_for_position(position.0);
+        if group.group_id(Some(entity)).0 == room.0 {
+            continue;
+        }
+        *group = group_strategy.replication_group(None, room, None);
+        if let Some(mut text_group) = global
+            .player_to_text_entity
+            .get(&entity)
+            .and_then(|text_entity| texts.get_mut(*text_entity).ok())
+        {
+            *text_group = group_strategy.replication_group(None, room, None);
+        }
+    }
+}
+
+/// Periodically reassigns the replication group of every entity according to the active
+/// `GroupStrategy`, which fixes the groups that drifted, for example if the strategy changed.
+/// If `max_group_size` is set, the entities over the cap in a group are moved to their own group
+#[derive(Resource)]
+pub(crate) struct GroupRebalancing {
+    pub timer: Timer,
+    pub max_group_size: Option<usize>,
+}
+
+impl Default for GroupRebalancing {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(GROUP_REBALANCE_INTERVAL_SECS, TimerMode::Repeating),
+            max_group_size: None,
+        }
+    }
+}
+
+pub(crate) fn rebalance_groups(
+    mut rebalancing: ResMut<GroupRebalancing>,
+    group_strategy: Res<GroupStrategy>,
+    config: Res<InterestConfig>,
+    time: Res<Time>,
+    mut entities: Query<(
+        Entity,
+        Option<&Position>,
+        Option<&ControlledBy>,
+        Option<&PlayerParent>,
+        &mut ReplicationGroup,
+    ), With<ReplicationCategory>>,
+) {
+    rebalancing.timer.tick(time.delta());
+    if !rebalancing.timer.just_finished() {
+        return;
+    }
+    // the owner is the client that controls the entity: players and the circles they spawned
+    let owner_of = |controlled_by: Option<&ControlledBy>| match controlled_by?.target {
+        NetworkTarget::Single(client_id) => Some(client_id),
+        _ => None,
+    };
+    // texts don't have a position of their own, they use their parent's
+    let mut desired: Vec<(Entity, ReplicationGroup)> = entities
+        .iter()
+        .map(|(entity, position, controlled_by, parent, _)| {
+            let parent_entity = parent.map(|parent| parent.0);
+            let source = parent_entity.and_then(|parent| entities.get(parent).ok());
+            let position = position.or(source.and_then(|(_, position, ..)| position));
+            let owner = owner_of(controlled_by)
+                .or(source.and_then(|(_, _, controlled_by, ..)| owner_of(controlled_by)));
+            let room =
+                config.room_id_for_position(position.map_or(SPAWN_POSITION, |position| position.0));
+            let group = group_strategy.replication_group(parent_entity, room, owner);
+            (entity, group)
+        })
+        .collect();
+
+    if let Some(max_group_size) = rebalancing.max_group_size {
+        // sort so that the same entities stay in the group on every pass
+        desired.sort_by_key(|(entity, group)| (group.group_id(Some(*entity)).0, *entity));
+        let mut group_sizes: HashMap<u64, usize> = HashMap::default();
+        for (entity, group) in desired.iter_mut() {
+            let size = group_sizes.entry(group.group_id(Some(*entity)).0).or_default();
+            *size += 1;
+            if *size > max_group_size {
+                *group = ReplicationGroup::default();
+            }
+        }
+    }
+
+    let mut reassigned = 0;
+    for (entity, group) in desired {
+        let Ok((.., mut current)) = entities.get_mut(entity) else {
+            continue;
+        };
+        if current.group_id(Some(entity)).0 != group.group_id(Some(entity)).0 {
+            *current = group;
+            reassigned += 1;
+        }
+    }
+    if reassigned > 0 {
+        info!("Rebalancing moved {} entities to another replication group", reassigned);
+    }
+}
+
+/// Debug entities are never replicated: switching them to interest management without ever
+/// gaining relevance means that no client receives them
+pub(crate) fn hide_debug_entities(
+    mut entities: Query<(&ReplicationCategory, &mut NetworkRelevanceMode), Added<ReplicationCategory>>,
 ) {
-    for (position, input) in position_query.iter_mut() {
-        shared_movement_behaviour(position, input);
+    for (category, mut relevance_mode) in entities.iter_mut() {
+        if *category == ReplicationCategory::Debug {
+            *relevance_mode = NetworkRelevanceMode::InterestManagement;
+        }
+    }
+}
+
+/// Global cap on the number of circle relevance updates applied per tick, across all clients.
+/// Updates over the cap stay queued for the next ticks. Clients are served in round-robin
+/// (one update each in turn, starting from a different client every tick) so that none is starved
+#[derive(Resource, Default)]
+pub(crate) struct RelevanceBudget {
+    /// `None` applies every update immediately
+    pub max_updates_per_tick: Option<usize>,
+    pending: HashMap<ClientId, VecDeque<(Entity, Relevance)>>,
+    round: usize,
+}
+
+impl RelevanceBudget {
+    /// Forget the queued updates of a client, e.g. when it disconnects
+    pub(crate) fn remove_client(&mut self, client_id: ClientId) {
+        self.pending.remove(&client_id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Forget the queued updates of an entity, e.g. when it is despawned
+    pub(crate) fn remove_entity(&mut self, entity: Entity) {
+        for updates in self.pending.values_mut() {
+            updates.retain(|(pending, _)| *pending != entity);
+        }
+    }
+
+    /// Number of updates still waiting for budget
+    pub(crate) fn num_pending(&self) -> usize {
+        self.pending.values().map(VecDeque::len).sum()
+    }
+
+    /// Apply the queued updates that fit in this tick's budget.
+    /// The updates of the clients frozen by the `ChurnBreaker` stay queued
+    fn apply(
+        &mut self,
+        relevance_manager: &mut RelevanceManager,
+        relevant_circles: &mut RelevantCircles,
+        churn_breaker: &mut ChurnBreaker,
+        metrics: &mut RelevanceMetrics,
+        relevance_changes: &mut EventWriter<RelevanceChanged>,
+    ) {
+        let mut clients: Vec<ClientId> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|client_id| !churn_breaker.is_frozen(*client_id))
+            .collect();
+        if clients.is_empty() {
+            return;
+        }
+        clients.sort_by_key(|client_id| client_id.to_bits());
+        clients.rotate_left(self.round % clients.len());
+        self.round = self.round.wrapping_add(1);
+
+        let mut budget = self.max_updates_per_tick.unwrap_or(usize::MAX);
+        while budget > 0 {
+            let mut applied = false;
+            for client_id in clients.iter() {
+                if budget == 0 {
+                    break;
+                }
+                let Some((entity, relevance)) = self
+                    .pending
+                    .get_mut(client_id)
+                    .and_then(VecDeque::pop_front)
+                else {
+                    continue;
+                };
+                let relevant = relevant_circles.0.entry(*client_id).or_default();
+                match relevance {
+                    Relevance::Relevant => {
+                        relevance_manager.gain_relevance(*client_id, entity);
+                        relevant.insert(entity);
+                        metrics.record_gain();
+                    }
+                    Relevance::NotRelevant => {
+                        relevance_manager.lose_relevance(*client_id, entity);
+                        relevant.remove(&entity);
+                        metrics.record_loss();
+                    }
+                }
+                churn_breaker.record(*client_id);
+                relevance_changes.send(RelevanceChanged {
+                    client: *client_id,
+                    entity,
+                });
+                budget -= 1;
+                applied = true;
+            }
+            if !applied {
+                break;
+            }
+        }
+        self.pending.retain(|_, updates| !updates.is_empty());
+    }
+}
+
+/// Circuit breaker for runaway relevance churn: a client whose circles gain or lose relevance
+/// more than `max_changes_per_window` times within a window gets its relevance changes frozen
+/// for `freeze_duration`, so that a bug flipping relevance every tick can't explode the bandwidth
+#[derive(Resource)]
+pub(crate) struct ChurnBreaker {
+    pub max_changes_per_window: usize,
+    pub freeze_duration: Duration,
+    window: Timer,
+    changes: HashMap<ClientId, usize>,
+    /// Time (from `Time::elapsed`) until which each frozen client stays frozen
+    frozen_until: HashMap<ClientId, Duration>,
+}
+
+impl Default for ChurnBreaker {
+    fn default() -> Self {
+        Self {
+            max_changes_per_window: CHURN_MAX_CHANGES_PER_WINDOW,
+            freeze_duration: Duration::from_secs_f32(CHURN_FREEZE_SECS),
+            window: Timer::from_seconds(CHURN_WINDOW_SECS, TimerMode::Repeating),
+            changes: HashMap::default(),
+            frozen_until: HashMap::default(),
+        }
+    }
+}
+
+impl ChurnBreaker {
+    pub(crate) fn is_frozen(&self, client_id: ClientId) -> bool {
+        self.frozen_until.contains_key(&client_id)
+    }
+
+    /// Count a relevance change of a client
+    fn record(&mut self, client_id: ClientId) {
+        *self.changes.entry(client_id).or_default() += 1;
+    }
+
+    pub(crate) fn remove_client(&mut self, client_id: ClientId) {
+        self.changes.remove(&client_id);
+        self.frozen_until.remove(&client_id);
+    }
+
+    /// Forget the changes counted so far and unfreeze every client
+    pub(crate) fn clear(&mut self) {
+        self.changes.clear();
+        self.frozen_until.clear();
+    }
+
+    /// Unfreeze the clients whose freeze expired, and at the end of each window freeze the clients
+    /// that went over the threshold
+    fn tick(&mut self, delta: Duration, now: Duration) {
+        self.frozen_until.retain(|client_id, until| {
+            let frozen = now < *until;
+            if !frozen {
+                info!("Relevance changes of client {} are no longer frozen", client_id);
+            }
+            frozen
+        });
+        self.window.tick(delta);
+        if !self.window.just_finished() {
+            return;
+        }
+        for (client_id, changes) in self.changes.drain() {
+            if changes > self.max_changes_per_window {
+                warn!(
+                    "Client {} had {} relevance changes in {:?}, freezing its relevance for {:?}",
+                    client_id,
+                    changes,
+                    self.window.duration(),
+                    self.freeze_duration
+                );
+                self.frozen_until.insert(client_id, now + self.freeze_duration);
+            }
+        }
+    }
+}
+
+pub(crate) fn tick_churn_breaker(mut churn_breaker: ResMut<ChurnBreaker>, time: Res<Time>) {
+    churn_breaker.tick(time.delta(), time.elapsed());
+}
+
+/// Clients that currently don't receive any replication updates
+#[derive(Resource, Default)]
+pub(crate) struct PausedClients(pub HashSet<ClientId>);
+
+/// Stop replicating to a client, for example while it is on a loading screen.
+/// The client is removed from its room and loses relevance of all circles; its player keeps being
+/// replicated to the other clients.
+/// Can be queued with `commands.add(move |world: &mut World| pause_replication(world, client_id))`
+pub(crate) fn pause_replication(world: &mut World, client_id: ClientId) {
+    if !world.resource_mut::<PausedClients>().0.insert(client_id) {
+        return;
+    }
+    // a follower is in the room of the player it follows, it will be put back there on resume
+    let follow_room = world
+        .resource_mut::<FollowTargets>()
+        .0
+        .get_mut(&client_id)
+        .map(|follow| follow.room.take());
+    let room = match follow_room {
+        Some(room) => room,
+        None => world.resource::<Global>().client_id_to_room_id.get(&client_id).copied(),
+    };
+    if let Some(room) = room {
+        world.resource_mut::<RoomManager>().remove_client(client_id, room);
+    }
+    world.resource_mut::<RelevanceBudget>().remove_client(client_id);
+    let circles = world
+        .resource_mut::<RelevantCircles>()
+        .0
+        .remove(&client_id)
+        .unwrap_or_default();
+    let mut relevance_manager = world.resource_mut::<RelevanceManager>();
+    for circle in circles {
+        relevance_manager.lose_relevance(client_id, circle);
+    }
+}
+
+/// Resume replicating to a client that was paused with `pause_replication`.
+/// The client is put back in the room its player is currently in, and its circle relevance is
+/// re-evaluated on the next run of `interest_management`
+pub(crate) fn resume_replication(world: &mut World, client_id: ClientId) {
+    if !world.resource_mut::<PausedClients>().0.remove(&client_id) {
+        return;
+    }
+    // followers are put back in their target's room by `interest_management`
+    if !world.resource::<FollowTargets>().0.contains_key(&client_id) {
+        let room = world.resource::<Global>().client_id_to_room_id.get(&client_id).copied();
+        if let Some(room) = room {
+            world.resource_mut::<RoomManager>().add_client(client_id, room);
+        }
+    }
+    mark_player_changed(world, client_id);
+}
+
+/// A client whose relevance is computed around another client's player instead of its own,
+/// e.g. a spectator following a player
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FollowTarget {
+    pub target: ClientId,
+    /// The room the follower client is currently in, `None` until it was put in the target's room
+    room: Option<RoomId>,
+}
+
+/// The clients that follow another client's player.
+/// If the followed client leaves, its followers fall back to their own position but stay in the
+/// last room of the target until they unfollow
+#[derive(Resource, Default)]
+pub(crate) struct FollowTargets(pub HashMap<ClientId, FollowTarget>);
+
+impl FollowTargets {
+    /// Keep every follower client in the room of the player it follows
+    fn update_rooms(
+        &mut self,
+        global: &Global,
+        paused_clients: &PausedClients,
+        room_manager: &mut RoomManager,
+    ) {
+        for (follower, follow) in self.0.iter_mut() {
+            if paused_clients.0.contains(follower) {
+                continue;
+            }
+            let Some(room) = global.client_id_to_room_id.get(&follow.target) else {
+                continue;
+            };
+            if follow.room != Some(*room) {
+                if let Some(old_room) = follow.room {
+                    room_manager.remove_client(*follower, old_room);
+                }
+                room_manager.add_client(*follower, *room);
+                follow.room = Some(*room);
+            }
+        }
+    }
+}
+
+/// Make `follower` see what the player of `target` sees: its circles are evaluated around the
+/// target's position, and the follower client is moved to the target's room.
+/// Can be queued with `commands.add(move |world: &mut World| follow(world, follower, target))`
+pub(crate) fn follow(world: &mut World, follower: ClientId, target: ClientId) {
+    unfollow(world, follower);
+    if !world.resource::<PausedClients>().0.contains(&follower) {
+        let room = world.resource::<Global>().client_id_to_room_id.get(&follower).copied();
+        if let Some(room) = room {
+            world.resource_mut::<RoomManager>().remove_client(follower, room);
+        }
+    }
+    world
+        .resource_mut::<FollowTargets>()
+        .0
+        .insert(follower, FollowTarget { target, room: None });
+    mark_player_changed(world, follower);
+}
+
+/// Stop following: the client goes back to its own room and its relevance is computed around its
+/// own player again
+pub(crate) fn unfollow(world: &mut World, follower: ClientId) {
+    let Some(follow) = world.resource_mut::<FollowTargets>().0.remove(&follower) else {
+        return;
+    };
+    if let Some(room) = follow.room {
+        world.resource_mut::<RoomManager>().remove_client(follower, room);
+    }
+    if !world.resource::<PausedClients>().0.contains(&follower) {
+        let room = world.resource::<Global>().client_id_to_room_id.get(&follower).copied();
+        if let Some(room) = room {
+            world.resource_mut::<RoomManager>().add_client(follower, room);
+        }
+    }
+    mark_player_changed(world, follower);
+}
+
+/// Mark the position of a client's player as changed, so that `interest_management` re-evaluates it
+fn mark_player_changed(world: &mut World, client_id: ClientId) {
+    let entity = world.resource::<Global>().client_id_to_entity_id.get(&client_id).copied();
+    if let Some(mut position) = entity.and_then(|entity| world.get_mut::<Position>(entity)) {
+        position.set_changed();
+    }
+}
+
+/// A player's client entered a room, because the player walked into it
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RoomEnter {
+    pub client: ClientId,
+    pub room: RoomId,
+}
+
+/// A player's client left a room, because the player walked out of it
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RoomLeave {
+    pub client: ClientId,
+    pub room: RoomId,
+}
+
+/// The relevance of an entity to a client changed, because `interest_management` applied an update
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RelevanceChanged {
+    pub client: ClientId,
+    pub entity: Entity,
+}
+
+/// Example of a gameplay system reacting to the room transitions
+pub(crate) fn log_room_transitions(
+    mut enters: EventReader<RoomEnter>,
+    mut leaves: EventReader<RoomLeave>,
+) {
+    for leave in leaves.read() {
+        debug!("Client {} left room {:?}", leave.client, room_cell(leave.room));
+    }
+    for enter in enters.read() {
+        debug!("Client {} entered room {:?}", enter.client, room_cell(enter.room));
+    }
+}
+
+/// The settings read by `interest_management`, grouped to stay under the system parameter limit
+#[derive(SystemParam)]
+pub(crate) struct InterestSettings<'w> {
+    config: Res<'w, InterestConfig>,
+    circle_strategy: Res<'w, CircleInterestStrategy>,
+    max_relevant_circles: Res<'w, MaxRelevantCircles>,
+}
+
+/// Move the players, their clients and their entities to a new room when they walk into it.
+/// Runs right before `interest_management`, so that it sees the new rooms
+pub(crate) fn room_assignment(
+    mut global: ResMut<Global>,
+    mut room_registry: ResMut<RoomRegistry>,
+    config: Res<InterestConfig>,
+    paused_clients: Res<PausedClients>,
+    follow_targets: Res<FollowTargets>,
+    mut room_enter: EventWriter<RoomEnter>,
+    mut room_leave: EventWriter<RoomLeave>,
+    mut room_manager: ResMut<RoomManager>,
+    mut metrics: ResMut<RelevanceMetrics>,
+    mut player_query: Query<
+        (&PlayerId, Entity, Ref<Position>, &mut CurrentRoom),
+        (Without<CircleMarker>, With<ReplicationTarget>),
+    >,
+) {
+    for (client_id, entity, position, mut current_room) in player_query.iter_mut() {
+        if !position.is_changed() {
+            continue;
+        }
+        // use the room that the client and its entities were actually put in, rather than the
+        // room of the last position: if the two ever disagreed, they would be removed from a
+        // room they are not in and stay in the old one, which breaks replication when coming back
+        let last_room = current_room.0;
+        let new_room = config.room_id_for_position(position.0);
+        if last_room == new_room {
+            continue;
+        }
+        info!(
+            "Client {} moved to room {:?} from room {:?}",
+            client_id.0,
+            room_cell(new_room),
+            room_cell(last_room)
+        );
+        // a paused client is not in any room, it will be added to its current room on resume.
+        // a follower is in the room of the player it follows
+        if !paused_clients.0.contains(&client_id.0) && !follow_targets.0.contains_key(&client_id.0)
+        {
+            room_manager.remove_client(client_id.0, last_room);
+            room_manager.add_client(client_id.0, new_room);
+        }
+        remove_player_from_room(&mut room_manager, &global, entity, last_room);
+        add_player_to_room(&mut room_manager, &global, entity, new_room);
+        global.client_id_to_room_id.insert(client_id.0, new_room);
+        current_room.0 = new_room;
+        room_registry.leave(last_room);
+        log_evicted_rooms(room_registry.enter(new_room, &mut room_manager));
+        metrics.record_room_transition();
+        room_leave.send(RoomLeave {
+            client: client_id.0,
+            room: last_room,
+        });
+        room_enter.send(RoomEnter {
+            client: client_id.0,
+            room: new_room,
+        });
+    }
+}
+
+/// The circles that `interest_management` reads to evaluate them for each player
+type CircleData = (
+    Entity,
+    &'static Position,
+    &'static ReplicationCategory,
+    Has<NewlySpawned>,
+);
+
+/// Evaluates the relevance of the circles around a set of players, without mutating any state.
+/// The evaluation can run serially or spread over the compute task pool, both give the same updates
+pub(crate) struct CircleEvaluation<'a, 'w, 's> {
+    pub(crate) config: &'a InterestConfig,
+    pub(crate) circle_strategy: &'a CircleInterestStrategy,
+    pub(crate) max_relevant_circles: usize,
+    pub(crate) interest_radius: &'a ClientInterestRadius,
+    pub(crate) relevant_circles: &'a RelevantCircles,
+    pub(crate) circle_index: &'a CircleSpatialIndex,
+    pub(crate) circles: &'a Query<'w, 's, CircleData, With<ReplicationTarget>>,
+}
+
+impl CircleEvaluation<'_, '_, '_> {
+    /// The circles whose relevance changes for a client whose interest is centered on `center`
+    fn evaluate(&self, client_id: ClientId, center: Vec2) -> VecDeque<(Entity, Relevance)> {
+        let radius = self.interest_radius.radius(client_id, self.config);
+        let no_relevant_circles = HashSet::default();
+        let relevant = self
+            .relevant_circles
+            .0
+            .get(&client_id)
+            .unwrap_or(&no_relevant_circles);
+        // players and texts are handled by rooms, debug entities never gain relevance.
+        // When the strategy has a maximum distance, only the circles near the player and the ones
+        // that were relevant until now (which may have just left the radius) need to be evaluated.
+        // The search uses the expanded radius so that the newly spawned circles are found
+        let search_radius = radius * self.config.spawn_expansion_factor.max(1.0);
+        let mut circles: Vec<(Entity, Vec2, bool)> =
+            match self.circle_strategy.0.max_distance(search_radius) {
+                Some(max_distance) => self
+                    .circle_index
+                    .query_radius(center, max_distance)
+                    .chain(relevant.iter().copied())
+                    .collect::<HashSet<Entity>>()
+                    .into_iter()
+                    .filter_map(|circle_entity| self.circles.get(circle_entity).ok())
+                    .filter(|(_, _, category, _)| category.is_distance_culled())
+                    .map(|(circle_entity, circle_position, _, new)| {
+                        (circle_entity, circle_position.0, new)
+                    })
+                    .collect(),
+                None => self
+                    .circles
+                    .iter()
+                    .filter(|(_, _, category, _)| category.is_distance_culled())
+                    .map(|(circle_entity, circle_position, _, new)| {
+                        (circle_entity, circle_position.0, new)
+                    })
+                    .collect(),
+            };
+        // evaluate the nearest circles first, so that they are the ones kept under the cap
+        circles.sort_by(|a, b| {
+            center
+                .distance_squared(a.1)
+                .total_cmp(&center.distance_squared(b.1))
+        });
+        let mut num_relevant = 0;
+        let mut updates = VecDeque::new();
+        for (circle_entity, circle_position, new) in circles {
+            // hysteresis: the circles that are already relevant are evaluated with the larger radius
+            let was_relevant = relevant.contains(&circle_entity);
+            let mut evaluated_radius = if was_relevant {
+                radius * self.config.lose_relevance_factor
+            } else {
+                radius
+            };
+            if new {
+                evaluated_radius =
+                    evaluated_radius.max(radius * self.config.spawn_expansion_factor);
+            }
+            let mut relevance = self.circle_strategy.0.evaluate(
+                center,
+                circle_position,
+                evaluated_radius,
+                self.config.room_cell_size,
+            );
+            if relevance == Relevance::Relevant && num_relevant >= self.max_relevant_circles {
+                relevance = Relevance::NotRelevant;
+            }
+            if relevance == Relevance::Relevant {
+                num_relevant += 1;
+            }
+            // only queue the circles whose relevance actually changes
+            if (relevance == Relevance::Relevant) != was_relevant {
+                updates.push_back((circle_entity, relevance));
+            }
+        }
+        updates
+    }
+
+    /// Evaluate the players one after the other
+    pub(crate) fn evaluate_serial(
+        &self,
+        players: &[(ClientId, Vec2)],
+    ) -> Vec<(ClientId, VecDeque<(Entity, Relevance)>)> {
+        players
+            .iter()
+            .map(|(client_id, center)| (*client_id, self.evaluate(*client_id, *center)))
+            .collect()
+    }
+
+    /// Evaluate chunks of players in parallel on the compute task pool.
+    /// This only reads the positions and the current relevance: the updates are queued serially
+    /// afterwards since the relevance state can't be mutated from several threads
+    pub(crate) fn evaluate_parallel(
+        &self,
+        players: &[(ClientId, Vec2)],
+    ) -> Vec<(ClientId, VecDeque<(Entity, Relevance)>)> {
+        players
+            .par_splat_map(ComputeTaskPool::get_or_init(TaskPool::default), None, |_, chunk| {
+                self.evaluate_serial(chunk)
+            })
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+/// Here we perform more "immediate" interest management: we will make a circle visible to a client
+/// depending on the distance to the client's entity (or any other `InterestStrategy`)
+pub(crate) fn interest_management(
+    global: Res<Global>,
+    settings: InterestSettings,
+    interest_radius: Res<ClientInterestRadius>,
+    paused_clients: Res<PausedClients>,
+    mut diagnostics: Diagnostics,
+    mut relevance_manager: ResMut<RelevanceManager>,
+    mut room_manager: ResMut<RoomManager>,
+    mut relevant_circles: ResMut<RelevantCircles>,
+    mut relevance_budget: ResMut<RelevanceBudget>,
+    mut follow_targets: ResMut<FollowTargets>,
+    circle_index: Res<CircleSpatialIndex>,
+    mut churn_breaker: ResMut<ChurnBreaker>,
+    mut metrics: ResMut<RelevanceMetrics>,
+    mut relevance_changes: EventWriter<RelevanceChanged>,
+    mut player_query: Query<
+        (&PlayerId, Entity, Ref<Position>, &mut LastPosition),
+        (Without<CircleMarker>, With<ReplicationTarget>),
+    >,
+    circle_query: Query<CircleData, With<ReplicationTarget>>,
+) {
+    let InterestSettings {
+        config,
+        circle_strategy,
+        max_relevant_circles,
+    } = settings;
+    let start = Instant::now();
+    // followers evaluate their circles around the position of the player they follow
+    let player_positions: HashMap<ClientId, (Vec2, bool)> = player_query
+        .iter()
+        .map(|(client_id, _, position, ..)| (client_id.0, (position.0, position.is_changed())))
+        .collect();
+    let players: Vec<(ClientId, Vec2)> = player_query
+        .iter()
+        .filter(|(client_id, ..)| !paused_clients.0.contains(&client_id.0))
+        .filter_map(|(client_id, _, position, _)| {
+            let (center, center_changed) = follow_targets
+                .0
+                .get(&client_id.0)
+                .and_then(|follow| player_positions.get(&follow.target))
+                .map_or((position.0, false), |(target_position, target_changed)| {
+                    (*target_position, *target_changed)
+                });
+            (position.is_changed() || center_changed).then_some((client_id.0, center))
+        })
+        .collect();
+    let evaluation = CircleEvaluation {
+        config: &config,
+        circle_strategy: &circle_strategy,
+        max_relevant_circles: max_relevant_circles.0,
+        interest_radius: &interest_radius,
+        relevant_circles: &relevant_circles,
+        circle_index: &circle_index,
+        circles: &circle_query,
+    };
+    let updates = evaluation.evaluate_parallel(&players);
+    for (client_id, updates) in updates {
+        relevant_circles.0.entry(client_id).or_default();
+        // updates that are still queued from a previous tick are outdated
+        relevance_budget.pending.insert(client_id, updates);
+    }
+    follow_targets.update_rooms(&global, &paused_clients, &mut room_manager);
+    relevance_budget.apply(
+        &mut relevance_manager,
+        &mut relevant_circles,
+        &mut churn_breaker,
+        &mut metrics,
+        &mut relevance_changes,
+    );
+    // only touch `LastPosition` for the players that moved, so that it isn't marked as changed every run
+    if player_positions.values().any(|(_, changed)| *changed) {
+        for (_, _, position, mut last_position) in player_query.iter_mut() {
+            if position.is_changed() {
+                last_position.0 = position.0;
+            }
+        }
+    }
+    diagnostics.add_measurement(&INTEREST_MANAGEMENT_TIME, || {
+        start.elapsed().as_secs_f64() * 1000.0
+    });
+}
+
+/// Keep the clients and the player entities in the zone of their room, when zones are enabled.
+/// The text entities are in the zone of their player, like they are in its room.
+/// Paused clients are not in any zone, and followers are in the zone of the player they follow
+pub(crate) fn update_zones(
+    config: Res<InterestConfig>,
+    global: Res<Global>,
+    paused_clients: Res<PausedClients>,
+    follow_targets: Res<FollowTargets>,
+    mut zones: ResMut<ZoneMembership>,
+    mut room_manager: ResMut<RoomManager>,
+) {
+    let Some(zone_size) = config.zone_size else {
+        return;
+    };
+    let clients = global
+        .client_id_to_room_id
+        .iter()
+        .filter(|(client_id, _)| !paused_clients.0.contains(*client_id))
+        .map(|(client_id, room)| {
+            let room = follow_targets
+                .0
+                .get(client_id)
+                .and_then(|follow| follow.room)
+                .unwrap_or(*room);
+            (*client_id, zone_for_room(room, zone_size))
+        })
+        .collect();
+    let entities = global
+        .client_id_to_entity_id
+        .iter()
+        .filter_map(|(client_id, entity)| {
+            let room = global.client_id_to_room_id.get(client_id)?;
+            Some((*entity, zone_for_room(*room, zone_size)))
+        })
+        .flat_map(|(entity, zone)| {
+            let text_entity = global.player_to_text_entity.get(&entity).copied();
+            std::iter::once(entity)
+                .chain(text_entity)
+                .map(move |entity| (entity, zone))
+        })
+        .collect();
+    zones.sync(&mut room_manager, clients, entities);
+}
+
+/// When enabled, other players (and their text) are only relevant to a client if they are within
+/// its interest radius, on top of being in the same room
+#[derive(Resource, Default)]
+pub(crate) struct PlayerCulling(pub bool);
+
+/// Whether each player was within the interest radius of each client at the last culling
+#[derive(Resource, Default)]
+pub(crate) struct CulledPlayers(HashMap<(ClientId, ClientId), bool>);
+
+impl CulledPlayers {
+    /// Forget the pairs that contain a client, e.g. when it disconnects
+    pub(crate) fn remove_client(&mut self, client_id: ClientId) {
+        self.0
+            .retain(|(client, other), _| *client != client_id && *other != client_id);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// When `PlayerCulling` is enabled, check every pair of players in which at least one of them
+/// moved, and only update the relevance of the pairs that crossed the interest radius of the
+/// client. Runs right after `interest_management`
+pub(crate) fn cull_distant_players(
+    global: Res<Global>,
+    player_culling: Res<PlayerCulling>,
+    interest_radius: Res<ClientInterestRadius>,
+    config: Res<InterestConfig>,
+    paused_clients: Res<PausedClients>,
+    mut culled_players: ResMut<CulledPlayers>,
+    mut relevance_manager: ResMut<RelevanceManager>,
+    mut metrics: ResMut<RelevanceMetrics>,
+    player_query: Query<
+        (&PlayerId, Entity, Ref<Position>),
+        (Without<CircleMarker>, With<ReplicationTarget>),
+    >,
+) {
+    if !player_culling.0 {
+        return;
+    }
+    let players: Vec<(ClientId, Entity, Vec2, bool)> = player_query
+        .iter()
+        .map(|(client_id, entity, position)| {
+            (client_id.0, entity, position.0, position.is_changed())
+        })
+        .collect();
+    for (client_id, _, client_position, client_moved) in players.iter() {
+        if paused_clients.0.contains(client_id) {
+            continue;
+        }
+        let radius = interest_radius.radius(*client_id, &config);
+        for (other_id, other_entity, other_position, other_moved) in players.iter() {
+            if other_id == client_id || !(*client_moved || *other_moved) {
+                continue;
+            }
+            let relevant = client_position.distance(*other_position) < radius;
+            if culled_players.0.insert((*client_id, *other_id), relevant) == Some(relevant) {
+                continue;
+            }
+            let text_entity = global.player_to_text_entity.get(other_entity);
+            for entity in std::iter::once(other_entity).chain(text_entity) {
+                if relevant {
+                    relevance_manager.gain_relevance(*client_id, *entity);
+                    metrics.record_gain();
+                } else {
+                    relevance_manager.lose_relevance(*client_id, *entity);
+                    metrics.record_loss();
+                }
+            }
+        }
+    }
+}
+
+/// Count the clients and entities of each room, from the rooms that we assigned them to.
+/// The players' text entities are in the room of their player. Paused clients are in no room,
+/// and followers are in the room of the player they follow
+pub(crate) fn update_room_occupancy(
+    mut occupancy: ResMut<RoomOccupancy>,
+    global: Res<Global>,
+    paused_clients: Res<PausedClients>,
+    follow_targets: Res<FollowTargets>,
+    players: Query<(Entity, &CurrentRoom), With<PlayerId>>,
+    room_members: Query<&RoomMember>,
+) {
+    let clients = global
+        .client_id_to_room_id
+        .iter()
+        .filter(|(client_id, _)| !paused_clients.0.contains(client_id))
+        .filter_map(|(client_id, room)| match follow_targets.0.get(client_id) {
+            Some(follow) => follow.room,
+            None => Some(*room),
+        });
+    let entities = players
+        .iter()
+        .flat_map(|(entity, room)| {
+            let has_text = global.player_to_text_entity.contains_key(&entity);
+            std::iter::repeat(room.0).take(1 + has_text as usize)
+        })
+        .chain(room_members.iter().map(|member| member.0));
+    *occupancy = RoomOccupancy::count(clients, entities);
+}
+
+/// Which source wins when the rooms and the `RelevanceManager` disagree about whether a client
+/// should see an entity that is managed by both. This only concerns the circles spawned after
+/// startup, which are added to a room on top of the distance-based relevance. The players are not
+/// concerned: `PlayerCulling` always narrows down their room visibility
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum VisibilityPrecedence {
+    /// Visible if it is in the client's room or relevant to the client
+    #[default]
+    Union,
+    /// Visible only if it is in the client's room and relevant to the client
+    Intersection,
+    /// Only the relevance computed by interest management counts
+    Relevance,
+    /// Only the rooms count
+    Room,
+}
+
+impl VisibilityPrecedence {
+    pub(crate) fn resolve(&self, in_room: bool, relevant: bool) -> bool {
+        match self {
+            VisibilityPrecedence::Union => in_room || relevant,
+            VisibilityPrecedence::Intersection => in_room && relevant,
+            VisibilityPrecedence::Relevance => relevant,
+            VisibilityPrecedence::Room => in_room,
+        }
+    }
+}
+
+/// The room an entity was added to, for the entities that are also managed by the `RelevanceManager`
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RoomMember(pub RoomId);
+
+/// The state of the entities managed by both the rooms and the `RelevanceManager`, at the last
+/// reconciliation
+#[derive(Resource, Default)]
+pub(crate) struct ReconciledVisibility {
+    /// For each client and entity, whether the entity was in the client's room and relevant to it
+    states: HashMap<ClientId, HashMap<Entity, (bool, bool)>>,
+    /// The room of each client that isn't paused. A follower is in the room of the player it
+    /// follows, which may be none
+    rooms: HashMap<ClientId, Option<RoomId>>,
+    /// Number of (client, entity) pairs that were reconciled during the last run
+    pub(crate) num_reconciled: usize,
+}
+
+impl ReconciledVisibility {
+    pub(crate) fn clear(&mut self) {
+        self.states.clear();
+        self.rooms.clear();
+    }
+}
+
+/// Enforce the `VisibilityPrecedence` for the entities managed by both the rooms and the
+/// `RelevanceManager`. Runs after the room changes and relevance updates of the frame, and only
+/// reconciles the pairs for which one of the two sources changed, since that is when they can
+/// override each other: every entity for the clients whose room changed, every client for the
+/// entities whose room changed, and the pairs whose relevance changed
+pub(crate) fn reconcile_visibility(
+    precedence: Res<VisibilityPrecedence>,
+    global: Res<Global>,
+    paused_clients: Res<PausedClients>,
+    follow_targets: Res<FollowTargets>,
+    relevant_circles: Res<RelevantCircles>,
+    mut relevance_changes: EventReader<RelevanceChanged>,
+    mut removed_members: RemovedComponents<RoomMember>,
+    mut reconciled: ResMut<ReconciledVisibility>,
+    mut relevance_manager: ResMut<RelevanceManager>,
+    entities: Query<(Entity, &RoomMember)>,
+    moved_entities: Query<Entity, Changed<RoomMember>>,
+) {
+    let ReconciledVisibility {
+        states,
+        rooms,
+        num_reconciled,
+    } = &mut *reconciled;
+    for entity in removed_members.read() {
+        for client_states in states.values_mut() {
+            client_states.remove(&entity);
+        }
+    }
+    // paused and disconnected clients are forgotten, so that all of their entities are
+    // reconciled again when they come back
+    let active = |client_id: &ClientId| {
+        global.client_id_to_room_id.contains_key(client_id) && !paused_clients.0.contains(client_id)
+    };
+    states.retain(|client_id, _| active(client_id));
+    rooms.retain(|client_id, _| active(client_id));
+
+    let mut pairs: HashSet<(ClientId, Entity)> = HashSet::default();
+    for (client_id, own_room) in global.client_id_to_room_id.iter() {
+        if !active(client_id) {
+            continue;
+        }
+        let room = match follow_targets.0.get(client_id) {
+            Some(follow) => follow.room,
+            None => Some(*own_room),
+        };
+        if rooms.insert(*client_id, room) != Some(room) {
+            pairs.extend(entities.iter().map(|(entity, _)| (*client_id, entity)));
+        }
+    }
+    for entity in moved_entities.iter() {
+        pairs.extend(rooms.keys().map(|client_id| (*client_id, entity)));
+    }
+    pairs.extend(
+        relevance_changes
+            .read()
+            .map(|change| (change.client, change.entity)),
+    );
+
+    *num_reconciled = 0;
+    for (client_id, entity) in pairs {
+        let (Some(room), Ok((_, member))) = (rooms.get(&client_id), entities.get(entity)) else {
+            continue;
+        };
+        let state = (
+            *room == Some(member.0),
+            relevant_circles
+                .0
+                .get(&client_id)
+                .is_some_and(|relevant| relevant.contains(&entity)),
+        );
+        let client_states = states.entry(client_id).or_default();
+        if client_states.insert(entity, state) != Some(state) {
+            if precedence.resolve(state.0, state.1) {
+                relevance_manager.gain_relevance(client_id, entity);
+            } else {
+                relevance_manager.lose_relevance(client_id, entity);
+            }
+        }
+        *num_reconciled += 1;
+    }
+}
+
+/// Marker for the entities that emit sounds. Server-only component
+#[derive(Component, Clone, Copy, Debug)]
+pub(crate) struct SoundEmitter;
+
+/// When set, sound emitters that are not replicated to a client but are strictly within this radius
+/// of its player are sent to it as `AudioRelevant` hints. It should be larger than the interest radius
+#[derive(Resource, Default)]
+pub(crate) struct AudioRadius(pub Option<f32>);
+
+/// The sound emitters currently sent to each client as audio hints
+#[derive(Resource, Default)]
+pub(crate) struct AudioRelevantEntities(pub HashMap<ClientId, HashSet<Entity>>);
+
+/// Compute the sound emitters within the audio radius of each player that moved, excluding the
+/// ones that are already relevant, and send them to the client if they changed
+pub(crate) fn update_audio_relevance(
+    audio_radius: Res<AudioRadius>,
+    relevant_circles: Res<RelevantCircles>,
+    paused_clients: Res<PausedClients>,
+    mut audio_relevant: ResMut<AudioRelevantEntities>,
+    mut outbox: ResMut<Outbox>,
+    players: Query<(&PlayerId, Ref<Position>), Without<CircleMarker>>,
+    emitters: Query<(Entity, &Position), With<SoundEmitter>>,
+) {
+    let Some(radius) = audio_radius.0 else {
+        return;
+    };
+    for (client_id, position) in players.iter() {
+        if !position.is_changed() || paused_clients.0.contains(&client_id.0) {
+            continue;
+        }
+        let relevant = relevant_circles.0.get(&client_id.0);
+        let hints: Vec<AudioSourceHint> = emitters
+            .iter()
+            .filter(|(entity, _)| relevant.map_or(true, |relevant| !relevant.contains(entity)))
+            .filter(|(_, emitter_position)| {
+                position.distance(emitter_position.0) < radius
+            })
+            .map(|(entity, emitter_position)| AudioSourceHint {
+                id: entity.to_bits(),
+                position: emitter_position.0,
+            })
+            .collect();
+        let entities: HashSet<Entity> = hints
+            .iter()
+            .map(|hint| Entity::from_bits(hint.id))
+            .collect();
+        if audio_relevant.0.get(&client_id.0) == Some(&entities) {
+            continue;
+        }
+        audio_relevant.0.insert(client_id.0, entities);
+        outbox.send(
+            AudioRelevant(hints),
+            NetworkTarget::Single(client_id.0),
+            MessagePriority::Low,
+            "send audio hints",
+        );
+    }
+}
+
+#[derive(Resource)]
+pub(crate) struct OccupancyTimer(pub Timer);
+
+/// Aggregate the player positions into a low-resolution grid covering the circles
+pub(crate) fn compute_occupancy<'a>(
+    config: &InterestConfig,
+    positions: impl Iterator<Item = &'a Position>,
+) -> OccupancyGrid {
+    let half_extent = config.half_extent();
+    let num_cells = (2.0 * half_extent / OCCUPANCY_CELL_SIZE).ceil() as u32;
+    let mut grid = OccupancyGrid::new(
+        Vec2::splat(-half_extent),
+        OCCUPANCY_CELL_SIZE,
+        num_cells,
+        num_cells,
+    );
+    for position in positions {
+        grid.occupy(position.0);
+    }
+    grid
+}
+
+/// Periodically send the occupancy grid to all clients, for example to draw a minimap
+pub(crate) fn broadcast_occupancy(
+    mut timer: ResMut<OccupancyTimer>,
+    mut outbox: ResMut<Outbox>,
+    config: Res<InterestConfig>,
+    players: Query<&Position, With<PlayerId>>,
+    time: Res<Time>,
+) {
+    timer.0.tick(time.delta());
+    if !timer.0.just_finished() {
+        return;
+    }
+    let grid = compute_occupancy(&config, players.iter());
+    // the minimap can wait if there are more important messages to send
+    outbox.send(
+        grid,
+        NetworkTarget::All,
+        MessagePriority::Low,
+        "broadcast occupancy grid",
+    );
+}
+
+/// Clients that receive the `InputSnapshot` of the players they are watching
+#[derive(Resource, Default)]
+pub(crate) struct Spectators(pub HashSet<ClientId>);
+
+impl Spectators {
+    fn target(&self) -> NetworkTarget {
+        NetworkTarget::Only(self.0.iter().copied().collect())
+    }
+}
+
+/// Derive the `InputSnapshot` of each player from its `ActionState`.
+/// The snapshot is only replicated to spectators
+pub(crate) fn update_input_snapshots(
+    mut commands: Commands,
+    spectators: Res<Spectators>,
+    mut players: Query<
+        (Entity, &ActionState<Inputs>, Option<&mut InputSnapshot>),
+        (With<PlayerId>, Without<InputMap<Inputs>>),
+    >,
+) {
+    for (entity, action_state, snapshot) in players.iter_mut() {
+        let new_snapshot = InputSnapshot::from_action_state(action_state);
+        match snapshot {
+            // only trigger change detection (and replication) when the inputs actually changed
+            Some(mut snapshot) => {
+                snapshot.set_if_neq(new_snapshot);
+            }
+            None => {
+                commands.entity(entity).insert((
+                    new_snapshot,
+                    OverrideTargetComponent::<InputSnapshot>::new(spectators.target()),
+                ));
+            }
+        }
+    }
+}
+
+/// Add the inputs of the players to the `InputHistory`
+pub(crate) fn track_input_history(
+    mut history: ResMut<InputHistory>,
+    ticks: Res<ServerTicks>,
+    players: Query<(&PlayerId, &InputSnapshot)>,
+) {
+    for (player_id, snapshot) in players.iter() {
+        if history.record(player_id.0, *snapshot, ticks.tick) {
+            debug!("Client {} holds {:?} since tick {}", player_id.0, snapshot, ticks.tick);
+        }
+    }
+}
+
+/// Keep the replication target of the `InputSnapshot` in sync with the list of spectators
+pub(crate) fn update_spectator_targets(
+    spectators: Res<Spectators>,
+    mut targets: Query<&mut OverrideTargetComponent<InputSnapshot>>,
+) {
+    if !spectators.is_changed() {
+        return;
+    }
+    for mut target in targets.iter_mut() {
+        *target = OverrideTargetComponent::<InputSnapshot>::new(spectators.target());
+    }
+}
+
+/// An error returned by lightyear while sending messages or replicating, with as much context as we have
+#[derive(Event, Debug)]
+pub(crate) struct ReplicationErrorEvent {
+    /// What we were trying to do
+    pub action: &'static str,
+    pub client_id: Option<ClientId>,
+    pub entity: Option<Entity>,
+    pub error: String,
+}
+
+impl ReplicationErrorEvent {
+    pub(crate) fn new(action: &'static str, error: impl std::fmt::Debug) -> Self {
+        Self {
+            action,
+            client_id: None,
+            entity: None,
+            error: format!("{:?}", error),
+        }
+    }
+
+    pub(crate) fn with_client(mut self, client_id: ClientId) -> Self {
+        self.client_id = Some(client_id);
+        self
+    }
+
+    pub(crate) fn with_entity(mut self, entity: Entity) -> Self {
+        self.entity = Some(entity);
+        self
+    }
+}
+
+/// Log all the lightyear errors that were reported during the frame
+pub(crate) fn log_replication_errors(mut errors: EventReader<ReplicationErrorEvent>) {
+    for error in errors.read() {
+        error!(
+            client_id = ?error.client_id,
+            entity = ?error.entity,
+            "Failed to {}: {}",
+            error.action,
+            error.error
+        );
+    }
+}
+
+/// Number of fixed ticks since the server started
+#[derive(Resource)]
+pub(crate) struct ServerTicks {
+    pub tick: u64,
+    timer: Timer,
+}
+
+pub(crate) fn count_server_ticks(mut ticks: ResMut<ServerTicks>) {
+    ticks.tick += 1;
+}
+
+/// Periodically send the current tick to all clients, for time-synchronized effects
+pub(crate) fn broadcast_server_time(
+    mut ticks: ResMut<ServerTicks>,
+    mut outbox: ResMut<Outbox>,
+    time: Res<Time>,
+) {
+    ticks.timer.tick(time.delta());
+    if !ticks.timer.just_finished() {
+        return;
+    }
+    // the estimate on the client gets worse the longer the message is delayed
+    outbox.send(
+        ServerTime { tick: ticks.tick },
+        NetworkTarget::All,
+        MessagePriority::High,
+        "broadcast server time",
+    );
+}
+
+#[derive(Resource)]
+pub(crate) struct HeartbeatTimer(pub Timer);
+
+/// Periodically send the current tick to all clients in a `Message1`, as a heartbeat that lets
+/// them detect when the server stalls
+pub(crate) fn broadcast_tick(
+    mut timer: ResMut<HeartbeatTimer>,
+    ticks: Res<ServerTicks>,
+    mut outbox: ResMut<Outbox>,
+    time: Res<Time>,
+) {
+    timer.0.tick(time.delta());
+    if !timer.0.just_finished() {
+        return;
+    }
+    outbox.send(
+        Message1(ticks.tick as usize),
+        NetworkTarget::All,
+        MessagePriority::Normal,
+        "broadcast heartbeat",
+    );
+}
+
+/// How many players the broadcasted `Leaderboard` contains, and how often it is sent
+#[derive(Resource)]
+pub(crate) struct LeaderboardConfig {
+    pub top_n: usize,
+    pub timer: Timer,
+}
+
+impl Default for LeaderboardConfig {
+    fn default() -> Self {
+        Self {
+            top_n: 10,
+            timer: Timer::from_seconds(LEADERBOARD_INTERVAL_SECS, TimerMode::Repeating),
+        }
+    }
+}
+
+/// Sort the scores by descending score and keep the `top_n` first.
+/// Ties are broken by client id so that the order is stable between broadcasts
+pub(crate) fn top_scores(
+    scores: impl Iterator<Item = (ClientId, u32)>,
+    top_n: usize,
+) -> Vec<(ClientId, u32)> {
+    let mut sorted: Vec<(ClientId, u32)> = scores.collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_bits().cmp(&b.0.to_bits())));
+    sorted.truncate(top_n);
+    sorted
+}
+
+/// Periodically send the best scores to all clients
+pub(crate) fn broadcast_leaderboard(
+    mut config: ResMut<LeaderboardConfig>,
+    mut outbox: ResMut<Outbox>,
+    players: Query<(&PlayerId, &Score)>,
+    time: Res<Time>,
+) {
+    config.timer.tick(time.delta());
+    if !config.timer.just_finished() {
+        return;
+    }
+    let top = top_scores(
+        players.iter().map(|(player_id, score)| (player_id.0, score.0)),
+        config.top_n,
+    );
+    outbox.send(
+        Leaderboard(top),
+        NetworkTarget::All,
+        MessagePriority::Low,
+        "broadcast leaderboard",
+    );
+}
+
+/// Position that a player is moving towards, set from the client's `MoveTarget` messages
+#[derive(Component, Clone, Debug, PartialEq)]
+pub(crate) struct Destination(pub Vec2);
+
+pub(crate) fn receive_move_target(
+    mut commands: Commands,
+    global: Res<Global>,
+    mut messages: EventReader<MessageEvent<MoveTarget>>,
+) {
+    for message in messages.read() {
+        if let Some(entity) = global.client_id_to_entity_id.get(message.context()) {
+            commands
+                .entity(*entity)
+                .insert(Destination(message.message().0));
+        }
+    }
+}
+
+/// Read client inputs and move players
+/// The inputs sent by a client are written by lightyear into the `ActionState<Inputs>` of the
+/// entity it controls (see `ControlledBy` in `PlayerBundle`), so each player only moves from
+/// its own client's inputs.
+/// Players with a `Destination` pursue it, until they reach it or press a movement input.
+/// The speed depends on the `RoomProperties` of the player's current room
+pub(crate) fn movement(
+    mut commands: Commands,
+    room_properties: Res<RoomProperties>,
+    mut position_query: Query<
+        (
+            Entity,
+            &mut Position,
+            &mut Velocity,
+            &CurrentRoom,
+            &ActionState<Inputs>,
+            Option<&Destination>,
+        ),
+        Without<InputMap<Inputs>>,
+    >,
+) {
+    for (entity, position, mut velocity, room, input, destination) in position_query.iter_mut() {
+        let speed_multiplier = room_properties.get(room.0).speed_multiplier;
+        match destination {
+            Some(destination) if !has_movement_input(input) => {
+                // pursuing a destination moves at a constant speed, without momentum
+                velocity.set_if_neq(Velocity(Vec2::ZERO));
+                if move_towards(position, destination.0, MOVE_SPEED * speed_multiplier) {
+                    commands.entity(entity).remove::<Destination>();
+                }
+            }
+            // keyboard inputs cancel the destination
+            Some(_) => {
+                commands.entity(entity).remove::<Destination>();
+                shared_movement_behaviour(position, velocity, input, speed_multiplier);
+            }
+            None => shared_movement_behaviour(position, velocity, input, speed_multiplier),
+        }
+    }
+}
+
+/// Keep the players within the grid of circles, otherwise they could walk into rooms where nothing
+/// is ever relevant. The velocity along a clamped axis is cancelled.
+/// The grid starts at `-half_extent` but its last circles are one cell before `half_extent`, which
+/// is already in a room outside of the grid: the players are clamped to the outermost circles
+pub(crate) fn clamp_positions(
+    config: Res<InterestConfig>,
+    mut players: Query<(&mut Position, &mut Velocity), With<PlayerId>>,
+) {
+    let min = Vec2::splat(-config.half_extent());
+    let max = Vec2::splat(config.half_extent() - config.room_cell_size);
+    for (mut position, mut velocity) in players.iter_mut() {
+        let clamped = position.0.clamp(min, max);
+        if clamped == position.0 {
+            continue;
+        }
+        if clamped.x != position.x {
+            velocity.x = 0.0;
+        }
+        if clamped.y != position.y {
+            velocity.y = 0.0;
+        }
+        position.0 = clamped;
+    }
+}
+
+/// Every pair of overlapping players damages both players, once per tick.
+/// A player is never paired with itself, and a `Shielded` player neither takes nor deals damage
+/// A player whose damage brings another player's health to 0 scores a kill
+pub(crate) fn combat(
+    mut kills: EventWriter<ScoreEvent>,
+    mut players: Query<(&PlayerId, &Position, &mut Health, Has<Shielded>)>,
+) {
+    let mut pairs = players.iter_combinations_mut();
+    while let Some(
+        [(a_id, a_position, mut a_health, a_shielded), (b_id, b_position, mut b_health, b_shielded)],
+    ) = pairs.fetch_next()
+    {
+        if a_shielded || b_shielded {
+            continue;
+        }
+        if a_position.distance_squared(b_position.0) > COLLISION_RADIUS * COLLISION_RADIUS {
+            continue;
+        }
+        if a_health.damage(COLLISION_DAMAGE_PER_TICK) {
+            kills.send(ScoreEvent { client: b_id.0 });
+        }
+        if b_health.damage(COLLISION_DAMAGE_PER_TICK) {
+            kills.send(ScoreEvent { client: a_id.0 });
+        }
+    }
+}
+
+/// Server-only timer of the `Shielded` marker that the players get when they spawn or respawn.
+/// Everyone spawns at the same position: without it, the players waiting there would damage the
+/// ones that just (re)spawned, kill them again and again and score a point every time
+#[derive(Component, Clone, Debug)]
+pub(crate) struct SpawnProtection(pub Timer);
+
+impl Default for SpawnProtection {
+    fn default() -> Self {
+        Self(Timer::from_seconds(SPAWN_PROTECTION_SECS, TimerMode::Once))
+    }
+}
+
+/// Remove the `Shielded` marker of the players whose spawn protection is over
+pub(crate) fn expire_spawn_protection(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut players: Query<(Entity, &mut SpawnProtection)>,
+) {
+    for (entity, mut protection) in players.iter_mut() {
+        protection.0.tick(time.delta());
+        if protection.0.finished() {
+            commands.entity(entity).remove::<(Shielded, SpawnProtection)>();
+        }
+    }
+}
+
+/// The player of `client` scored a point
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ScoreEvent {
+    pub client: ClientId,
+}
+
+/// Give the points scored this tick to the players.
+/// This is the only system that changes `Score`, and it changes it normally so that lightyear
+/// replicates the new value
+pub(crate) fn award_scores(
+    global: Res<Global>,
+    mut events: EventReader<ScoreEvent>,
+    mut scores: Query<&mut Score>,
+) {
+    for event in events.read() {
+        let Some(entity) = global.client_id_to_entity_id.get(&event.client) else {
+            continue;
+        };
+        if let Ok(mut score) = scores.get_mut(*entity) {
+            score.0 += 1;
+            info!("Client {} scored, score is now {}", event.client, score.0);
+        }
+    }
+}
+
+/// Players whose health reached 0 are brought back to the spawn position with full health, and
+/// get a new spawn protection. Their number of deaths is replicated in the `DEATHS_KEY` of their
+/// `Metadata`.
+/// The entity and its `PlayerId` are kept, so the client keeps controlling it. The client and the
+/// player are moved to the spawn room by `room_assignment`, like for any other position change
+pub(crate) fn respawn(
+    mut commands: Commands,
+    mut players: Query<(
+        Entity,
+        &PlayerId,
+        &mut Position,
+        &mut Velocity,
+        &mut Health,
+        Option<&mut Metadata>,
+    )>,
+) {
+    for (entity, player_id, mut position, mut velocity, mut health, metadata) in players.iter_mut() {
+        if health.current > 0.0 {
+            continue;
+        }
+        info!("Client {} died, respawning", player_id.0);
+        position.0 = SPAWN_POSITION;
+        velocity.0 = Vec2::ZERO;
+        health.current = health.max;
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert((Shielded, SpawnProtection::default()));
+        match metadata {
+            Some(mut metadata) => record_death(&mut metadata),
+            None => {
+                let mut metadata = Metadata::default();
+                record_death(&mut metadata);
+                entity_commands.insert(metadata);
+            }
+        }
+    }
+}
+
+fn record_death(metadata: &mut Metadata) {
+    let deaths = metadata
+        .get(DEATHS_KEY)
+        .and_then(|deaths| deaths.parse::<u32>().ok())
+        .unwrap_or(0);
+    if let Err(e) = metadata.insert(DEATHS_KEY, (deaths + 1).to_string()) {
+        warn!("Could not record a death: {:?}", e);
+    }
+}
+
+/// A circle spawned after startup, less than `InterestConfig::spawn_expansion_secs` ago.
+/// It is evaluated with an expanded interest radius
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub(crate) struct NewlySpawned {
+    /// Seconds since the circle was spawned
+    pub age: f32,
+}
+
+/// Remove `NewlySpawned` from the circles once their spawn expansion is over
+pub(crate) fn expire_newly_spawned(
+    mut commands: Commands,
+    config: Res<InterestConfig>,
+    time: Res<Time>,
+    mut circles: Query<(Entity, &mut NewlySpawned)>,
+) {
+    for (entity, mut newly_spawned) in circles.iter_mut() {
+        newly_spawned.age += time.delta_seconds();
+        if newly_spawned.age >= config.spawn_expansion_secs {
+            commands.entity(entity).remove::<NewlySpawned>();
+        }
+    }
+}
+
+/// Components of a circle spawned after startup, in `room`. The circle is controlled by `owner`, if any.
+/// The spawned circles are `SoundEmitter`s, so they can be heard from outside the interest radius
+fn spawned_circle_bundle(
+    position: Vec2,
+    room: RoomId,
+    owner: Option<ClientId>,
+    group_strategy: &GroupStrategy,
+) -> impl Bundle {
+    (
+        Position(position),
+        CircleMarker,
+        CircleActive(true),
+        ReplicationCategory::Circle,
+        NewlySpawned::default(),
+        RoomMember(room),
+        SoundEmitter,
+        Replicate {
+            controlled_by: ControlledBy {
+                target: owner.map_or(NetworkTarget::None, NetworkTarget::Single),
+                ..default()
+            },
+            group: group_strategy.replication_group(None, room, owner),
+            relevance_mode: NetworkRelevanceMode::InterestManagement,
+            ..default()
+        },
+    )
+}
+
+/// Random generator of the test bursts, seeded so that the bursts are the same from run to run
+#[derive(Resource)]
+pub(crate) struct TestBurstRng(pub StdRng);
+
+impl Default for TestBurstRng {
+    fn default() -> Self {
+        Self(StdRng::seed_from_u64(TEST_BURST_SEED))
+    }
+}
+
+/// Admin command to test relevance: spawn `n` circles at random within `TEST_BURST_RADIUS` of the
+/// player of `client_id`. They are evaluated by `interest_management` on its next run.
+/// Can be queued with `commands.add(move |world: &mut World| spawn_test_burst(world, client_id, n))`.
+/// There is no client message for it, so that clients can't flood the world with circles
+pub(crate) fn spawn_test_burst(world: &mut World, client_id: ClientId, n: usize) {
+    let entity = world.resource::<Global>().client_id_to_entity_id.get(&client_id).copied();
+    let Some(center) = entity.and_then(|entity| world.get::<Position>(entity)).map(|p| p.0) else {
+        warn!("Cannot spawn a test burst near client {}: it has no player", client_id);
+        return;
+    };
+    let config = *world.resource::<InterestConfig>();
+    let group_strategy = *world.resource::<GroupStrategy>();
+    for _ in 0..n {
+        let mut rng = world.resource_mut::<TestBurstRng>();
+        // uniform in the disk around the player
+        let angle = rng.0.gen_range(0.0..std::f32::consts::TAU);
+        let distance = TEST_BURST_RADIUS * rng.0.gen::<f32>().sqrt();
+        let position = center + Vec2::from_angle(angle) * distance;
+        let room = config.room_id_for_position(position);
+        let circle = world
+            .spawn(spawned_circle_bundle(position, room, None, &group_strategy))
+            .id();
+        world.resource_mut::<RoomManager>().add_entity(circle, room);
+        world.resource_mut::<RoomRegistry>().add_member(room);
+    }
+    info!("Spawned a burst of {} circles near client {}", n, client_id);
+    mark_player_changed(world, client_id);
+}
+
+/// Whether the clients can use the `DebugCommand`s. They let any client pause its replication
+/// or watch the other players, so they are off unless the server is started with the
+/// `ALLOW_DEBUG_COMMANDS` environment variable
+#[derive(Resource)]
+pub(crate) struct AllowDebugCommands(pub bool);
+
+impl Default for AllowDebugCommands {
+    fn default() -> Self {
+        Self(std::env::var_os(ALLOW_DEBUG_COMMANDS_VAR).is_some())
+    }
+}
+
+/// Run the `DebugCommand`s sent by the clients: pause or resume their replication, or follow
+/// another player
+pub(crate) fn receive_debug_commands(
+    mut commands: Commands,
+    allow: Res<AllowDebugCommands>,
+    global: Res<Global>,
+    paused_clients: Res<PausedClients>,
+    follow_targets: Res<FollowTargets>,
+    mut messages: EventReader<MessageEvent<DebugCommand>>,
+) {
+    if !allow.0 {
+        messages.clear();
+        return;
+    }
+    for message in messages.read() {
+        let client_id = *message.context();
+        match message.message() {
+            DebugCommand::TogglePause => {
+                if paused_clients.0.contains(&client_id) {
+                    commands.add(move |world: &mut World| resume_replication(world, client_id));
+                } else {
+                    commands.add(move |world: &mut World| pause_replication(world, client_id));
+                }
+            }
+            DebugCommand::FollowNext => {
+                let current = follow_targets.0.get(&client_id).map(|follow| follow.target);
+                match next_follow_target(&global, client_id, current) {
+                    Some(target) => {
+                        commands.add(move |world: &mut World| follow(world, client_id, target))
+                    }
+                    None => commands.add(move |world: &mut World| unfollow(world, client_id)),
+                }
+            }
+        }
+    }
+}
+
+/// The client with a player that comes after `current` in id order, skipping `follower` itself.
+/// `None` after the last one, to go back to the follower's own player
+fn next_follow_target(
+    global: &Global,
+    follower: ClientId,
+    current: Option<ClientId>,
+) -> Option<ClientId> {
+    let after = current.map(|current| current.to_bits());
+    global
+        .client_id_to_entity_id
+        .keys()
+        .filter(|client_id| **client_id != follower)
+        .filter(|client_id| after.map_or(true, |after| client_id.to_bits() > after))
+        .min_by_key(|client_id| client_id.to_bits())
+        .copied()
+}
+
+/// A circle spawned by a client with `Inputs::Spawn`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SpawnedCircle {
+    pub entity: Entity,
+    /// The room the circle was added to
+    pub room: RoomId,
+}
+
+/// The circles spawned by each client, in spawn order
+#[derive(Resource, Default)]
+pub(crate) struct SpawnedCircles(pub HashMap<ClientId, Vec<SpawnedCircle>>);
+
+/// Despawn a circle spawned by a client, after removing it from its room and from the relevance
+/// state
+fn despawn_spawned_circle(
+    commands: &mut Commands,
+    circle: &SpawnedCircle,
+    room_manager: &mut RoomManager,
+    room_registry: &mut RoomRegistry,
+    relevant_circles: &mut RelevantCircles,
+    relevance_budget: &mut RelevanceBudget,
+) {
+    room_manager.remove_entity(circle.entity, circle.room);
+    room_registry.remove_member(circle.room);
+    for relevant in relevant_circles.0.values_mut() {
+        relevant.remove(&circle.entity);
+    }
+    relevance_budget.remove_entity(circle.entity);
+    if let Some(entity_commands) = commands.get_entity(circle.entity) {
+        entity_commands.despawn();
+    }
+}
+
+/// Spawn a circle at the position of the players that pressed `Inputs::Spawn`.
+/// The circle is controlled by the client and added to the player's current room
+pub(crate) fn handle_spawn_input(
+    mut commands: Commands,
+    group_strategy: Res<GroupStrategy>,
+    mut room_manager: ResMut<RoomManager>,
+    mut room_registry: ResMut<RoomRegistry>,
+    mut spawned_circles: ResMut<SpawnedCircles>,
+    players: Query<
+        (&PlayerId, &Position, &CurrentRoom, &ActionState<Inputs>),
+        Without<InputMap<Inputs>>,
+    >,
+) {
+    for (player_id, position, current_room, input) in players.iter() {
+        if !input.just_pressed(&Inputs::Spawn) {
+            continue;
+        }
+        let client_id = player_id.0;
+        let room = current_room.0;
+        let entity = commands
+            .spawn(spawned_circle_bundle(position.0, room, Some(client_id), &group_strategy))
+            .id();
+        room_manager.add_entity(entity, room);
+        room_registry.add_member(room);
+        spawned_circles
+            .0
+            .entry(client_id)
+            .or_default()
+            .push(SpawnedCircle { entity, room });
+        info!("Client {} spawned circle {:?} in room {:?}", client_id, entity, room_cell(room));
+    }
+}
+
+/// Despawn the last circle spawned by the clients that pressed `Inputs::Delete`.
+/// The clients that could see the circle are told that it was destroyed.
+/// Nothing happens if the client has no circle left
+pub(crate) fn handle_delete_input(
+    mut commands: Commands,
+    global: Res<Global>,
+    mut outbox: ResMut<Outbox>,
+    mut room_manager: ResMut<RoomManager>,
+    mut room_registry: ResMut<RoomRegistry>,
+    mut spawned_circles: ResMut<SpawnedCircles>,
+    mut relevant_circles: ResMut<RelevantCircles>,
+    mut relevance_budget: ResMut<RelevanceBudget>,
+    players: Query<(&PlayerId, &ActionState<Inputs>), Without<InputMap<Inputs>>>,
+    circles: Query<&Position, With<CircleMarker>>,
+) {
+    for (player_id, input) in players.iter() {
+        if !input.just_pressed(&Inputs::Delete) {
+            continue;
+        }
+        let client_id = player_id.0;
+        let Some(circle) = spawned_circles
+            .0
+            .get_mut(&client_id)
+            .and_then(|circles| circles.pop())
+        else {
+            continue;
+        };
+        if let Ok(position) = circles.get(circle.entity) {
+            // the circle is visible to the clients in its room and to the ones it is relevant to
+            let clients: HashSet<ClientId> = global
+                .client_id_to_room_id
+                .iter()
+                .filter(|(_, room)| **room == circle.room)
+                .map(|(client_id, _)| *client_id)
+                .chain(
+                    relevant_circles
+                        .0
+                        .iter()
+                        .filter(|(_, relevant)| relevant.contains(&circle.entity))
+                        .map(|(client_id, _)| *client_id),
+                )
+                .collect();
+            outbox.send(
+                EntityDestroyed { position: position.0 },
+                NetworkTarget::Only(clients.into_iter().collect()),
+                MessagePriority::High,
+                "send entity destroyed",
+            );
+        }
+        despawn_spawned_circle(
+            &mut commands,
+            &circle,
+            &mut room_manager,
+            &mut room_registry,
+            &mut relevant_circles,
+            &mut relevance_budget,
+        );
+        info!("Client {} deleted circle {:?}", client_id, circle.entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::diagnostic::DiagnosticsStore;
+    use bevy::ecs::component::Tick;
+    use bevy::ecs::schedule::ScheduleLabel;
+    use bevy::ecs::system::{System, SystemState};
+    use bevy::log::tracing_subscriber::{self, layer::Context, prelude::*, Layer};
+    use bevy::utils::tracing::field::Field;
+    use lightyear::prelude::client::{ClientCommands, Confirmed};
+
+    use crate::diagnostics::interest_management_average_ms;
+    use crate::replay::Replay;
+    use crate::rooms::RoomProperty;
+    use crate::test_utils::{
+        connect, play_replay, player_of, press, release, server_app, server_app_with, step,
+        Stepper,
+    };
+
+    #[test]
+    fn players_on_the_edges_of_the_world_are_in_the_occupancy_grid() {
+        let config = InterestConfig::default();
+        let half_extent = config.half_extent();
+        let positions = [
+            Position(Vec2::splat(half_extent)),
+            Position(Vec2::splat(-half_extent)),
+        ];
+        let grid = compute_occupancy(&config, positions.iter());
+        assert!(grid.is_occupied(grid.width - 1, grid.height - 1));
+        assert!(grid.is_occupied(0, 0));
+    }
+
+    #[test]
+    fn each_connected_client_gets_its_own_player() {
+        let mut app = server_app();
+        let clients = [ClientId::Netcode(1), ClientId::Netcode(2)];
+        for client_id in clients {
+            let player = connect(&mut app, client_id).expect("the client is admitted");
+            assert_eq!(app.world().get::<PlayerId>(player).unwrap().0, client_id);
+        }
+        let spawn_room = InterestConfig::default().room_id_for_position(SPAWN_POSITION);
+        let global = app.world().resource::<Global>();
+        assert_ne!(
+            global.client_id_to_entity_id[&clients[0]],
+            global.client_id_to_entity_id[&clients[1]]
+        );
+        for client_id in clients {
+            assert_eq!(global.client_id_to_room_id[&client_id], spawn_room);
+        }
+    }
+
+    /// Spawn a replicated circle, like the ones spawned by the clients
+    fn spawn_circle(app: &mut App, position: Vec2) -> Entity {
+        let world = app.world_mut();
+        let room = world.resource::<InterestConfig>().room_id_for_position(position);
+        let circle = world
+            .spawn(spawned_circle_bundle(position, room, None, &GroupStrategy::default()))
+            .id();
+        world.resource_mut::<RoomManager>().add_entity(circle, room);
+        circle
+    }
+
+    fn is_relevant(app: &App, client_id: ClientId, circle: Entity) -> bool {
+        app.world()
+            .resource::<RelevantCircles>()
+            .0
+            .get(&client_id)
+            .map_or(false, |circles| circles.contains(&circle))
+    }
+
+    #[test]
+    fn a_larger_interest_radius_requested_by_a_client_makes_further_circles_relevant() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        connect(&mut app, client_id).unwrap();
+        // beyond the default radius, even expanded for the newly spawned circles
+        let circle = spawn_circle(&mut app, Vec2::new(400.0, 0.0));
+        step(&mut app, 3);
+        assert!(!is_relevant(&app, client_id, circle));
+
+        app.world_mut()
+            .send_event(MessageEvent::new(SetInterestRadius(500.0), client_id));
+        step(&mut app, 3);
+        assert!(is_relevant(&app, client_id, circle));
+
+        // the requested radius is clamped
+        app.world_mut()
+            .send_event(MessageEvent::new(SetInterestRadius(5000.0), client_id));
+        step(&mut app, 1);
+        let config = InterestConfig::default();
+        let radius = app.world().resource::<ClientInterestRadius>().radius(client_id, &config);
+        assert_eq!(radius, MAX_INTEREST_RADIUS);
+    }
+
+    #[test]
+    fn a_burst_of_connections_is_admitted_over_several_frames() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(AdmissionQueue {
+                max_admissions_per_frame: Some(2),
+                ..default()
+            });
+        });
+        for id in 1..=5 {
+            app.world_mut().send_event(ConnectEvent {
+                client_id: ClientId::Netcode(id),
+            });
+        }
+        for expected in [2, 4, 5] {
+            app.update();
+            let global = app.world().resource::<Global>();
+            assert_eq!(global.client_id_to_entity_id.len(), expected);
+        }
+    }
+
+    #[test]
+    fn inputs_move_the_player_controlled_by_the_client() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        for (_, client_app) in stepper.client_apps.iter_mut() {
+            client_app.add_systems(Update, crate::client::add_input_map);
+        }
+        // wait for the predicted players, which get the `InputMap` that sends the inputs
+        stepper.frame_steps(20);
+        let player_a = player_of(&stepper.server_app, a).unwrap();
+        let player_b = player_of(&stepper.server_app, b).unwrap();
+        assert_eq!(
+            stepper.server_app.world().get::<ControlledBy>(player_a).unwrap().target,
+            NetworkTarget::Single(a)
+        );
+        let position = |app: &App, player| app.world().get::<Position>(player).unwrap().0;
+        let start_a = position(&stepper.server_app, player_a);
+        let start_b = position(&stepper.server_app, player_b);
+
+        stepper
+            .client_app(a)
+            .world_mut()
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .press(KeyCode::ArrowRight);
+        stepper.frame_steps(20);
+        let end_a = position(&stepper.server_app, player_a);
+        assert!(end_a.x > start_a.x, "{} didn't move right of {}", end_a, start_a);
+        assert_eq!(end_a.y, start_a.y);
+        assert_eq!(position(&stepper.server_app, player_b), start_b);
+    }
+
+    #[test]
+    fn players_in_the_same_room_are_replicated_to_each_other() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        stepper.frame_steps(20);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        stepper.assert_replicated::<Position>(a, b);
+        stepper.assert_replicated::<PlayerColor>(a, b);
+        stepper.assert_replicated::<Team>(b, a);
+        stepper.assert_replicated::<Health>(b, a);
+    }
+
+    #[test]
+    fn changed_player_texts_are_sent_to_the_clients_in_the_room() {
+        let mut world = World::new();
+        world.init_resource::<InterestConfig>();
+        world.init_resource::<Global>();
+        world.init_resource::<PausedClients>();
+        world.init_resource::<FollowTargets>();
+        world.init_resource::<Outbox>();
+        let mut time = Time::<()>::default();
+        time.advance_by(Duration::from_secs(6));
+        world.insert_resource(time);
+
+        let player = world.spawn(CurrentRoom(RoomId(1))).id();
+        let text_entity = world
+            .spawn((
+                PlayerParent(player),
+                PlayerText("Player 1".to_string()),
+                TimerComponent(Timer::from_seconds(5.0, TimerMode::Once)),
+            ))
+            .id();
+        let mut global = world.resource_mut::<Global>();
+        global.client_id_to_room_id.insert(ClientId::Netcode(1), RoomId(1));
+        global.client_id_to_room_id.insert(ClientId::Netcode(2), RoomId(2));
+
+        world.run_system_once(check_timers);
+        assert_eq!(world.get::<PlayerText>(text_entity).unwrap().0, "Server changed");
+        assert_eq!(world.resource::<Outbox>().len(), 1);
+        assert!(world.get::<TimerComponent>(text_entity).is_none());
+    }
+
+    #[test]
+    fn respawned_players_count_their_deaths_in_their_metadata() {
+        let mut world = World::new();
+        let player = world
+            .spawn((
+                PlayerId(ClientId::Netcode(1)),
+                Position(Vec2::new(100.0, 100.0)),
+                Velocity(Vec2::ONE),
+                Health { current: 0.0, max: 100.0 },
+            ))
+            .id();
+
+        world.run_system_once(respawn);
+        assert_eq!(world.get::<Metadata>(player).unwrap().get(DEATHS_KEY), Some("1"));
+        assert_eq!(world.get::<Position>(player).unwrap().0, SPAWN_POSITION);
+        assert!(world.get::<Shielded>(player).is_some());
+
+        world.get_mut::<Health>(player).unwrap().current = 0.0;
+        world.run_system_once(respawn);
+        assert_eq!(world.get::<Metadata>(player).unwrap().get(DEATHS_KEY), Some("2"));
+    }
+
+    #[test]
+    fn sound_emitters_in_the_audio_band_are_hinted() {
+        let mut world = World::new();
+        world.insert_resource(AudioRadius(Some(500.0)));
+        world.init_resource::<RelevantCircles>();
+        world.init_resource::<PausedClients>();
+        world.init_resource::<AudioRelevantEntities>();
+        world.init_resource::<Outbox>();
+        let client_id = ClientId::Netcode(1);
+        world.spawn((PlayerId(client_id), Position(Vec2::ZERO)));
+        let group_strategy = GroupStrategy::default();
+        let [visible, in_band, too_far] = [100.0, 300.0, 600.0].map(|x| {
+            world
+                .spawn(spawned_circle_bundle(Vec2::new(x, 0.0), RoomId(0), None, &group_strategy))
+                .id()
+        });
+        world
+            .resource_mut::<RelevantCircles>()
+            .0
+            .insert(client_id, HashSet::from_iter([visible]));
+
+        world.run_system_once(update_audio_relevance);
+        let hinted = &world.resource::<AudioRelevantEntities>().0[&client_id];
+        assert!(hinted.contains(&in_band));
+        assert!(!hinted.contains(&visible));
+        assert!(!hinted.contains(&too_far));
+        assert_eq!(world.resource::<Outbox>().len(), 1);
+    }
+
+    fn debug_command_world(clients: &[ClientId]) -> World {
+        let mut world = World::new();
+        world.insert_resource(AllowDebugCommands(true));
+        world.init_resource::<InterestConfig>();
+        world.init_resource::<Global>();
+        world.init_resource::<GroupStrategy>();
+        world.init_resource::<PausedClients>();
+        world.init_resource::<FollowTargets>();
+        world.init_resource::<RelevanceBudget>();
+        world.init_resource::<RelevantCircles>();
+        world.init_resource::<RoomRegistry>();
+        world.init_resource::<TestBurstRng>();
+        world.insert_resource(RoomManager::default());
+        world.insert_resource(RelevanceManager::default());
+        world.init_resource::<Events<MessageEvent<DebugCommand>>>();
+        for (i, client_id) in clients.iter().enumerate() {
+            let player = world.spawn(Position(Vec2::new(i as f32 * 1000.0, 0.0))).id();
+            let mut global = world.resource_mut::<Global>();
+            global.client_id_to_entity_id.insert(*client_id, player);
+            global.client_id_to_room_id.insert(*client_id, RoomId(i as u64));
+        }
+        world
+    }
+
+    fn run_debug_command(world: &mut World, client_id: ClientId, command: DebugCommand) {
+        world.send_event(MessageEvent::new(command, client_id));
+        world.run_system_once(receive_debug_commands);
+        world.resource_mut::<Events<MessageEvent<DebugCommand>>>().clear();
+    }
+
+    #[test]
+    fn debug_commands_toggle_the_pause() {
+        let client_id = ClientId::Netcode(1);
+        let mut world = debug_command_world(&[client_id]);
+        run_debug_command(&mut world, client_id, DebugCommand::TogglePause);
+        assert!(world.resource::<PausedClients>().0.contains(&client_id));
+        run_debug_command(&mut world, client_id, DebugCommand::TogglePause);
+        assert!(!world.resource::<PausedClients>().0.contains(&client_id));
+    }
+
+    #[test]
+    fn the_pause_command_is_ignored_unless_the_debug_commands_are_allowed() {
+        let client_id = ClientId::Netcode(1);
+        let mut world = debug_command_world(&[client_id]);
+        world.insert_resource(AllowDebugCommands(false));
+        run_debug_command(&mut world, client_id, DebugCommand::TogglePause);
+        assert!(!world.resource::<PausedClients>().0.contains(&client_id));
+    }
+
+    #[test]
+    fn debug_commands_cycle_through_the_players_to_follow() {
+        let clients = [ClientId::Netcode(1), ClientId::Netcode(2), ClientId::Netcode(3)];
+        let mut world = debug_command_world(&clients);
+        let target = |world: &World| {
+            world.resource::<FollowTargets>().0.get(&clients[0]).map(|follow| follow.target)
+        };
+        run_debug_command(&mut world, clients[0], DebugCommand::FollowNext);
+        assert_eq!(target(&world), Some(clients[1]));
+        run_debug_command(&mut world, clients[0], DebugCommand::FollowNext);
+        assert_eq!(target(&world), Some(clients[2]));
+        run_debug_command(&mut world, clients[0], DebugCommand::FollowNext);
+        assert_eq!(target(&world), None);
+    }
+
+    #[test]
+    fn the_follow_command_is_ignored_unless_the_debug_commands_are_allowed() {
+        let clients = [ClientId::Netcode(1), ClientId::Netcode(2)];
+        let mut world = debug_command_world(&clients);
+        world.insert_resource(AllowDebugCommands(false));
+        run_debug_command(&mut world, clients[0], DebugCommand::FollowNext);
+        assert!(world.resource::<FollowTargets>().0.is_empty());
+    }
+
+    #[test]
+    fn test_bursts_are_spawned_near_the_player() {
+        let clients = [ClientId::Netcode(1), ClientId::Netcode(2)];
+        let mut world = debug_command_world(&clients);
+        spawn_test_burst(&mut world, clients[1], 5);
+        let center = Vec2::new(1000.0, 0.0);
+        let mut circles = world.query_filtered::<&Position, With<CircleMarker>>();
+        let positions: Vec<Vec2> = circles.iter(&world).map(|position| position.0).collect();
+        assert_eq!(positions.len(), 5);
+        for position in positions {
+            assert!(position.distance(center) <= TEST_BURST_RADIUS);
+        }
+    }
+
+    #[test]
+    fn room_viewers_include_followers_and_exclude_paused_clients() {
+        let mut world = World::new();
+        world.init_resource::<InterestConfig>();
+        world.init_resource::<Global>();
+        world.init_resource::<PausedClients>();
+        world.init_resource::<FollowTargets>();
+        let (a, b, c, d) = (
+            ClientId::Netcode(1),
+            ClientId::Netcode(2),
+            ClientId::Netcode(3),
+            ClientId::Netcode(4),
+        );
+        let mut global = world.resource_mut::<Global>();
+        for client_id in [a, b, c] {
+            global.client_id_to_room_id.insert(client_id, RoomId(1));
+        }
+        global.client_id_to_room_id.insert(d, RoomId(2));
+        world.resource_mut::<PausedClients>().0.insert(b);
+        // `c` follows a player in room 2
+        world.resource_mut::<FollowTargets>().0.insert(
+            c,
+            FollowTarget {
+                target: d,
+                room: Some(RoomId(2)),
+            },
+        );
+
+        let viewers = |world: &mut World, room| {
+            let mut clients = world
+                .run_system_once(move |viewers: RoomViewers| viewers.clients(room));
+            clients.sort_by_key(|client_id| client_id.to_bits());
+            clients
+        };
+        assert_eq!(viewers(&mut world, RoomId(1)), vec![a]);
+        assert_eq!(viewers(&mut world, RoomId(2)), vec![c, d]);
+    }
+
+    #[test]
+    fn texts_are_only_replicated_to_the_clients_that_see_their_player() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        stepper.frame_steps(20);
+        let texts_seen_by_a = |stepper: &mut Stepper| {
+            let world = stepper.client_app(a).world_mut();
+            let mut texts = world.query_filtered::<(), (With<PlayerText>, With<Confirmed>)>();
+            texts.iter(world).count()
+        };
+        assert_eq!(texts_seen_by_a(&mut stepper), 2);
+
+        // move the player of b to another room
+        let player_b = player_of(&stepper.server_app, b).unwrap();
+        stepper
+            .server_app
+            .world_mut()
+            .get_mut::<Position>(player_b)
+            .unwrap()
+            .0 = Vec2::new(1000.0, 1000.0);
+        stepper.frame_steps(20);
+        assert_eq!(texts_seen_by_a(&mut stepper), 1);
+
+        // on the server, the text is in the same rooms as its player
+        let world = stepper.server_app.world();
+        let text_b = world.resource::<Global>().player_to_text_entity[&player_b];
+        let room_b = world.resource::<Global>().client_id_to_room_id[&b];
+        let room = world.resource::<RoomManager>().get_room(room_b).unwrap();
+        assert!(room.entities.contains(&player_b) && room.entities.contains(&text_b));
+    }
+
+    #[test]
+    fn the_input_snapshots_are_replicated_to_the_spectators_only() {
+        let mut app = server_app();
+        let (player, spectator) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let entity = connect(&mut app, player).unwrap();
+        connect(&mut app, spectator).unwrap();
+        app.world_mut().resource_mut::<Spectators>().0.insert(spectator);
+        for _ in 0..2 {
+            press(&mut app, player, Inputs::Right);
+            press(&mut app, player, Inputs::Up);
+            step(&mut app, 1);
+        }
+
+        let snapshot = *app.world().get::<InputSnapshot>(entity).unwrap();
+        assert!(snapshot.is_pressed(Inputs::Right) && snapshot.is_pressed(Inputs::Up));
+        assert!(!snapshot.is_pressed(Inputs::Left));
+        let target = &app
+            .world()
+            .get::<OverrideTargetComponent<InputSnapshot>>(entity)
+            .unwrap()
+            .target;
+        assert_eq!(target, &NetworkTarget::Only(vec![spectator]));
+
+        release(&mut app, player, Inputs::Right);
+        release(&mut app, player, Inputs::Up);
+        step(&mut app, 2);
+        assert_eq!(app.world().get::<InputSnapshot>(entity).unwrap().0, 0);
+    }
+
+    /// What a test can compare between two servers: the circle positions, and for each client
+    /// its player's position, its room and the number of circles relevant to it
+    fn world_summary(app: &mut App) -> (Vec<(i32, i32)>, Vec<(ClientId, Vec2, RoomId, usize)>) {
+        let world = app.world_mut();
+        let mut circles: Vec<(i32, i32)> = world
+            .query_filtered::<&Position, With<CircleMarker>>()
+            .iter(world)
+            .map(|position| (position.x as i32, position.y as i32))
+            .collect();
+        circles.sort();
+        let global = world.resource::<Global>();
+        let relevant_circles = world.resource::<RelevantCircles>();
+        let mut players: Vec<_> = global
+            .client_id_to_entity_id
+            .iter()
+            .map(|(client_id, entity)| {
+                (
+                    *client_id,
+                    world.get::<Position>(*entity).unwrap().0,
+                    global.client_id_to_room_id[client_id],
+                    relevant_circles.0.get(client_id).map_or(0, |circles| circles.len()),
+                )
+            })
+            .collect();
+        players.sort_by_key(|(client_id, ..)| client_id.to_bits());
+        (circles, players)
+    }
+
+    #[test]
+    fn after_a_reset_the_world_matches_a_fresh_start() {
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let mut fresh = server_app();
+        connect(&mut fresh, a).unwrap();
+        connect(&mut fresh, b).unwrap();
+        step(&mut fresh, 2);
+
+        let mut app = server_app();
+        let player_a = connect(&mut app, a).unwrap();
+        connect(&mut app, b).unwrap();
+        app.world_mut().get_mut::<Position>(player_a).unwrap().0 = Vec2::new(900.0, -700.0);
+        spawn_circle(&mut app, Vec2::new(950.0, -700.0));
+        pause_replication(app.world_mut(), b);
+        step(&mut app, 2);
+
+        reset_world(app.world_mut());
+        step(&mut app, 2);
+        assert!(app.world().resource::<PausedClients>().0.is_empty());
+        assert_ne!(player_of(&app, a), Some(player_a));
+        assert_eq!(world_summary(&mut app), world_summary(&mut fresh));
+    }
+
+    #[test]
+    fn each_circle_relevance_mode_gives_its_relevance_set() {
+        let client_id = ClientId::Netcode(1);
+        for (mode, expected) in [
+            (CircleRelevanceMode::DistanceOnly, [true, true, false, false]),
+            (CircleRelevanceMode::RoomOnly, [true, false, true, false]),
+            (CircleRelevanceMode::DistanceAndRoom, [true, false, false, false]),
+        ] {
+            let mut app = server_app_with(|app| {
+                app.insert_resource(CircleInterestStrategy::new(mode));
+            });
+            connect(&mut app, client_id).unwrap();
+            // the player spawns at the corner of its room
+            let circles = [
+                Vec2::new(50.0, 50.0),
+                Vec2::new(-30.0, 0.0),
+                Vec2::new(190.0, 190.0),
+                Vec2::new(600.0, 600.0),
+            ]
+            .map(|position| spawn_circle(&mut app, position));
+            step(&mut app, 3);
+            for (circle, expected) in circles.into_iter().zip(expected) {
+                assert_eq!(is_relevant(&app, client_id, circle), expected, "{:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_interest_radius_requests_are_clamped() {
+        let config = InterestConfig::default();
+        let mut interest_radius = ClientInterestRadius::default();
+        let client_id = ClientId::Netcode(1);
+        assert_eq!(interest_radius.radius(client_id, &config), config.interest_radius);
+        assert_eq!(interest_radius.set(client_id, 1.0, &config), MIN_INTEREST_RADIUS);
+        assert_eq!(interest_radius.set(client_id, 1e6, &config), MAX_INTEREST_RADIUS);
+        assert_eq!(interest_radius.set(client_id, f32::NAN, &config), config.interest_radius);
+        assert_eq!(interest_radius.set(client_id, 300.0, &config), 300.0);
+        assert_eq!(interest_radius.radius(client_id, &config), 300.0);
+    }
+
+    /// Collects the fields of the error events logged while it is the default subscriber
+    #[derive(Clone, Default)]
+    struct CapturedErrors(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: bevy::utils::tracing::Subscriber> Layer<S> for CapturedErrors {
+        fn on_event(&self, event: &bevy::utils::tracing::Event<'_>, _: Context<'_, S>) {
+            if *event.metadata().level() != bevy::utils::tracing::Level::ERROR {
+                return;
+            }
+            let mut fields = String::new();
+            event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                fields.push_str(&format!("{}={:?} ", field.name(), value));
+            });
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    #[test]
+    fn replication_errors_are_logged_with_their_context() {
+        let mut world = World::new();
+        world.init_resource::<Events<ReplicationErrorEvent>>();
+        let (client_id, entity) = (ClientId::Netcode(7), Entity::from_raw(12));
+        world.send_event(
+            ReplicationErrorEvent::new("send chat message", "MessageBufferFull")
+                .with_client(client_id)
+                .with_entity(entity),
+        );
+
+        let captured = CapturedErrors::default();
+        let subscriber = tracing_subscriber::registry().with(captured.clone());
+        bevy::utils::tracing::subscriber::with_default(subscriber, || {
+            world.run_system_once(log_replication_errors);
+        });
+        let errors = captured.0.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("Failed to send chat message: \"MessageBufferFull\""));
+        assert!(errors[0].contains(&format!("{:?}", Some(client_id))));
+        assert!(errors[0].contains(&format!("{:?}", Some(entity))));
+    }
+
+    /// The rooms of the `RoomManager` that contain the client, and the ones that contain its player
+    fn rooms_of(app: &App, client_id: ClientId) -> (Vec<RoomId>, Vec<RoomId>) {
+        let player = player_of(app, client_id).unwrap();
+        let room_manager = app.world().resource::<RoomManager>();
+        let rooms = || {
+            (-3..=3)
+                .flat_map(|x| (-3..=3).map(move |y| shared::room_id_for_cell(IVec2::new(x, y))))
+                .filter_map(|room_id| Some((room_id, room_manager.get_room(room_id)?)))
+        };
+        (
+            rooms()
+                .filter(|(_, room)| room.clients.contains(&client_id))
+                .map(|(room_id, _)| room_id)
+                .collect(),
+            rooms()
+                .filter(|(_, room)| room.entities.contains(&player))
+                .map(|(room_id, _)| room_id)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn players_leave_the_spawn_room_like_any_other_room() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        assert_eq!(rooms_of(&app, client_id), (vec![RoomId(0)], vec![RoomId(0)]));
+
+        let other_room = shared::room_id_for_cell(IVec2::new(2, 0));
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(500.0, 10.0);
+        step(&mut app, 2);
+        assert_eq!(rooms_of(&app, client_id), (vec![other_room], vec![other_room]));
+
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(10.0, 10.0);
+        step(&mut app, 2);
+        assert_eq!(rooms_of(&app, client_id), (vec![RoomId(0)], vec![RoomId(0)]));
+    }
+
+    #[test]
+    fn players_converge_to_their_move_target() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let target = Vec2::new(55.0, -32.0);
+        app.world_mut()
+            .send_event(MessageEvent::new(MoveTarget(target), client_id));
+        // 64 units at 10 per tick
+        step(&mut app, 20);
+        assert_eq!(app.world().get::<Position>(player).unwrap().0, target);
+        assert!(app.world().get::<Destination>(player).is_none());
+    }
+
+    #[test]
+    fn clients_only_see_the_current_room_of_their_own_player() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        stepper.frame_steps(20);
+        stepper.assert_replicated::<CurrentRoom>(a, a);
+        let world = stepper.client_app(b).world_mut();
+        let mut players = world.query_filtered::<(&PlayerId, Has<CurrentRoom>), With<Confirmed>>();
+        let players: Vec<_> = players.iter(world).collect();
+        assert!(players.contains(&(&PlayerId(a), false)));
+        assert!(players.contains(&(&PlayerId(b), true)));
+
+        let player_a = player_of(&stepper.server_app, a).unwrap();
+        stepper
+            .server_app
+            .world_mut()
+            .get_mut::<Position>(player_a)
+            .unwrap()
+            .0 = Vec2::new(500.0, 10.0);
+        stepper.frame_steps(20);
+        let room = stepper.server_app.world().get::<CurrentRoom>(player_a).unwrap().0;
+        assert_eq!(room, shared::room_id_for_cell(IVec2::new(2, 0)));
+        stepper.assert_replicated::<CurrentRoom>(a, a);
+    }
+
+    #[test]
+    fn removing_a_replicated_marker_removes_it_on_the_clients() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        stepper.frame_steps(20);
+        let player_a = player_of(&stepper.server_app, a).unwrap();
+        let shielded_copies = |stepper: &mut Stepper, observer| {
+            let world = stepper.client_app(observer).world_mut();
+            let mut players = world.query::<(&PlayerId, Has<Shielded>)>();
+            players
+                .iter(world)
+                .filter(|(player_id, shielded)| player_id.0 == a && *shielded)
+                .count()
+        };
+        // the players spawn shielded: the confirmed entity and its predicted or interpolated copy
+        assert_eq!(shielded_copies(&mut stepper, a), 2);
+        assert_eq!(shielded_copies(&mut stepper, b), 2);
+
+        stepper
+            .server_app
+            .world_mut()
+            .entity_mut(player_a)
+            .remove::<(Shielded, SpawnProtection)>();
+        stepper.frame_steps(20);
+        assert_eq!(shielded_copies(&mut stepper, a), 0);
+        assert_eq!(shielded_copies(&mut stepper, b), 0);
+    }
+
+    #[test]
+    fn under_a_tight_cap_only_the_nearest_circles_are_relevant() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(MaxRelevantCircles(2));
+            // keep the static grid out of the relevance, it has a circle on the player
+            app.insert_resource(CompactCircleGrid(true));
+        });
+        let client_id = ClientId::Netcode(1);
+        connect(&mut app, client_id).unwrap();
+        // spawned from the furthest to the nearest, so that the spawn order doesn't help
+        let circles = [100.0, 80.0, 60.0, 40.0, 20.0]
+            .map(|distance| spawn_circle(&mut app, Vec2::new(distance, 10.0)));
+        step(&mut app, 3);
+        let relevant: Vec<bool> = circles
+            .iter()
+            .map(|circle| is_relevant(&app, client_id, *circle))
+            .collect();
+        assert_eq!(relevant, [false, false, false, true, true]);
+    }
+
+    /// The players (confirmed) seen by a client
+    fn players_seen_by(stepper: &mut Stepper, observer: ClientId) -> Vec<ClientId> {
+        let world = stepper.client_app(observer).world_mut();
+        let mut players = world.query_filtered::<&PlayerId, With<Confirmed>>();
+        let mut seen: Vec<ClientId> = players.iter(world).map(|player_id| player_id.0).collect();
+        seen.sort_by_key(|client_id| client_id.to_bits());
+        seen
+    }
+
+    /// Move the player of a client, on the server
+    fn teleport(stepper: &mut Stepper, client_id: ClientId, position: Vec2) {
+        let player = player_of(&stepper.server_app, client_id).unwrap();
+        stepper
+            .server_app
+            .world_mut()
+            .get_mut::<Position>(player)
+            .unwrap()
+            .0 = position;
+    }
+
+    #[test]
+    fn distant_players_in_the_same_room_are_culled() {
+        let mut stepper = Stepper::with(&[1, 2, 3], |app| {
+            app.insert_resource(PlayerCulling(true));
+        });
+        let (a, b, c) = (ClientId::Netcode(1), ClientId::Netcode(2), ClientId::Netcode(3));
+        // all in the spawn room, b is out of the interest radius of a
+        teleport(&mut stepper, b, Vec2::new(190.0, 190.0));
+        teleport(&mut stepper, c, Vec2::new(40.0, 40.0));
+        stepper.frame_steps(20);
+        assert_eq!(players_seen_by(&mut stepper, a), vec![a, c]);
+        assert_eq!(players_seen_by(&mut stepper, b), vec![b]);
+
+        // b comes closer
+        teleport(&mut stepper, b, Vec2::new(100.0, 0.0));
+        stepper.frame_steps(20);
+        assert_eq!(players_seen_by(&mut stepper, a), vec![a, b, c]);
+    }
+
+    #[test]
+    fn culling_only_updates_the_pairs_that_cross_the_interest_radius() {
+        let mut app = server_app_with(|app| {
+            // keep the static grid out of the relevance
+            app.insert_resource(CompactCircleGrid(true));
+            app.insert_resource(PlayerCulling(true));
+            let mut metrics = RelevanceMetrics::default();
+            metrics.timer = Timer::from_seconds(1000.0, TimerMode::Repeating);
+            app.insert_resource(metrics);
+        });
+        // roll the counters over on the next frame, and return the gains and losses since the last
+        // roll over
+        let roll_over = |app: &mut App| {
+            let mut metrics = app.world_mut().resource_mut::<RelevanceMetrics>();
+            let duration = metrics.timer.duration();
+            metrics.timer.set_elapsed(duration - Duration::from_millis(1));
+            step(app, 1);
+            let counts = app.world().resource::<RelevanceMetrics>().last_second;
+            (counts.gains, counts.losses)
+        };
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        connect(&mut app, a).unwrap();
+        let player_b = connect(&mut app, b).unwrap();
+        step(&mut app, 10);
+        roll_over(&mut app);
+
+        // b keeps moving within the interest radius of a
+        for x in [10.0, 20.0, 30.0, 40.0] {
+            app.world_mut().get_mut::<Position>(player_b).unwrap().0 = Vec2::new(x, 0.0);
+            step(&mut app, 2);
+        }
+        assert_eq!(roll_over(&mut app), (0, 0));
+
+        // b walks out of the radius, in the same room: each client loses the other player and
+        // its text
+        app.world_mut().get_mut::<Position>(player_b).unwrap().0 = Vec2::new(190.0, 190.0);
+        step(&mut app, 2);
+        assert_eq!(roll_over(&mut app), (0, 4));
+    }
+
+    #[test]
+    fn each_replication_category_is_replicated_as_documented() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let mut spawn = |category, position, relevance_mode| {
+            app.world_mut()
+                .spawn((
+                    Position(position),
+                    category,
+                    Replicate {
+                        relevance_mode,
+                        ..default()
+                    },
+                ))
+                .id()
+        };
+        let near = Vec2::new(30.0, 10.0);
+        let effect = spawn(
+            ReplicationCategory::Effect,
+            near,
+            NetworkRelevanceMode::InterestManagement,
+        );
+        let far_effect = spawn(
+            ReplicationCategory::Effect,
+            Vec2::new(600.0, 600.0),
+            NetworkRelevanceMode::InterestManagement,
+        );
+        let debug = spawn(ReplicationCategory::Debug, near, NetworkRelevanceMode::All);
+        step(&mut app, 3);
+
+        // effects are distance culled like the circles
+        assert!(is_relevant(&app, client_id, effect));
+        assert!(!is_relevant(&app, client_id, far_effect));
+        // debug entities are never replicated, even close to the player
+        assert!(!is_relevant(&app, client_id, debug));
+        assert!(matches!(
+            app.world().get::<NetworkRelevanceMode>(debug).unwrap(),
+            NetworkRelevanceMode::InterestManagement
+        ));
+        // players and their text go through the rooms instead
+        let text = app.world().resource::<Global>().player_to_text_entity[&player];
+        for (entity, category) in [
+            (player, ReplicationCategory::Player),
+            (text, ReplicationCategory::Text),
+        ] {
+            assert_eq!(app.world().get::<ReplicationCategory>(entity), Some(&category));
+            assert!(!category.is_distance_culled());
+        }
+    }
+
+    #[test]
+    fn moving_entities_join_the_group_of_their_new_room() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(GroupStrategy::PerRoom);
+        });
+        let player = connect(&mut app, ClientId::Netcode(1)).unwrap();
+        let text = app.world().resource::<Global>().player_to_text_entity[&player];
+        let circle = spawn_circle(&mut app, Vec2::new(50.0, 50.0));
+        let group_id = |app: &App, entity: Entity| {
+            app.world()
+                .get::<ReplicationGroup>(entity)
+                .unwrap()
+                .group_id(Some(entity))
+                .0
+        };
+        step(&mut app, 1);
+        for entity in [player, text, circle] {
+            assert_eq!(group_id(&app, entity), 0);
+        }
+
+        let new_room = shared::room_id_for_cell(IVec2::new(2, -1));
+        for entity in [player, circle] {
+            app.world_mut().get_mut::<Position>(entity).unwrap().0 = Vec2::new(450.0, -50.0);
+        }
+        step(&mut app, 2);
+        for entity in [player, text, circle] {
+            assert_eq!(group_id(&app, entity), new_room.0);
+        }
+    }
+
+    #[test]
+    fn the_text_of_a_player_is_replicated_to_the_other_clients_of_its_room() {
+        let mut stepper = Stepper::new(&[1, 2]);
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        stepper.frame_steps(20);
+        let player_a = player_of(&stepper.server_app, a).unwrap();
+        let world = stepper.server_app.world();
+        let text_a = world.resource::<Global>().player_to_text_entity[&player_a];
+        let spawn_room = world.resource::<RoomManager>().get_room(RoomId(0)).unwrap();
+        assert!(spawn_room.entities.contains(&text_a));
+
+        let world = stepper.client_app(b).world_mut();
+        let mut players = world.query_filtered::<(Entity, &PlayerId), With<Confirmed>>();
+        let (player_a_on_b, _) = players
+            .iter(world)
+            .find(|(_, player_id)| player_id.0 == a)
+            .expect("b sees the player of a");
+        let mut texts = world.query_filtered::<(&PlayerParent, &PlayerText), With<Confirmed>>();
+        assert!(texts.iter(world).any(|(parent, _)| parent.0 == player_a_on_b));
+    }
+
+    /// The tick at which the system called `name` in `schedule` last ran
+    fn last_run(app: &App, schedule: impl ScheduleLabel, name: &str) -> Tick {
+        let suffix = format!("::{}", name);
+        let (_, system) = app
+            .get_schedule(schedule)
+            .expect("the schedule exists")
+            .systems()
+            .expect("the schedule ran")
+            .find(|(_, system)| system.name().ends_with(&suffix))
+            .expect("the system is in the schedule");
+        system.get_last_run()
+    }
+
+    #[test]
+    fn the_player_systems_are_skipped_without_clients() {
+        // the ticks only change when the systems actually run, not when their condition is false
+        let last_runs = |app: &App| {
+            [
+                last_run(app, FixedUpdate, "movement"),
+                last_run(app, Update, "interest_management"),
+            ]
+        };
+        let mut app = server_app();
+        step(&mut app, 1);
+        let before = last_runs(&app);
+        step(&mut app, 10);
+        assert!(!app.world_mut().run_system_once(has_players));
+        assert_eq!(last_runs(&app), before);
+        // `interest_management` records its duration every time it runs
+        let diagnostics = app.world().resource::<DiagnosticsStore>();
+        assert_eq!(interest_management_average_ms(diagnostics), None);
+
+        connect(&mut app, ClientId::Netcode(1)).unwrap();
+        step(&mut app, 10);
+        assert!(app.world_mut().run_system_once(has_players));
+        for (after, before) in last_runs(&app).iter().zip(before.iter()) {
+            assert_ne!(after, before);
+        }
+        let diagnostics = app.world().resource::<DiagnosticsStore>();
+        assert!(interest_management_average_ms(diagnostics).is_some());
+    }
+
+    #[test]
+    fn players_coming_back_to_a_room_are_replicated_in_it_again() {
+        let replay = Replay::parse(include_str!("../assets/replays/room_reentry.replay")).unwrap();
+        let mut app = server_app();
+        let mut visited = Vec::new();
+        let client_ids = play_replay(&mut app, &replay, |app| {
+            let rooms = rooms_of(app, ClientId::Netcode(1));
+            if visited.last() != Some(&rooms) {
+                visited.push(rooms);
+            }
+        });
+        // let the player slow down and stop
+        step(&mut app, 40);
+        let (walker, watcher) = (client_ids[0], client_ids[1]);
+
+        let spawn_room = (vec![RoomId(0)], vec![RoomId(0)]);
+        let right_room = shared::room_id_for_cell(IVec2::new(1, 0));
+        assert_eq!(visited.first(), Some(&spawn_room));
+        assert!(visited.contains(&(vec![right_room], vec![right_room])));
+        assert_eq!(rooms_of(&app, walker), spawn_room);
+        // the watcher still sees the player and its text
+        assert_eq!(rooms_of(&app, watcher), spawn_room);
+        let player = player_of(&app, walker).unwrap();
+        let text = app.world().resource::<Global>().player_to_text_entity[&player];
+        let room = app.world().resource::<RoomManager>().get_room(RoomId(0)).unwrap();
+        assert!(room.entities.contains(&text));
+        assert!(room.clients.contains(&watcher));
+    }
+
+    #[test]
+    fn rebalancing_keeps_the_groups_under_their_maximum_size() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(GroupStrategy::PerRoom);
+            app.insert_resource(GroupRebalancing {
+                timer: Timer::from_seconds(0.001, TimerMode::Repeating),
+                max_group_size: Some(3),
+            });
+        });
+        let group_sizes = |app: &mut App| {
+            let mut sizes: HashMap<u64, usize> = HashMap::default();
+            let mut groups = app.world_mut().query::<(Entity, &ReplicationGroup)>();
+            for (entity, group) in groups.iter(app.world()) {
+                *sizes.entry(group.group_id(Some(entity)).0).or_default() += 1;
+            }
+            sizes
+        };
+        let room = shared::room_id_for_cell(IVec2::new(1, 1));
+        let other_room = shared::room_id_for_cell(IVec2::new(2, 1));
+        // with the static circle of the room, 9 circles start in the same room
+        let circles: Vec<Entity> = (0..8)
+            .map(|i| spawn_circle(&mut app, Vec2::new(210.0 + 10.0 * i as f32, 210.0)))
+            .collect();
+        step(&mut app, 3);
+        let sizes = group_sizes(&mut app);
+        assert_eq!(sizes[&room.0], 3);
+        assert!(sizes.values().all(|size| *size <= 3));
+
+        // half of them scatter to the next room, then they all regroup
+        for circle in circles.iter().take(4) {
+            app.world_mut().get_mut::<Position>(*circle).unwrap().0 = Vec2::new(410.0, 210.0);
+        }
+        step(&mut app, 3);
+        let sizes = group_sizes(&mut app);
+        assert_eq!((sizes[&room.0], sizes[&other_room.0]), (3, 3));
+        assert!(sizes.values().all(|size| *size <= 3));
+
+        for circle in circles.iter() {
+            app.world_mut().get_mut::<Position>(*circle).unwrap().0 = Vec2::new(210.0, 210.0);
+        }
+        step(&mut app, 3);
+        let sizes = group_sizes(&mut app);
+        assert_eq!((sizes[&room.0], sizes.get(&other_room.0)), (3, Some(&1)));
+        assert!(sizes.values().all(|size| *size <= 3));
+    }
+
+    #[test]
+    fn the_relevance_budget_is_shared_fairly_between_the_clients() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(RelevanceBudget {
+                max_updates_per_tick: Some(2),
+                ..default()
+            });
+        });
+        let destination = Vec2::new(1000.0, 1000.0);
+        for i in 0..6 {
+            spawn_circle(&mut app, destination + Vec2::new(10.0 * i as f32, 10.0));
+        }
+        let client_ids = [1, 2, 3].map(ClientId::Netcode);
+        for client_id in client_ids {
+            connect(&mut app, client_id).unwrap();
+        }
+        step(&mut app, 10);
+        assert_eq!(app.world().resource::<RelevanceBudget>().num_pending(), 0);
+
+        // every client now has the same updates to apply: losing the circle of the spawn room and
+        // gaining the ones around the destination
+        for client_id in client_ids {
+            let player = player_of(&app, client_id).unwrap();
+            app.world_mut().get_mut::<Position>(player).unwrap().0 = destination;
+        }
+        let pending = |app: &App| {
+            let budget = app.world().resource::<RelevanceBudget>();
+            client_ids.map(|client_id| budget.pending.get(&client_id).map_or(0, VecDeque::len))
+        };
+        let mut last_pending = None;
+        for _ in 0..60 {
+            step(&mut app, 1);
+            let pending = pending(&app);
+            let (min, max) = (pending.iter().min().unwrap(), pending.iter().max().unwrap());
+            assert!(max - min <= 1, "a client fell behind: {:?}", pending);
+            if let Some(last_pending) = last_pending {
+                let applied = last_pending - pending.iter().sum::<usize>();
+                assert!(applied <= 2, "{} updates were applied in a tick", applied);
+            }
+            last_pending = Some(pending.iter().sum());
+        }
+        assert_eq!(last_pending, Some(0));
+        let relevant_circles = &app.world().resource::<RelevantCircles>().0;
+        assert_eq!(relevant_circles[&client_ids[0]].len(), 7);
+        assert_eq!(relevant_circles[&client_ids[0]], relevant_circles[&client_ids[2]]);
+    }
+
+    #[test]
+    fn clients_whose_relevance_flips_every_tick_get_frozen() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(ChurnBreaker {
+                max_changes_per_window: 20,
+                ..default()
+            });
+        });
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let calm_client_id = ClientId::Netcode(2);
+        connect(&mut app, calm_client_id).unwrap();
+        // jump back and forth between two circles, so that both flip their relevance every time
+        let flip = |app: &mut App, frames: usize| {
+            for _ in 0..frames {
+                let mut position = app.world_mut().get_mut::<Position>(player).unwrap();
+                position.0 = if position.0 == Vec2::ZERO {
+                    Vec2::new(1000.0, 1000.0)
+                } else {
+                    Vec2::ZERO
+                };
+                step(app, 1);
+            }
+        };
+        // the window is one second
+        flip(&mut app, 90);
+        let churn_breaker = app.world().resource::<ChurnBreaker>();
+        assert!(churn_breaker.is_frozen(client_id));
+        assert!(!churn_breaker.is_frozen(calm_client_id));
+
+        // while frozen, the relevance doesn't change anymore
+        let relevant = app.world().resource::<RelevantCircles>().0[&client_id].clone();
+        flip(&mut app, 10);
+        assert_eq!(app.world().resource::<RelevantCircles>().0[&client_id], relevant);
+    }
+
+    #[test]
+    fn clients_rebuild_the_compact_grid_and_receive_the_other_circles() {
+        let mut stepper = Stepper::with(&[1], |app| {
+            app.insert_resource(CompactCircleGrid(true));
+        });
+        let client_id = ClientId::Netcode(1);
+        // the grid was already sent during the connection, before the client app could handle it
+        stepper
+            .client_app(client_id)
+            .add_systems(Update, crate::client::receive_circle_grid);
+        let grid = circle_grid(stepper.server_app.world().resource::<InterestConfig>());
+        stepper.server_app.world_mut().resource_mut::<Outbox>().send(
+            grid.clone(),
+            NetworkTarget::Single(client_id),
+            MessagePriority::High,
+            "send circle grid",
+        );
+        let dynamic_position = Vec2::new(30.0, 30.0);
+        spawn_circle(&mut stepper.server_app, dynamic_position);
+        stepper.frame_steps(20);
+
+        let client_app = stepper.client_app(client_id);
+        let mut local_circles = client_app
+            .world_mut()
+            .query_filtered::<&Position, With<crate::client::LocalCircle>>();
+        let mut local_positions: Vec<Vec2> =
+            local_circles.iter(client_app.world()).map(|position| position.0).collect();
+        let mut expected: Vec<Vec2> = grid.positions().collect();
+        let by_coordinates = |a: &Vec2, b: &Vec2| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y));
+        local_positions.sort_by(by_coordinates);
+        expected.sort_by(by_coordinates);
+        assert_eq!(local_positions, expected);
+        // only the dynamic circle is replicated
+        let mut replicated_circles = client_app
+            .world_mut()
+            .query_filtered::<&Position, (With<CircleMarker>, With<Confirmed>)>();
+        let replicated: Vec<Vec2> = replicated_circles
+            .iter(client_app.world())
+            .map(|position| position.0)
+            .collect();
+        assert_eq!(replicated, vec![dynamic_position]);
+    }
+
+    #[test]
+    fn disconnected_players_disappear_from_the_remaining_clients() {
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let mut stepper = Stepper::new(&[1, 2]);
+        stepper.frame_steps(10);
+        assert_eq!(players_seen_by(&mut stepper, a), vec![a, b]);
+        let player = player_of(&stepper.server_app, b).unwrap();
+        let text = stepper.server_app.world().resource::<Global>().player_to_text_entity[&player];
+
+        stepper
+            .client_app(b)
+            .world_mut()
+            .run_system_once(|mut commands: Commands| commands.disconnect_client());
+        stepper.frame_steps(10);
+        assert_eq!(players_seen_by(&mut stepper, a), vec![a]);
+        let server = stepper.server_app.world();
+        assert!(server.get_entity(player).is_none());
+        assert!(server.get_entity(text).is_none());
+        let global = server.resource::<Global>();
+        assert!(!global.client_id_to_room_id.contains_key(&b));
+        assert!(!global.player_to_text_entity.contains_key(&player));
+        let room = server.resource::<RoomManager>().get_room(RoomId(0)).unwrap();
+        assert!(!room.clients.contains(&b));
+        assert!(!room.entities.contains(&player));
+    }
+
+    #[test]
+    fn crossing_a_room_boundary_back_and_forth_keeps_the_replication() {
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let mut stepper = Stepper::new(&[1, 2]);
+        teleport(&mut stepper, a, Vec2::new(100.0, 100.0));
+        for crossing in 0..4 {
+            teleport(&mut stepper, b, Vec2::new(300.0, 100.0));
+            stepper.frame_steps(20);
+            assert_eq!(players_seen_by(&mut stepper, a), vec![a], "crossing {}", crossing);
+            assert_eq!(players_seen_by(&mut stepper, b), vec![b], "crossing {}", crossing);
+
+            teleport(&mut stepper, b, Vec2::new(120.0, 100.0));
+            stepper.frame_steps(20);
+            assert_eq!(players_seen_by(&mut stepper, a), vec![a, b], "crossing {}", crossing);
+            assert_eq!(players_seen_by(&mut stepper, b), vec![a, b], "crossing {}", crossing);
+            // the updates of the player that came back keep flowing
+            stepper.assert_replicated::<Position>(a, b);
+        }
+    }
+
+    #[test]
+    fn the_configured_interest_radius_is_used_for_the_relevance() {
+        for (interest_radius, relevant) in [(None, false), (Some(300.0), true)] {
+            let mut app = server_app_with(|app| {
+                if let Some(interest_radius) = interest_radius {
+                    app.insert_resource(InterestConfig {
+                        interest_radius,
+                        ..default()
+                    });
+                }
+            });
+            let client_id = ClientId::Netcode(1);
+            connect(&mut app, client_id).unwrap();
+            // beyond the default radius, even expanded for the newly spawned circles
+            let circle = spawn_circle(&mut app, Vec2::new(0.0, 250.0));
+            step(&mut app, 3);
+            assert_eq!(is_relevant(&app, client_id, circle), relevant);
+        }
+    }
+
+    #[test]
+    fn circles_only_lose_relevance_beyond_the_outer_threshold() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        // out of the expanded radius of the newly spawned circles
+        let circle_position = Vec2::new(190.0, 190.0);
+        let circle = spawn_circle(&mut app, circle_position);
+        // wait until the circle is no longer newly spawned
+        step(&mut app, 40);
+        assert!(!is_relevant(&app, client_id, circle));
+        let mut move_to = |distance: f32| {
+            app.world_mut().get_mut::<Position>(player).unwrap().0 =
+                circle_position - Vec2::new(distance, 0.0);
+            step(&mut app, 2);
+            is_relevant(&app, client_id, circle)
+        };
+        assert!(move_to(140.0));
+        // jitter around the inner threshold of 150, but within the outer one of 172.5
+        for distance in [155.0, 145.0, 160.0, 149.0, 170.0] {
+            assert!(move_to(distance), "lost relevance at {}", distance);
+        }
+        assert!(!move_to(175.0));
+        // and it is only gained again within the inner threshold
+        assert!(!move_to(160.0));
+        assert!(move_to(149.0));
+    }
+
+    #[test]
+    fn nothing_changes_while_the_simulation_is_paused() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let text = app.world().resource::<Global>().player_to_text_entity[&player];
+        // right at the edge of the spawn room, so that moving right would change the room
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(195.0, 10.0);
+        step(&mut app, 2);
+        app.insert_resource(SimulationPaused(true));
+        let frozen = |app: &App| {
+            (
+                app.world().get::<Position>(player).unwrap().0,
+                app.world().get::<CurrentRoom>(player).unwrap().0,
+                app.world().get::<TimerComponent>(text).unwrap().0.elapsed(),
+            )
+        };
+        let before = frozen(&app);
+        for _ in 0..20 {
+            press(&mut app, client_id, Inputs::Right);
+            step(&mut app, 1);
+        }
+        assert_eq!(frozen(&app), before);
+        // the clients stay connected, and new ones can still join
+        assert_eq!(player_of(&app, client_id), Some(player));
+        assert!(connect(&mut app, ClientId::Netcode(2)).is_some());
+
+        app.insert_resource(SimulationPaused(false));
+        for _ in 0..20 {
+            press(&mut app, client_id, Inputs::Right);
+            step(&mut app, 1);
+        }
+        let (position, room, _) = frozen(&app);
+        assert!(position.x > 200.0);
+        assert_ne!(room, before.1);
+    }
+
+    #[test]
+    fn pressing_spawn_spawns_a_replicated_circle_at_the_player() {
+        let client_id = ClientId::Netcode(1);
+        let mut stepper = Stepper::new(&[1]);
+        let position = Vec2::new(50.0, 50.0);
+        teleport(&mut stepper, client_id, position);
+        stepper.frame_steps(2);
+        press(&mut stepper.server_app, client_id, Inputs::Spawn);
+        stepper.server_app.world_mut().run_system_once(handle_spawn_input);
+        stepper.frame_steps(20);
+
+        let spawned = &stepper.server_app.world().resource::<SpawnedCircles>().0[&client_id];
+        assert_eq!(spawned.len(), 1);
+        assert_eq!(spawned[0].room, RoomId(0));
+        let world = stepper.client_app(client_id).world_mut();
+        let mut circles =
+            world.query_filtered::<&Position, (With<CircleMarker>, With<Confirmed>)>();
+        assert!(circles.iter(world).any(|circle| circle.0 == position));
+    }
+
+    #[test]
+    fn pressing_delete_despawns_the_last_spawned_circles() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let tap = |app: &mut App, input: Inputs| {
+            press(app, client_id, input);
+            app.world_mut().run_system_once(handle_spawn_input);
+            app.world_mut().run_system_once(handle_delete_input);
+            release(app, client_id, input);
+            step(app, 2);
+        };
+        let mut spawned = Vec::new();
+        for position in [Vec2::new(20.0, 20.0), Vec2::new(60.0, 20.0)] {
+            app.world_mut().get_mut::<Position>(player).unwrap().0 = position;
+            tap(&mut app, Inputs::Spawn);
+            let circles = &app.world().resource::<SpawnedCircles>().0[&client_id];
+            spawned.push(circles.last().unwrap().entity);
+        }
+        for circle in spawned.iter() {
+            assert!(is_relevant(&app, client_id, *circle));
+        }
+
+        for (deleted, remaining) in [(spawned[1], Some(spawned[0])), (spawned[0], None)] {
+            tap(&mut app, Inputs::Delete);
+            assert!(app.world().get_entity(deleted).is_none());
+            assert!(!is_relevant(&app, client_id, deleted));
+            let room = app.world().resource::<RoomManager>().get_room(RoomId(0)).unwrap();
+            assert!(!room.entities.contains(&deleted));
+            if let Some(remaining) = remaining {
+                assert!(app.world().get_entity(remaining).is_some());
+            }
+        }
+        // nothing left to delete
+        tap(&mut app, Inputs::Delete);
+        assert!(app.world().resource::<SpawnedCircles>().0[&client_id].is_empty());
+    }
+
+    #[test]
+    fn the_circles_of_a_disconnected_client_are_despawned() {
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let mut stepper = Stepper::new(&[1, 2]);
+        let position = Vec2::new(50.0, 50.0);
+        teleport(&mut stepper, b, position);
+        stepper.frame_steps(2);
+        press(&mut stepper.server_app, b, Inputs::Spawn);
+        stepper.server_app.world_mut().run_system_once(handle_spawn_input);
+        release(&mut stepper.server_app, b, Inputs::Spawn);
+        stepper.frame_steps(20);
+        let circle = stepper.server_app.world().resource::<SpawnedCircles>().0[&b][0];
+        let seen_by_a = |stepper: &mut Stepper| {
+            let world = stepper.client_app(a).world_mut();
+            let mut circles =
+                world.query_filtered::<&Position, (With<CircleMarker>, With<Confirmed>)>();
+            let seen = circles.iter(world).any(|circle| circle.0 == position);
+            seen
+        };
+        assert!(seen_by_a(&mut stepper));
+
+        stepper
+            .client_app(b)
+            .world_mut()
+            .run_system_once(|mut commands: Commands| commands.disconnect_client());
+        stepper.frame_steps(20);
+        let server = stepper.server_app.world();
+        assert!(server.get_entity(circle.entity).is_none());
+        assert!(!server.resource::<SpawnedCircles>().0.contains_key(&b));
+        let room = server.resource::<RoomManager>().get_room(circle.room).unwrap();
+        assert!(!room.entities.contains(&circle.entity));
+        assert!(!seen_by_a(&mut stepper));
+    }
+
+    #[test]
+    fn only_destroyed_circles_are_animated_on_the_clients() {
+        use crate::client::{receive_entity_destroyed, Destruction, DestructionSettings};
+
+        let client_id = ClientId::Netcode(1);
+        let mut stepper = Stepper::new(&[1]);
+        let client_app = stepper.client_app(client_id);
+        client_app.init_resource::<DestructionSettings>();
+        client_app.add_systems(Update, receive_entity_destroyed);
+        let position = Vec2::new(50.0, 50.0);
+        teleport(&mut stepper, client_id, position);
+        stepper.frame_steps(2);
+        press(&mut stepper.server_app, client_id, Inputs::Spawn);
+        stepper.server_app.world_mut().run_system_once(handle_spawn_input);
+        release(&mut stepper.server_app, client_id, Inputs::Spawn);
+        stepper.frame_steps(20);
+        let client_state = |stepper: &mut Stepper| {
+            let world = stepper.client_app(client_id).world_mut();
+            let mut circles =
+                world.query_filtered::<&Position, (With<CircleMarker>, With<Confirmed>)>();
+            let has_circle = circles.iter(world).any(|circle| circle.0 == position);
+            let mut destructions = world.query::<&Destruction>();
+            let destructions: Vec<Vec2> =
+                destructions.iter(world).map(|destruction| destruction.position).collect();
+            (has_circle, destructions)
+        };
+        assert_eq!(client_state(&mut stepper), (true, vec![]));
+
+        // culled: the player leaves the room of the circle
+        teleport(&mut stepper, client_id, Vec2::new(1050.0, 50.0));
+        stepper.frame_steps(20);
+        assert_eq!(client_state(&mut stepper), (false, vec![]));
+
+        // destroyed: the player comes back and deletes it
+        teleport(&mut stepper, client_id, position);
+        stepper.frame_steps(20);
+        assert_eq!(client_state(&mut stepper), (true, vec![]));
+        press(&mut stepper.server_app, client_id, Inputs::Delete);
+        stepper.server_app.world_mut().run_system_once(handle_delete_input);
+        release(&mut stepper.server_app, client_id, Inputs::Delete);
+        stepper.frame_steps(10);
+        assert_eq!(client_state(&mut stepper), (false, vec![position]));
+    }
+
+    #[test]
+    fn players_pushed_past_the_edges_stay_next_to_the_edge_circles() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let mut circles = app
+            .world_mut()
+            .query_filtered::<(Entity, &Position), With<CircleMarker>>();
+        // the grid spans [-2000, 1800] on both axes
+        for (pushed, edge) in [
+            (Vec2::new(-5000.0, 30.0), Vec2::new(-2000.0, 30.0)),
+            (Vec2::new(30.0, 9000.0), Vec2::new(30.0, 1800.0)),
+            (Vec2::new(5000.0, -5000.0), Vec2::new(1800.0, -2000.0)),
+        ] {
+            app.world_mut().get_mut::<Position>(player).unwrap().0 = pushed;
+            step(&mut app, 2);
+            assert_eq!(app.world().get::<Position>(player).unwrap().0, edge);
+            let nearest = circles
+                .iter(app.world())
+                .min_by(|a, b| a.1 .0.distance(edge).total_cmp(&b.1 .0.distance(edge)))
+                .unwrap();
+            assert!(is_relevant(&app, client_id, nearest.0), "nothing is relevant at {}", edge);
+        }
+    }
+
+    #[test]
+    fn zones_are_coarser_than_rooms() {
+        let mut app = server_app_with(|app| {
+            app.insert_resource(InterestConfig {
+                zone_size: Some(3),
+                ..default()
+            });
+        });
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        connect(&mut app, a).unwrap();
+        let player = connect(&mut app, b).unwrap();
+        let zone = zone_for_room(RoomId(0), 3);
+        let shares_with_a = |app: &App| {
+            let room_manager = app.world().resource::<RoomManager>();
+            let shares = |room_id: RoomId| {
+                room_manager.get_room(room_id).map_or(false, |room| {
+                    room.clients.contains(&a) && room.entities.contains(&player)
+                })
+            };
+            (shares(RoomId(0)), shares(zone))
+        };
+        step(&mut app, 2);
+        assert_eq!(shares_with_a(&app), (true, true));
+
+        // another room of the same zone
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(450.0, 50.0);
+        step(&mut app, 2);
+        assert_eq!(shares_with_a(&app), (false, true));
+
+        // the next zone
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(650.0, 50.0);
+        step(&mut app, 2);
+        assert_eq!(shares_with_a(&app), (false, false));
+
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(50.0, 50.0);
+        step(&mut app, 2);
+        assert_eq!(shares_with_a(&app), (true, true));
+    }
+
+    #[test]
+    fn players_of_the_same_team_have_the_same_color() {
+        let mut app = server_app();
+        let players: Vec<Entity> = (1..=4)
+            .map(|id| connect(&mut app, ClientId::Netcode(id)).unwrap())
+            .collect();
+        let team_and_color = |player: Entity| {
+            let world = app.world();
+            (*world.get::<Team>(player).unwrap(), world.get::<PlayerColor>(player).unwrap().clone())
+        };
+        let (team_1, color_1) = team_and_color(players[0]);
+        let (team_2, color_2) = team_and_color(players[1]);
+        assert_ne!(team_1, team_2);
+        assert_ne!(color_1, color_2);
+        // the teams alternate with the connection order
+        assert_eq!(team_and_color(players[2]), (team_1, color_1));
+        assert_eq!(team_and_color(players[3]), (team_2, color_2));
+    }
+
+    #[test]
+    fn chat_messages_are_relayed_to_the_room_of_the_sender_only() {
+        #[derive(Resource, Default)]
+        struct ReceivedChats(Vec<ChatMessage>);
+
+        let (a, b, c) = (ClientId::Netcode(1), ClientId::Netcode(2), ClientId::Netcode(3));
+        let mut stepper = Stepper::new(&[1, 2, 3]);
+        // c is in another room
+        teleport(&mut stepper, c, Vec2::new(650.0, 50.0));
+        for client_id in [a, b, c] {
+            let client_app = stepper.client_app(client_id);
+            client_app.init_resource::<ReceivedChats>();
+            client_app.add_systems(
+                Update,
+                |mut received: ResMut<ReceivedChats>,
+                 mut messages: EventReader<client::MessageEvent<ChatMessage>>| {
+                    received
+                        .0
+                        .extend(messages.read().map(|message| message.message().clone()));
+                },
+            );
+        }
+        stepper.frame_steps(5);
+
+        let message = ChatMessage {
+            sender: a,
+            text: "hello".to_string(),
+        };
+        stepper
+            .client_app(a)
+            .world_mut()
+            .resource_mut::<client::ConnectionManager>()
+            .send_message::<Channel1, _>(&message)
+            .unwrap();
+        stepper.frame_steps(10);
+        let mut received = |client_id: ClientId| {
+            let client_app = stepper.client_app(client_id);
+            std::mem::take(&mut client_app.world_mut().resource_mut::<ReceivedChats>().0)
+        };
+        assert_eq!(received(b), vec![message]);
+        // the sender doesn't get its own message back
+        assert!(received(a).is_empty());
+        assert!(received(c).is_empty());
+    }
+
+    #[test]
+    fn players_crossing_boundaries_in_the_same_tick_end_up_in_their_own_rooms() {
+        let mut app = server_app();
+        let client_ids = [1, 2, 3].map(ClientId::Netcode);
+        let players = client_ids.map(|client_id| connect(&mut app, client_id).unwrap());
+        step(&mut app, 2);
+        let mut circles = app
+            .world_mut()
+            .query_filtered::<(Entity, &Position), With<CircleMarker>>();
+        let mut check = |app: &mut App, positions: [Vec2; 3]| {
+            for (player, position) in players.iter().zip(positions) {
+                app.world_mut().get_mut::<Position>(*player).unwrap().0 = position;
+            }
+            step(app, 2);
+            for (client_id, position) in client_ids.iter().zip(positions) {
+                let room = shared::room_id_for_position(position, shared::ROOM_SIZE);
+                assert_eq!(rooms_of(app, *client_id), (vec![room], vec![room]));
+                let expected: HashSet<Entity> = circles
+                    .iter(app.world())
+                    .filter(|(_, circle)| circle.0.distance(position) < 150.0)
+                    .map(|(circle, _)| circle)
+                    .collect();
+                assert_eq!(app.world().resource::<RelevantCircles>().0[client_id], expected);
+            }
+        };
+        // each player crosses a different boundary
+        check(
+            &mut app,
+            [Vec2::new(210.0, 10.0), Vec2::new(-10.0, 390.0), Vec2::new(-190.0, -190.0)],
+        );
+        // then they swap rooms, entering the rooms that the others are leaving
+        check(
+            &mut app,
+            [Vec2::new(-10.0, 390.0), Vec2::new(-190.0, -190.0), Vec2::new(210.0, 10.0)],
+        );
+    }
+
+    #[test]
+    fn the_same_input_moves_players_slower_in_slow_rooms() {
+        let (normal_room, slow_room) = (RoomId(0), shared::room_id_for_cell(IVec2::new(1, 0)));
+        let mut world = World::new();
+        let mut room_properties = RoomProperties::default();
+        room_properties.0.insert(
+            slow_room,
+            RoomProperty {
+                speed_multiplier: 0.5,
+            },
+        );
+        world.insert_resource(room_properties);
+        let mut input = ActionState::<Inputs>::default();
+        input.press(&Inputs::Right);
+        let players = [normal_room, slow_room].map(|room| {
+            world
+                .spawn((
+                    Position(Vec2::ZERO),
+                    Velocity(Vec2::ZERO),
+                    CurrentRoom(room),
+                    input.clone(),
+                ))
+                .id()
+        });
+        for _ in 0..50 {
+            world.run_system_once(movement);
+        }
+        let [normal, slow] = players.map(|player| world.get::<Position>(player).unwrap().0);
+        assert_eq!(normal.y, 0.0);
+        assert_eq!(slow.y, 0.0);
+        assert!((slow.x - normal.x / 2.0).abs() < 1e-3, "{} vs {}", slow.x, normal.x);
+    }
+
+    #[test]
+    fn a_room_crossing_emits_one_leave_and_one_enter() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        step(&mut app, 2);
+        let mut enter_reader = app.world().resource::<Events<RoomEnter>>().get_reader_current();
+        let mut leave_reader = app.world().resource::<Events<RoomLeave>>().get_reader_current();
+        let (mut enters, mut leaves) = (Vec::new(), Vec::new());
+
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(250.0, 50.0);
+        for _ in 0..10 {
+            step(&mut app, 1);
+            let world = app.world();
+            enters.extend(enter_reader.read(world.resource::<Events<RoomEnter>>()).copied());
+            leaves.extend(leave_reader.read(world.resource::<Events<RoomLeave>>()).copied());
+        }
+        let new_room = shared::room_id_for_cell(IVec2::new(1, 0));
+        assert_eq!(
+            leaves,
+            vec![RoomLeave {
+                client: client_id,
+                room: RoomId(0),
+            }]
+        );
+        assert_eq!(
+            enters,
+            vec![RoomEnter {
+                client: client_id,
+                room: new_room,
+            }]
+        );
+    }
+
+    #[test]
+    fn rooms_and_relevance_follow_a_moving_player() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        let config = InterestConfig::default();
+        // wait until the circles are no longer newly spawned
+        step(&mut app, 40);
+        let mut circles = app
+            .world_mut()
+            .query_filtered::<(Entity, &Position), With<CircleMarker>>();
+        let mut visited_rooms = HashSet::new();
+        for _ in 0..60 {
+            press(&mut app, client_id, Inputs::Right);
+            press(&mut app, client_id, Inputs::Up);
+            step(&mut app, 1);
+            let world = app.world();
+            let position = world.get::<Position>(player).unwrap().0;
+            let room = config.room_id_for_position(position);
+            visited_rooms.insert(room);
+            assert_eq!(world.get::<CurrentRoom>(player).unwrap().0, room);
+            assert_eq!(rooms_of(&app, client_id), (vec![room], vec![room]));
+            // the relevance has the hysteresis of `lose_relevance_factor`
+            let relevant = &world.resource::<RelevantCircles>().0[&client_id];
+            for (circle, circle_position) in circles.iter(world) {
+                let distance = circle_position.0.distance(position);
+                if distance < config.interest_radius {
+                    assert!(relevant.contains(&circle), "{:?} is not relevant", circle);
+                }
+                if distance > config.interest_radius * config.lose_relevance_factor {
+                    assert!(!relevant.contains(&circle), "{:?} is still relevant", circle);
+                }
+            }
+        }
+        assert!(visited_rooms.len() >= 3);
+    }
+
+    #[test]
+    fn last_position_is_only_changed_when_the_player_moves() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        step(&mut app, 3);
+        let last_changed = |app: &App| {
+            app.world()
+                .entity(player)
+                .get_ref::<LastPosition>()
+                .unwrap()
+                .last_changed()
+        };
+        let stationary = last_changed(&app);
+        step(&mut app, 10);
+        assert_eq!(last_changed(&app), stationary);
+
+        press(&mut app, client_id, Inputs::Right);
+        step(&mut app, 1);
+        assert_ne!(last_changed(&app), stationary);
+        assert_eq!(
+            app.world().get::<LastPosition>(player).unwrap().0,
+            app.world().get::<Position>(player).unwrap().0
+        );
+    }
+
+    #[test]
+    fn overlapping_players_damage_each_other() {
+        let mut app = server_app();
+        let clients = [1, 2, 3].map(ClientId::Netcode);
+        let players = clients.map(|client_id| connect(&mut app, client_id).unwrap());
+        for player in players {
+            app.world_mut()
+                .entity_mut(player)
+                .remove::<(Shielded, SpawnProtection)>();
+        }
+        // the first two players stay on top of each other at the spawn position
+        app.world_mut().get_mut::<Position>(players[2]).unwrap().0 = Vec2::new(500.0, 500.0);
+        let health = |app: &App, player: Entity| *app.world().get::<Health>(player).unwrap();
+        let max = health(&app, players[0]).max;
+        step(&mut app, 10);
+        let (a, b) = (health(&app, players[0]), health(&app, players[1]));
+        assert!(a.current < max && a.current > 0.0, "{:?}", a);
+        assert_eq!(a, b);
+        assert_eq!(health(&app, players[2]).current, max);
+    }
+
+    #[test]
+    fn dead_players_respawn_at_the_origin_in_room_0() {
+        let mut app = server_app();
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(250.0, 50.0);
+        step(&mut app, 2);
+        assert_ne!(rooms_of(&app, client_id).0, vec![RoomId(0)]);
+
+        app.world_mut().get_mut::<Health>(player).unwrap().current = 0.0;
+        step(&mut app, 2);
+        // the client keeps controlling the same entity
+        assert_eq!(player_of(&app, client_id), Some(player));
+        assert_eq!(app.world().get::<PlayerId>(player).unwrap().0, client_id);
+        let health = app.world().get::<Health>(player).unwrap();
+        assert_eq!(health.current, health.max);
+        assert_eq!(app.world().get::<Position>(player).unwrap().0, Vec2::ZERO);
+        assert_eq!(rooms_of(&app, client_id), (vec![RoomId(0)], vec![RoomId(0)]));
+    }
+
+    #[test]
+    fn circles_spawned_just_outside_the_radius_are_relevant_to_an_approaching_client() {
+        for (spawn_expansion_factor, relevant) in [(1.0, false), (1.5, true)] {
+            let mut app = server_app_with(|app| {
+                app.insert_resource(InterestConfig {
+                    spawn_expansion_factor,
+                    ..default()
+                });
+            });
+            let client_id = ClientId::Netcode(1);
+            let player = connect(&mut app, client_id).unwrap();
+            press(&mut app, client_id, Inputs::Up);
+            step(&mut app, 3);
+            // 200 ahead of the player, out of the radius of 150 but within the expanded one
+            let position = app.world().get::<Position>(player).unwrap().0;
+            let circle = spawn_circle(&mut app, position + Vec2::new(0.0, 200.0));
+            press(&mut app, client_id, Inputs::Up);
+            step(&mut app, 1);
+            assert_eq!(is_relevant(&app, client_id, circle), relevant);
+        }
+    }
+
+    #[test]
+    fn the_leaderboard_is_sorted_and_truncated_to_the_top_scores() {
+        let mut stepper = Stepper::with(&[1, 2, 3], |app| {
+            app.insert_resource(LeaderboardConfig {
+                top_n: 2,
+                timer: Timer::from_seconds(0.1, TimerMode::Repeating),
+            });
+        });
+        let clients = [1, 2, 3].map(ClientId::Netcode);
+        for (client_id, score) in clients.into_iter().zip([5, 9, 7]) {
+            let player = player_of(&stepper.server_app, client_id).unwrap();
+            stepper.server_app.world_mut().get_mut::<Score>(player).unwrap().0 = score;
+        }
+        for client_id in clients {
+            let client_app = stepper.client_app(client_id);
+            client_app.init_resource::<crate::client::LatestLeaderboard>();
+            client_app.add_systems(Update, crate::client::receive_leaderboard);
+        }
+        stepper.frame_steps(20);
+        for client_id in clients {
+            let leaderboard = stepper
+                .client_app(client_id)
+                .world()
+                .resource::<crate::client::LatestLeaderboard>();
+            assert_eq!(leaderboard.0, vec![(clients[1], 9), (clients[2], 7)]);
+        }
+    }
+
+    #[test]
+    fn the_relevance_metrics_count_the_transitions_of_a_moving_player() {
+        let mut app = server_app_with(|app| {
+            // keep the static grid out of the relevance
+            app.insert_resource(CompactCircleGrid(true));
+            let mut metrics = RelevanceMetrics::default();
+            metrics.timer = Timer::from_seconds(1000.0, TimerMode::Repeating);
+            app.insert_resource(metrics);
+        });
+        // roll the counters over on the next frame, and return the counts since the last roll over
+        let roll_over = |app: &mut App| {
+            let mut metrics = app.world_mut().resource_mut::<RelevanceMetrics>();
+            let duration = metrics.timer.duration();
+            metrics.timer.set_elapsed(duration - Duration::from_millis(1));
+            step(app, 1);
+            app.world().resource::<RelevanceMetrics>().last_second
+        };
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        spawn_circle(&mut app, Vec2::new(400.0, 0.0));
+        spawn_circle(&mut app, Vec2::new(800.0, 0.0));
+        step(&mut app, 40);
+        roll_over(&mut app);
+
+        // onto the first circle, onto the second one, then away from both, each in a new room
+        for x in [400.0, 800.0, 1200.0] {
+            app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(x, 0.0);
+            step(&mut app, 2);
+        }
+        let counts = roll_over(&mut app);
+        assert_eq!((counts.gains, counts.losses, counts.room_transitions), (2, 2, 3));
+    }
+
+    #[test]
+    fn room_and_relevance_disagreements_are_resolved_by_the_precedence() {
+        // in the room of the player but out of its radius, and relevant but in another room
+        let (in_room, relevant) = (Vec2::new(190.0, 190.0), Vec2::new(-50.0, 0.0));
+        for (precedence, expected) in [
+            (VisibilityPrecedence::Union, vec![in_room, relevant]),
+            (VisibilityPrecedence::Intersection, vec![]),
+            (VisibilityPrecedence::Relevance, vec![relevant]),
+            (VisibilityPrecedence::Room, vec![in_room]),
+        ] {
+            let mut stepper = Stepper::with(&[1], |app| {
+                app.insert_resource(CompactCircleGrid(true));
+                app.insert_resource(precedence);
+            });
+            let client_id = ClientId::Netcode(1);
+            spawn_circle(&mut stepper.server_app, in_room);
+            spawn_circle(&mut stepper.server_app, relevant);
+            // the relevance is only evaluated when the player moves
+            teleport(&mut stepper, client_id, Vec2::ONE);
+            stepper.frame_steps(20);
+
+            let client_app = stepper.client_app(client_id);
+            let mut circles = client_app
+                .world_mut()
+                .query_filtered::<&Position, (With<CircleMarker>, With<Confirmed>)>();
+            let mut seen: Vec<Vec2> = circles
+                .iter(client_app.world())
+                .map(|position| position.0)
+                .collect();
+            seen.sort_by(|a, b| b.x.total_cmp(&a.x));
+            assert_eq!(seen, expected, "with {:?}", precedence);
+        }
+    }
+
+    #[test]
+    fn only_the_pairs_whose_room_or_relevance_changed_are_reconciled() {
+        let mut app = server_app();
+        let (a, b) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        connect(&mut app, a).unwrap();
+        connect(&mut app, b).unwrap();
+        for x in [300.0, 500.0, 700.0] {
+            spawn_circle(&mut app, Vec2::new(x, 100.0));
+        }
+        step(&mut app, 5);
+        // the pairs reconciled over the next frames, which may not all send
+        let num_reconciled = |app: &mut App| {
+            (0..10)
+                .map(|_| {
+                    step(app, 1);
+                    app.world().resource::<ReconciledVisibility>().num_reconciled
+                })
+                .sum::<usize>()
+        };
+        assert_eq!(num_reconciled(&mut app), 0);
+
+        // a walks to another room, away from the circles: only its pairs are reconciled
+        let player = player_of(&app, a).unwrap();
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(-500.0, -500.0);
+        assert_eq!(num_reconciled(&mut app), 3);
+        assert_eq!(num_reconciled(&mut app), 0);
+    }
+
+    #[test]
+    fn the_parallel_evaluation_gives_the_relevance_of_a_serial_scan() {
+        let mut app = server_app();
+        let clients: Vec<ClientId> = (1..=12).map(ClientId::Netcode).collect();
+        for client_id in clients.iter() {
+            connect(&mut app, *client_id).unwrap();
+        }
+        // between 4 circles of the grid, or next to a single one. The players are far from the
+        // spawn position, so that none of its circles stays relevant through the hysteresis
+        let positions: Vec<Vec2> = (0..clients.len())
+            .map(|i| {
+                let circle =
+                    Vec2::new((i % 4) as f32 * 400.0 - 1200.0, (i / 4) as f32 * 600.0 + 400.0);
+                if i % 2 == 0 {
+                    circle + Vec2::splat(100.0)
+                } else {
+                    circle + Vec2::new(10.0, -10.0)
+                }
+            })
+            .collect();
+        for (client_id, position) in clients.iter().zip(positions.iter()) {
+            let player = player_of(&app, *client_id).unwrap();
+            app.world_mut().get_mut::<Position>(player).unwrap().0 = *position;
+        }
+        step(&mut app, 2);
+
+        let radius = app.world().resource::<InterestConfig>().interest_radius;
+        let mut circles = app
+            .world_mut()
+            .query_filtered::<(Entity, &Position, &ReplicationCategory), With<CircleMarker>>();
+        let circles: Vec<(Entity, Vec2)> = circles
+            .iter(app.world())
+            .filter(|(_, _, category)| category.is_distance_culled())
+            .map(|(entity, position, _)| (entity, position.0))
+            .collect();
+        let relevant_circles = &app.world().resource::<RelevantCircles>().0;
+        for (client_id, position) in clients.iter().zip(positions.iter()) {
+            let expected: HashSet<Entity> = circles
+                .iter()
+                .filter(|(_, circle)| circle.distance(*position) < radius)
+                .map(|(entity, _)| *entity)
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(relevant_circles[client_id], expected, "relevance of {}", client_id);
+        }
+    }
+
+    /// Run `f` with an evaluation of the circles that reads the current state of the server
+    fn with_circle_evaluation<R>(app: &mut App, f: impl FnOnce(&CircleEvaluation) -> R) -> R {
+        let mut state: SystemState<(
+            InterestSettings,
+            Res<ClientInterestRadius>,
+            Res<RelevantCircles>,
+            Res<CircleSpatialIndex>,
+            Query<CircleData, With<ReplicationTarget>>,
+        )> = SystemState::new(app.world_mut());
+        let (settings, interest_radius, relevant_circles, circle_index, circles) =
+            state.get(app.world());
+        f(&CircleEvaluation {
+            config: &settings.config,
+            circle_strategy: &settings.circle_strategy,
+            max_relevant_circles: settings.max_relevant_circles.0,
+            interest_radius: &interest_radius,
+            relevant_circles: &relevant_circles,
+            circle_index: &circle_index,
+            circles: &circles,
+        })
+    }
+
+    #[test]
+    fn the_parallel_and_serial_evaluations_give_the_same_updates() {
+        let mut app = server_app();
+        let clients: Vec<ClientId> = (1..=12).map(ClientId::Netcode).collect();
+        for client_id in clients.iter() {
+            connect(&mut app, *client_id).unwrap();
+        }
+        step(&mut app, 2);
+        // the players leave the circles around the spawn position for other parts of the grid
+        let players: Vec<(ClientId, Vec2)> = clients
+            .iter()
+            .enumerate()
+            .map(|(i, client_id)| {
+                let position =
+                    Vec2::new((i % 4) as f32 * 400.0 - 1200.0, (i / 4) as f32 * 600.0 + 400.0);
+                (*client_id, position + Vec2::splat(50.0))
+            })
+            .collect();
+
+        let (serial, parallel) = with_circle_evaluation(&mut app, |evaluation| {
+            (evaluation.evaluate_serial(&players), evaluation.evaluate_parallel(&players))
+        });
+
+        let gains_and_losses = |updates: Vec<(ClientId, VecDeque<(Entity, Relevance)>)>| {
+            updates
+                .into_iter()
+                .map(|(client_id, updates)| {
+                    let (gained, lost): (Vec<_>, Vec<_>) = updates
+                        .into_iter()
+                        .partition(|(_, relevance)| *relevance == Relevance::Relevant);
+                    let gained: HashSet<Entity> = gained.into_iter().map(|(e, _)| e).collect();
+                    let lost: HashSet<Entity> = lost.into_iter().map(|(e, _)| e).collect();
+                    (client_id, (gained, lost))
+                })
+                .collect::<HashMap<ClientId, (HashSet<Entity>, HashSet<Entity>)>>()
+        };
+        assert_eq!(serial.len(), clients.len());
+        assert_eq!(parallel.len(), clients.len());
+        let serial = gains_and_losses(serial);
+        assert!(serial.values().all(|(gained, lost)| !gained.is_empty() && !lost.is_empty()));
+        assert_eq!(serial, gains_and_losses(parallel));
+    }
+
+    /// A server with 200 players spread over the grid, and the positions they are evaluated at
+    #[cfg(feature = "bench")]
+    fn evaluation_bench_app() -> (App, Vec<(ClientId, Vec2)>) {
+        let mut app = server_app();
+        let half_extent = app.world().resource::<InterestConfig>().half_extent();
+        let mut rng = StdRng::seed_from_u64(0);
+        let players: Vec<(ClientId, Vec2)> = (1..=200)
+            .map(|client_id| {
+                let position = Vec2::new(
+                    rng.gen_range(-half_extent..half_extent),
+                    rng.gen_range(-half_extent..half_extent),
+                );
+                (ClientId::Netcode(client_id), position)
+            })
+            .collect();
+        for (client_id, _) in players.iter() {
+            connect(&mut app, *client_id).unwrap();
+        }
+        step(&mut app, 2);
+        (app, players)
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn bench_serial_evaluation(b: &mut test::Bencher) {
+        let (mut app, players) = evaluation_bench_app();
+        with_circle_evaluation(&mut app, |evaluation| {
+            b.iter(|| evaluation.evaluate_serial(&players))
+        });
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn bench_parallel_evaluation(b: &mut test::Bencher) {
+        let (mut app, players) = evaluation_bench_app();
+        with_circle_evaluation(&mut app, |evaluation| {
+            b.iter(|| evaluation.evaluate_parallel(&players))
+        });
+    }
+
+    #[test]
+    fn the_occupancy_counts_the_clients_and_entities_of_each_room() {
+        let mut app = server_app();
+        let clients = [1, 2, 3].map(ClientId::Netcode);
+        for client_id in clients {
+            connect(&mut app, client_id).unwrap();
+        }
+        let player = player_of(&app, clients[2]).unwrap();
+        app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec2::new(250.0, 50.0);
+        spawn_circle(&mut app, Vec2::new(300.0, 100.0));
+        step(&mut app, 2);
+
+        let occupancy = app.world().resource::<RoomOccupancy>();
+        let other_room = shared::room_id_for_cell(IVec2::new(1, 0));
+        // the players and their texts, plus the spawned circle
+        assert_eq!(occupancy.get(RoomId(0)), (2, 4));
+        assert_eq!(occupancy.get(other_room), (1, 3));
+        assert_eq!(occupancy.0.len(), 2);
     }
 }
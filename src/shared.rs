@@ -6,6 +6,7 @@ use leafwing_input_manager::action_state::ActionState;
 use std::ops::Deref;
 
 use lightyear::client::components::Confirmed;
+use lightyear::prelude::server::RoomId;
 use lightyear::prelude::*;
 
 use crate::protocol::*;
@@ -17,58 +18,296 @@ impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(ProtocolPlugin);
         if app.is_plugin_added::<RenderPlugin>() {
-            app.add_systems(Startup, init);
-            app.add_systems(Update, (draw_boxes, draw_circles));
+            app.add_plugins(SharedRenderPlugin);
         }
     }
 }
 
+/// The rendering systems, which don't depend on networking
+pub struct SharedRenderPlugin;
+
+impl Plugin for SharedRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, init);
+        app.add_systems(Update, (draw_boxes, draw_circles));
+    }
+}
+
 fn init(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
 }
 
-// This system defines how we update the player's positions when we receive an input
-pub(crate) fn shared_movement_behaviour(mut position: Mut<Position>, input: &ActionState<Inputs>) {
-    const MOVE_SPEED: f32 = 10.0;
+/// Distance travelled by a player in one fixed tick, once it reached full speed
+pub(crate) const MOVE_SPEED: f32 = 10.0;
+/// Fraction of its velocity that a player keeps from one fixed tick to the next
+pub(crate) const FRICTION: f32 = 0.8;
+/// Below this speed, a player stops completely
+const MIN_SPEED: f32 = 0.01;
+/// Health of a newly spawned player
+pub(crate) const PLAYER_MAX_HEALTH: f32 = 100.0;
+
+// This system defines how we update the player's positions when we receive an input.
+// The inputs accelerate the player, which then slows down from friction.
+// `speed_multiplier` scales the speed that the inputs push towards, e.g. in a slow room
+pub(crate) fn shared_movement_behaviour(
+    mut position: Mut<Position>,
+    mut velocity: Mut<Velocity>,
+    input: &ActionState<Inputs>,
+    speed_multiplier: f32,
+) {
+    velocity.set_if_neq(Velocity(next_velocity(velocity.0, input, speed_multiplier)));
+    // don't trigger change detection if the player doesn't move
+    if velocity.0 != Vec2::ZERO {
+        position.0 += velocity.0;
+    }
+}
+
+/// The velocity of a player after one fixed tick with the given inputs.
+/// While an input is held, the velocity converges towards `MOVE_SPEED * speed_multiplier` in its direction
+pub(crate) fn next_velocity(
+    velocity: Vec2,
+    input: &ActionState<Inputs>,
+    speed_multiplier: f32,
+) -> Vec2 {
+    let target = movement_delta(input) * speed_multiplier;
+    let velocity = velocity * FRICTION + target * (1.0 - FRICTION);
+    // otherwise the velocity would decay forever and keep marking the position as changed
+    if velocity.length() < MIN_SPEED {
+        Vec2::ZERO
+    } else {
+        velocity
+    }
+}
+
+/// The velocity that the given inputs push towards.
+/// The direction is normalized, so that moving diagonally is not faster than along an axis
+pub(crate) fn movement_delta(input: &ActionState<Inputs>) -> Vec2 {
+    let mut direction = Vec2::ZERO;
     if input.pressed(&Inputs::Up) {
-        position.y += MOVE_SPEED;
+        direction.y += 1.0;
     }
     if input.pressed(&Inputs::Down) {
-        position.y -= MOVE_SPEED;
+        direction.y -= 1.0;
     }
     if input.pressed(&Inputs::Left) {
-        position.x -= MOVE_SPEED;
+        direction.x -= 1.0;
     }
     if input.pressed(&Inputs::Right) {
-        position.x += MOVE_SPEED;
+        direction.x += 1.0;
+    }
+    // no input (or opposite inputs) must not produce NaNs
+    direction.normalize_or_zero() * MOVE_SPEED
+}
+
+/// Whether any of the movement inputs is pressed
+pub(crate) fn has_movement_input(input: &ActionState<Inputs>) -> bool {
+    [Inputs::Up, Inputs::Down, Inputs::Left, Inputs::Right]
+        .iter()
+        .any(|direction| input.pressed(direction))
+}
+
+/// Move the player towards `target` at `speed` per tick. Returns true once the target is reached
+pub(crate) fn move_towards(mut position: Mut<Position>, target: Vec2, speed: f32) -> bool {
+    let offset = target - position.0;
+    if offset.length() <= speed {
+        position.0 = target;
+        return true;
+    }
+    position.0 += offset.normalize() * speed;
+    false
+}
+
+/// Interpolated `Position` sampled once per fixed tick.
+/// Rendering blends between the last two samples so that the visuals are the same regardless
+/// of the client's frame rate
+#[derive(Component, Clone, Debug, PartialEq)]
+pub(crate) struct FixedTickPosition {
+    pub previous: Vec2,
+    pub current: Vec2,
+    /// The interpolated `Position` at the last sample
+    pub received: Vec2,
+    /// Number of ticks for which the interpolated `Position` hasn't moved
+    pub stalled_ticks: u32,
+}
+
+impl FixedTickPosition {
+    pub(crate) fn new(position: Vec2) -> Self {
+        Self {
+            previous: position,
+            current: position,
+            received: position,
+            stalled_ticks: 0,
+        }
+    }
+
+    /// Blend between the last two fixed samples. `overstep` is the fraction of a fixed tick
+    /// that has elapsed since the last sample
+    pub(crate) fn blend(&self, overstep: f32) -> Vec2 {
+        self.previous.lerp(self.current, overstep.clamp(0.0, 1.0))
+    }
+
+    /// Take a new sample of the interpolated `position`. While it doesn't move, the entity is
+    /// extrapolated with its `velocity` for at most `max_ticks`, then holds its position
+    pub(crate) fn sample(&mut self, position: Vec2, velocity: Vec2, max_ticks: u32) {
+        self.previous = self.current;
+        if position != self.received {
+            self.received = position;
+            self.current = position;
+            self.stalled_ticks = 0;
+        } else if self.stalled_ticks < max_ticks {
+            self.stalled_ticks += 1;
+            self.current += velocity;
+        }
     }
 }
 
+/// Position at which a predicted entity is drawn
+#[derive(Component, Clone, Debug, PartialEq)]
+pub(crate) struct SmoothedPosition {
+    pub rendered: Vec2,
+    /// Visual error that hasn't been corrected yet: `rendered - position`
+    pub error: Vec2,
+    /// The predicted position at the end of the previous tick
+    pub last: Vec2,
+}
+
+/// Visual state of a circle, from 0.0 (inactive) to 1.0 (active)
+#[derive(Component, Clone, Debug, PartialEq)]
+pub(crate) struct CircleFade {
+    pub value: f32,
+    pub target: f32,
+}
+
+/// Saturation used to draw a player, so that the predicted and interpolated copies can be told apart.
+/// This is kept separate from `PlayerColor`, so that the replicated color stays identical to the
+/// server's on every client
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DrawSaturation(pub f32);
+
 /// System that draws the boxed of the player positions.
 /// The components should be replicated from the server to the client
 /// This time we will only draw the predicted/interpolated entities
+/// Interpolated entities are drawn from their fixed-tick samples so that they move the same at any frame rate,
+/// and predicted entities from their smoothed position so that corrections don't teleport them
 pub(crate) fn draw_boxes(
     mut gizmos: Gizmos,
-    mut players: Query<(&Position, &mut Transform, &PlayerColor), Without<Confirmed>>,
+    fixed_time: Res<Time<Fixed>>,
+    mut players: Query<
+        (
+            &Position,
+            Option<&FixedTickPosition>,
+            Option<&SmoothedPosition>,
+            &mut Transform,
+            &PlayerColor,
+            Option<&DrawSaturation>,
+        ),
+        Without<Confirmed>,
+    >,
 ) {
-    for (position, mut transform, color) in players.iter_mut() {
+    for (position, fixed_position, smoothed_position, mut transform, color, saturation) in
+        players.iter_mut()
+    {
+        let position = match (fixed_position, smoothed_position) {
+            (Some(fixed_position), _) => fixed_position.blend(fixed_time.overstep_fraction()),
+            (None, Some(smoothed_position)) => smoothed_position.rendered,
+            (None, None) => position.0,
+        };
+        let color = match saturation {
+            Some(saturation) => Color::from(Hsva {
+                saturation: saturation.0,
+                ..Hsva::from(color.0)
+            }),
+            None => color.0,
+        };
         gizmos.rect(
             Vec3::new(position.x, position.y, 0.0),
             Quat::IDENTITY,
             Vec2::ONE * 50.0,
-            color.0,
+            color,
         );
         transform.translation = Vec3::new(position.x, position.y, 0.0);
     }
 }
 
 /// System that draws circles
-pub(crate) fn draw_circles(mut gizmos: Gizmos, circles: Query<&Position, With<CircleMarker>>) {
-    for position in &circles {
-        gizmos.circle_2d(*position.deref(), 1.0, GREEN);
+/// Inactive circles are drawn faded out
+pub(crate) fn draw_circles(
+    mut gizmos: Gizmos,
+    circles: Query<(&Position, Option<&CircleFade>), With<CircleMarker>>,
+) {
+    for (position, fade) in &circles {
+        let alpha = fade.map_or(1.0, |fade| fade.value);
+        gizmos.circle_2d(*position.deref(), 1.0, GREEN.with_alpha(alpha));
     }
 }
 
+/// Default size of a room along both axes. The circles are spawned with the same spacing
+pub(crate) const ROOM_SIZE: f32 = 200.0;
+
+/// Get the room that contains the given position, for rooms of `room_size` along both axes.
+/// Rooms are the cells of a 2D grid, so players that are far apart on the y axis are in
+/// different rooms
+pub(crate) fn room_id_for_position(position: Vec2, room_size: f32) -> RoomId {
+    // `floor` instead of truncating with a cast, otherwise the rooms around 0 are twice as large
+    let cell = (position / room_size).floor().as_ivec2();
+    room_id_for_cell(cell)
+}
+
+/// The `RoomId` of a cell is the Morton (Z-order) encoding of its coordinates,
+/// so that every cell gets a unique id that can be decoded back with `room_cell`
+pub(crate) fn room_id_for_cell(cell: IVec2) -> RoomId {
+    RoomId(morton_encode(cell.x, cell.y))
+}
+
+/// The cell coordinates of a room, useful for debugging
+pub(crate) fn room_cell(room: RoomId) -> IVec2 {
+    let (x, y) = morton_decode(room.0);
+    IVec2::new(x, y)
+}
+
+/// Interleave the bits of two signed integers into a u64.
+/// The integers are zigzag-encoded first so that small negative values also give small ids
+pub(crate) fn morton_encode(x: i32, y: i32) -> u64 {
+    spread_bits(zigzag_encode(x)) | (spread_bits(zigzag_encode(y)) << 1)
+}
+
+pub(crate) fn morton_decode(code: u64) -> (i32, i32) {
+    (
+        zigzag_decode(compact_bits(code)),
+        zigzag_decode(compact_bits(code >> 1)),
+    )
+}
+
+fn zigzag_encode(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn zigzag_decode(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+/// Insert a 0 bit between each bit of `value`
+fn spread_bits(value: u32) -> u64 {
+    let mut x = value as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+    x
+}
+
+/// Inverse of `spread_bits`: keep every other bit of `value`
+fn compact_bits(value: u64) -> u32 {
+    let mut x = value & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x >> 16)) & 0x0000_0000_FFFF_FFFF;
+    x as u32
+}
+
 /// Generate a color from the `ClientId`
 pub(crate) fn color_from_id(client_id: ClientId) -> Color {
     let h = (((client_id.to_bits().wrapping_mul(30)) % 360) as f32) / 360.0;
@@ -76,3 +315,133 @@ pub(crate) fn color_from_id(client_id: ClientId) -> Color {
     let l = 0.5;
     Color::hsl(h, s, l)
 }
+
+/// Generate a color from a team. The hues are spaced by the golden angle, so that the first
+/// teams get clearly different colors
+pub(crate) fn color_from_team(team: u8) -> Color {
+    let h = (team as f32 * 137.5) % 360.0;
+    let s = 1.0;
+    let l = 0.5;
+    Color::hsl(h, s, l)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the fixed-tick samples of an entity moving by 10 per tick, at `frame_micros` per frame.
+    /// Returns the time of each frame in microseconds, with the rendered x
+    fn render_at(frame_micros: u64) -> Vec<(u64, f32)> {
+        const TICK_MICROS: u64 = 15_625;
+        let mut fixed = FixedTickPosition::new(Vec2::ZERO);
+        let (mut ticks, mut accumulated) = (0, 0);
+        (1..=1_000_000 / frame_micros)
+            .map(|frame| {
+                accumulated += frame_micros;
+                while accumulated >= TICK_MICROS {
+                    accumulated -= TICK_MICROS;
+                    ticks += 1;
+                    fixed.sample(Vec2::new(ticks as f32 * 10.0, 0.0), Vec2::ZERO, 0);
+                }
+                let overstep = accumulated as f32 / TICK_MICROS as f32;
+                (frame * frame_micros, fixed.blend(overstep).x)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed_tick_positions_move_the_same_at_any_frame_rate() {
+        for frame_micros in [4_000, 8_000, 25_000] {
+            // one tick behind the latest sample, whatever the frame rate
+            for (time, x) in render_at(frame_micros) {
+                let expected = (time as f32 / 15_625.0 - 1.0).max(0.0) * 10.0;
+                assert!((x - expected).abs() < 1e-3, "{} instead of {} at {}us", x, expected, time);
+            }
+        }
+    }
+
+    #[test]
+    fn morton_codes_decode_back_to_their_coordinates() {
+        for (x, y) in [
+            (0, 0),
+            (1, 0),
+            (0, 1),
+            (-1, -1),
+            (-7, 12),
+            (1000, -1000),
+            (i32::MAX, i32::MIN),
+            (i32::MIN, i32::MAX),
+        ] {
+            assert_eq!(morton_decode(morton_encode(x, y)), (x, y));
+        }
+    }
+
+    #[test]
+    fn morton_codes_are_unique_and_small_around_the_origin() {
+        let codes: bevy::utils::HashSet<u64> = (-8..8)
+            .flat_map(|x| (-8..8).map(move |y| morton_encode(x, y)))
+            .collect();
+        assert_eq!(codes.len(), 16 * 16);
+        // zigzag encoding keeps the negative cells next to the positive ones
+        assert!(codes.iter().all(|code| *code < 16 * 16));
+        assert_eq!(morton_encode(0, 0), 0);
+    }
+
+    #[test]
+    fn rooms_are_the_cells_of_a_2d_grid() {
+        let room = |x: f32, y: f32| room_cell(room_id_for_position(Vec2::new(x, y), ROOM_SIZE));
+        assert_eq!(room(0.0, 0.0), IVec2::new(0, 0));
+        assert_eq!(room(199.9, 199.9), IVec2::new(0, 0));
+        assert_eq!(room(200.0, 0.0), IVec2::new(1, 0));
+        // the rooms around 0 are not twice as large
+        assert_eq!(room(-0.1, 0.0), IVec2::new(-1, 0));
+        assert_eq!(room(-200.0, -200.1), IVec2::new(-1, -2));
+        // players far apart on the y axis are in different rooms
+        assert_ne!(
+            room_id_for_position(Vec2::new(50.0, 50.0), ROOM_SIZE),
+            room_id_for_position(Vec2::new(50.0, 850.0), ROOM_SIZE)
+        );
+    }
+
+    fn pressed(inputs: &[Inputs]) -> ActionState<Inputs> {
+        let mut action_state = ActionState::default();
+        for input in inputs {
+            action_state.press(input);
+        }
+        action_state
+    }
+
+    #[test]
+    fn held_inputs_accelerate_towards_the_move_speed() {
+        let input = pressed(&[Inputs::Right]);
+        let velocity = next_velocity(Vec2::ZERO, &input, 1.0);
+        assert_eq!(velocity, Vec2::new(MOVE_SPEED * (1.0 - FRICTION), 0.0));
+        let velocity = (0..100).fold(velocity, |velocity, _| next_velocity(velocity, &input, 1.0));
+        assert!((velocity - Vec2::new(MOVE_SPEED, 0.0)).length() < 1e-3);
+        // slow rooms lower the speed that the inputs push towards
+        let velocity = (0..100).fold(velocity, |velocity, _| next_velocity(velocity, &input, 0.5));
+        assert!((velocity - Vec2::new(MOVE_SPEED * 0.5, 0.0)).length() < 1e-3);
+    }
+
+    #[test]
+    fn released_players_slow_down_until_they_stop() {
+        let input = pressed(&[]);
+        let velocity = next_velocity(Vec2::new(MOVE_SPEED, 0.0), &input, 1.0);
+        assert_eq!(velocity, Vec2::new(MOVE_SPEED * FRICTION, 0.0));
+        // the velocity doesn't decay forever
+        let velocity = (0..100).fold(velocity, |velocity, _| next_velocity(velocity, &input, 1.0));
+        assert_eq!(velocity, Vec2::ZERO);
+    }
+
+    #[test]
+    fn diagonal_movement_is_not_faster() {
+        assert_eq!(movement_delta(&pressed(&[Inputs::Up])), Vec2::new(0.0, MOVE_SPEED));
+        assert_eq!(movement_delta(&pressed(&[Inputs::Left])), Vec2::new(-MOVE_SPEED, 0.0));
+        let diagonal = movement_delta(&pressed(&[Inputs::Down, Inputs::Right]));
+        assert!((diagonal.length() - MOVE_SPEED).abs() < 1e-4);
+        assert!(diagonal.x > 0.0 && diagonal.y < 0.0);
+        // opposite inputs cancel out without producing NaNs
+        assert_eq!(movement_delta(&pressed(&[Inputs::Up, Inputs::Down])), Vec2::ZERO);
+        assert_eq!(movement_delta(&pressed(&[])), Vec2::ZERO);
+    }
+}
@@ -0,0 +1,216 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use lightyear::prelude::server::ReplicationTarget;
+
+use crate::protocol::*;
+
+/// Spatial hash of the circles that are relevant depending on their distance to the players, so
+/// that we can find the circles close to a position without iterating through all of them.
+/// It is updated incrementally by `update_circle_index`: only the circles that were spawned, moved
+/// or despawned since its last run are touched
+#[derive(Resource)]
+pub(crate) struct CircleSpatialIndex {
+    cell_size: f32,
+    cells: HashMap<IVec2, HashSet<Entity>>,
+    entities: HashMap<Entity, (IVec2, Vec2)>,
+}
+
+impl CircleSpatialIndex {
+    pub(crate) fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::default(),
+            entities: HashMap::default(),
+        }
+    }
+
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Insert an entity in the index, or move it if it was already present
+    pub(crate) fn insert(&mut self, entity: Entity, position: Vec2) {
+        let cell = self.cell(position);
+        if let Some((old_cell, _)) = self.entities.insert(entity, (cell, position)) {
+            if old_cell == cell {
+                return;
+            }
+            self.remove_from_cell(entity, old_cell);
+        }
+        self.cells.entry(cell).or_default().insert(entity);
+    }
+
+    pub(crate) fn remove(&mut self, entity: Entity) {
+        if let Some((cell, _)) = self.entities.remove(&entity) {
+            self.remove_from_cell(entity, cell);
+        }
+    }
+
+    fn remove_from_cell(&mut self, entity: Entity, cell: IVec2) {
+        if let Some(entities) = self.cells.get_mut(&cell) {
+            entities.remove(&entity);
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Iterate through the entities that are strictly within `radius` of `center`
+    pub(crate) fn query_radius(&self, center: Vec2, radius: f32) -> impl Iterator<Item = Entity> + '_ {
+        let min = self.cell(center - Vec2::splat(radius));
+        let max = self.cell(center + Vec2::splat(radius));
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(move |entity| self.entities[entity].1.distance(center) < radius)
+    }
+}
+
+/// Keep the index up-to-date with the circles that were spawned, moved or despawned.
+/// The players move all the time but are not in the index, and the circles that didn't move are
+/// not touched
+pub(crate) fn update_circle_index(
+    mut index: ResMut<CircleSpatialIndex>,
+    mut removed: RemovedComponents<ReplicationCategory>,
+    circles: Query<
+        (Entity, &Position, &ReplicationCategory),
+        (With<ReplicationTarget>, Changed<Position>),
+    >,
+) {
+    for entity in removed.read() {
+        index.remove(entity);
+    }
+    for (entity, position, category) in circles.iter() {
+        if category.is_distance_culled() {
+            index.insert(entity, position.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashSet;
+
+    use super::*;
+
+    fn circle(position: Vec2) -> impl Bundle {
+        (
+            Position(position),
+            CircleMarker,
+            ReplicationCategory::Circle,
+            ReplicationTarget::default(),
+        )
+    }
+
+    #[test]
+    fn query_radius_finds_the_same_entities_as_a_linear_scan() {
+        let mut world = World::new();
+        let circles: Vec<(Entity, Vec2)> = (-5..5)
+            .flat_map(|x| (-5..5).map(move |y| Vec2::new(x as f32 * 37.0, y as f32 * 53.0)))
+            .map(|position| (world.spawn_empty().id(), position))
+            .collect();
+        let mut index = CircleSpatialIndex::new(50.0);
+        for (entity, position) in circles.iter() {
+            index.insert(*entity, *position);
+        }
+        assert_eq!(index.len(), circles.len());
+
+        for (center, radius) in [
+            (Vec2::ZERO, 100.0),
+            (Vec2::new(-120.0, 80.0), 60.0),
+            (Vec2::ZERO, 0.0),
+        ] {
+            let found: HashSet<Entity> = index.query_radius(center, radius).collect();
+            let expected: HashSet<Entity> = circles
+                .iter()
+                .filter(|(_, position)| position.distance(center) < radius)
+                .map(|(entity, _)| *entity)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn query_radius_is_exclusive() {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        let mut index = CircleSpatialIndex::new(50.0);
+        index.insert(entity, Vec2::new(100.0, 0.0));
+        assert_eq!(index.query_radius(Vec2::ZERO, 100.0).count(), 0);
+        assert_eq!(index.query_radius(Vec2::ZERO, 100.5).count(), 1);
+    }
+
+    #[test]
+    fn the_index_follows_the_spawned_moved_and_despawned_circles() {
+        let mut world = World::new();
+        world.insert_resource(CircleSpatialIndex::new(100.0));
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_circle_index);
+
+        let a = world.spawn(circle(Vec2::ZERO)).id();
+        let b = world.spawn(circle(Vec2::new(500.0, 0.0))).id();
+        schedule.run(&mut world);
+        let near_origin = |world: &World| -> Vec<Entity> {
+            world
+                .resource::<CircleSpatialIndex>()
+                .query_radius(Vec2::ZERO, 10.0)
+                .collect()
+        };
+        assert_eq!(near_origin(&world), vec![a]);
+
+        world.get_mut::<Position>(b).unwrap().0 = Vec2::new(1.0, 0.0);
+        schedule.run(&mut world);
+        assert_eq!(near_origin(&world).len(), 2);
+
+        world.despawn(a);
+        schedule.run(&mut world);
+        assert_eq!(near_origin(&world), vec![b]);
+        assert_eq!(world.resource::<CircleSpatialIndex>().len(), 1);
+    }
+
+    #[test]
+    fn the_index_stays_correct_when_only_some_circles_move() {
+        let mut world = World::new();
+        world.insert_resource(CircleSpatialIndex::new(100.0));
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_circle_index);
+        let circles: Vec<Entity> = (0..20)
+            .map(|i| world.spawn(circle(Vec2::new(i as f32 * 40.0, 0.0))).id())
+            .collect();
+        schedule.run(&mut world);
+
+        // every third circle moves to another cell, and another one moves within its cell
+        for (i, entity) in circles.iter().enumerate().step_by(3) {
+            world.get_mut::<Position>(*entity).unwrap().0 = Vec2::new(i as f32 * 40.0, 250.0);
+        }
+        world.get_mut::<Position>(circles[1]).unwrap().0.y = 10.0;
+        schedule.run(&mut world);
+
+        let mut positions = world.query::<(Entity, &Position)>();
+        let positions: Vec<(Entity, Vec2)> = positions
+            .iter(&world)
+            .map(|(entity, position)| (entity, position.0))
+            .collect();
+        let index = world.resource::<CircleSpatialIndex>();
+        assert_eq!(index.len(), circles.len());
+        for (center, radius) in [
+            (Vec2::ZERO, 150.0),
+            (Vec2::new(300.0, 250.0), 120.0),
+            (Vec2::new(400.0, 100.0), 200.0),
+        ] {
+            let found: HashSet<Entity> = index.query_radius(center, radius).collect();
+            let expected: HashSet<Entity> = positions
+                .iter()
+                .filter(|(_, position)| position.distance(center) < radius)
+                .map(|(entity, _)| *entity)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
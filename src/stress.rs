@@ -0,0 +1,171 @@
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::diagnostic::DiagnosticsStore;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::utils::{Duration, HashMap};
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::diagnostics::{interest_management_average_ms, RelevanceMetrics};
+use crate::protocol::*;
+use crate::rooms::RoomRegistry;
+use crate::server::{ExampleServerPlugin, RelevantCircles};
+use crate::shared::SharedPlugin;
+
+pub(crate) const DEFAULT_NUM_CLIENTS: usize = 50;
+/// How long the stress test runs before printing the report
+const STRESS_TEST_DURATION: Duration = Duration::from_secs(30);
+/// Seed of the random movement, so that runs can be compared
+const STRESS_TEST_SEED: u64 = 42;
+/// Number of fixed ticks during which a simulated client keeps going in the same direction
+const DIRECTION_CHANGE_TICKS: u32 = 32;
+
+/// Run a headless server with `num_clients` simulated clients moving randomly,
+/// then print a summary of the interest management load.
+/// The clients are simulated on the server, nothing goes through the network: the summary only
+/// covers the server-side cost of the relevance, not the bandwidth
+pub(crate) fn run(num_clients: usize) {
+    App::new()
+        .add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
+            Duration::from_secs_f64(1.0 / 60.0),
+        )))
+        .add_plugins((LogPlugin::default(), StatesPlugin))
+        .add_plugins(ServerPlugins::new(ServerConfig::default()))
+        .add_plugins((SharedPlugin, ExampleServerPlugin))
+        .add_plugins(StressTestPlugin { num_clients })
+        .run();
+}
+
+pub(crate) struct StressTestPlugin {
+    pub num_clients: usize,
+}
+
+impl Plugin for StressTestPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(StressTest {
+            num_clients: self.num_clients,
+            rng: StdRng::seed_from_u64(STRESS_TEST_SEED),
+            ticks: 0,
+            directions: HashMap::default(),
+            timer: Timer::new(STRESS_TEST_DURATION, TimerMode::Once),
+            peak_churn_per_second: 0,
+        });
+        app.add_systems(Startup, connect_simulated_clients);
+        app.add_systems(FixedPreUpdate, move_simulated_clients);
+        app.add_systems(Update, report);
+    }
+}
+
+#[derive(Resource)]
+pub(crate) struct StressTest {
+    num_clients: usize,
+    rng: StdRng,
+    ticks: u32,
+    directions: HashMap<ClientId, Inputs>,
+    timer: Timer,
+    /// Highest number of relevance gains and losses in one second
+    peak_churn_per_second: usize,
+}
+
+/// The simulated clients don't go through the network: we emit their `ConnectEvent`s directly,
+/// so that the server spawns their players through the usual `handle_connections` path
+pub(crate) fn connect_simulated_clients(
+    stress_test: Res<StressTest>,
+    mut connections: EventWriter<ConnectEvent>,
+) {
+    for i in 0..stress_test.num_clients {
+        connections.send(ConnectEvent {
+            client_id: ClientId::Local(i as u64),
+        });
+    }
+}
+
+/// Every `DIRECTION_CHANGE_TICKS`, each simulated client picks a new random direction
+pub(crate) fn move_simulated_clients(
+    mut stress_test: ResMut<StressTest>,
+    mut players: Query<(&PlayerId, &mut ActionState<Inputs>)>,
+) {
+    const DIRECTIONS: [Inputs; 4] = [Inputs::Up, Inputs::Down, Inputs::Left, Inputs::Right];
+    stress_test.ticks += 1;
+    let change_direction = stress_test.ticks % DIRECTION_CHANGE_TICKS == 1;
+    let StressTest { rng, directions, .. } = &mut *stress_test;
+    for (player_id, mut action_state) in players.iter_mut() {
+        if change_direction || !directions.contains_key(&player_id.0) {
+            directions.insert(player_id.0, DIRECTIONS[rng.gen_range(0..DIRECTIONS.len())]);
+        }
+        for direction in DIRECTIONS {
+            action_state.release(&direction);
+        }
+        action_state.press(&directions[&player_id.0]);
+    }
+}
+
+/// Print the summary once the stress test is over, and exit
+pub(crate) fn report(
+    mut stress_test: ResMut<StressTest>,
+    relevant_circles: Res<RelevantCircles>,
+    room_registry: Res<RoomRegistry>,
+    metrics: Res<RelevanceMetrics>,
+    diagnostics: Res<DiagnosticsStore>,
+    time: Res<Time>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let churn = metrics.last_second.gains + metrics.last_second.losses;
+    stress_test.peak_churn_per_second = stress_test.peak_churn_per_second.max(churn);
+    stress_test.timer.tick(time.delta());
+    if !stress_test.timer.just_finished() {
+        return;
+    }
+    let counts: Vec<usize> = relevant_circles.0.values().map(|circles| circles.len()).collect();
+    let total: usize = counts.iter().sum();
+    let average = total as f32 / counts.len().max(1) as f32;
+    info!(
+        clients = stress_test.num_clients,
+        live_rooms = room_registry.len(),
+        peak_churn_per_second = stress_test.peak_churn_per_second,
+        "Stress test finished after {:?}",
+        STRESS_TEST_DURATION
+    );
+    if let Some(average) = interest_management_average_ms(&diagnostics) {
+        info!("Time in interest_management: {:.3}ms on average", average);
+    }
+    info!(
+        "Relevant circles per client: min {}, average {:.1}, max {}",
+        counts.iter().min().copied().unwrap_or_default(),
+        average,
+        counts.iter().max().copied().unwrap_or_default(),
+    );
+    exit.send(AppExit::Success);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Global;
+    use crate::test_utils::{server_app_with, step};
+
+    #[test]
+    fn the_simulated_clients_connect_and_move() {
+        let mut app = server_app_with(|app| {
+            app.add_plugins(StressTestPlugin { num_clients: 5 });
+        });
+        step(&mut app, 20);
+        let players: Vec<Entity> = app
+            .world()
+            .resource::<Global>()
+            .client_id_to_entity_id
+            .values()
+            .copied()
+            .collect();
+        assert_eq!(players.len(), 5);
+        assert_eq!(app.world().resource::<StressTest>().directions.len(), 5);
+        for player in players {
+            let position = app.world().get::<Position>(player).unwrap().0;
+            assert_ne!(position, Vec2::ZERO);
+        }
+    }
+}
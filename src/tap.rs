@@ -0,0 +1,209 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use lightyear::prelude::server::*;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Position;
+use crate::send_rate::is_send_frame;
+use crate::server::ServerTicks;
+
+// Plugin that records the replication updates handed to lightyear, for debugging
+pub struct ReplicationTapPlugin;
+
+impl Plugin for ReplicationTapPlugin {
+    fn build(&self, app: &mut App) {
+        // insert an enabled `ReplicationTap` before adding the plugin to start recording
+        app.init_resource::<ReplicationTap>();
+        app.add_systems(
+            PostUpdate,
+            record_replication
+                .run_if(|tap: Res<ReplicationTap>| tap.enabled)
+                .run_if(is_send_frame)
+                .before(MainSet::Send),
+        );
+    }
+}
+
+/// A replication update recorded by the `ReplicationTap`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct TapRecord {
+    /// Server tick of the frame where the update was sent
+    pub tick: u64,
+    pub entity: Entity,
+    pub event: TapEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) enum TapEvent {
+    /// The entity started being replicated, at this position
+    Spawn { position: Option<Vec2> },
+    /// The `Position` of the entity changed
+    Position(Vec2),
+    /// The entity stopped being replicated
+    Despawn,
+}
+
+/// Records the replication updates sent at the end of each send frame, as bincode-encoded
+/// `TapRecord`s. Use `decode_tap` to read them back.
+/// The records describe what is handed to lightyear's replication, before the per-client relevance
+/// filtering: combine them with the rooms and `RelevantCircles` to know what each client received
+#[derive(Resource, Default)]
+pub(crate) struct ReplicationTap {
+    pub enabled: bool,
+    /// When set, the records are also appended to this file
+    pub path: Option<PathBuf>,
+    buffer: Vec<u8>,
+}
+
+impl ReplicationTap {
+    /// The records encoded since the tap was created or last taken
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Take the encoded records, leaving the buffer empty
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+fn encode(record: &TapRecord) -> Vec<u8> {
+    bincode::serde::encode_to_vec(record, bincode::config::standard())
+        .expect("a tap record can always be encoded")
+}
+
+/// Decode the records written by the `ReplicationTap`, in the order they were recorded
+pub(crate) fn decode_tap(
+    mut bytes: &[u8],
+) -> Result<Vec<TapRecord>, bincode::error::DecodeError> {
+    let mut records = Vec::new();
+    while !bytes.is_empty() {
+        let (record, read) = bincode::serde::decode_from_slice::<TapRecord, _>(
+            bytes,
+            bincode::config::standard(),
+        )?;
+        records.push(record);
+        bytes = &bytes[read..];
+    }
+    Ok(records)
+}
+
+/// Record the entities that start or stop being replicated, and the positions that changed since
+/// the last send frame
+pub(crate) fn record_replication(
+    mut tap: ResMut<ReplicationTap>,
+    ticks: Res<ServerTicks>,
+    mut removed: RemovedComponents<ReplicationTarget>,
+    replicated: Query<(Entity, Ref<ReplicationTarget>, Option<Ref<Position>>)>,
+) {
+    let mut frame = Vec::new();
+    for (entity, target, position) in replicated.iter() {
+        let event = if target.is_added() {
+            TapEvent::Spawn {
+                position: position.map(|position| position.0),
+            }
+        } else {
+            match position {
+                Some(position) if position.is_changed() => TapEvent::Position(position.0),
+                _ => continue,
+            }
+        };
+        frame.extend(encode(&TapRecord {
+            tick: ticks.tick,
+            entity,
+            event,
+        }));
+    }
+    for entity in removed.read() {
+        frame.extend(encode(&TapRecord {
+            tick: ticks.tick,
+            entity,
+            event: TapEvent::Despawn,
+        }));
+    }
+    if frame.is_empty() {
+        return;
+    }
+    if let Some(path) = &tap.path {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&frame));
+        if let Err(e) = written {
+            warn!("Could not write the replication tap to {:?}: {}", path, e);
+        }
+    }
+    tap.buffer.extend(frame);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Inputs;
+    use crate::test_utils::{connect, press, server_app_with, step};
+
+    #[test]
+    fn decoded_records_are_the_recorded_ones() {
+        let records = vec![
+            TapRecord {
+                tick: 1,
+                entity: Entity::from_raw(3),
+                event: TapEvent::Spawn {
+                    position: Some(Vec2::new(1.0, 2.0)),
+                },
+            },
+            TapRecord {
+                tick: 2,
+                entity: Entity::from_raw(3),
+                event: TapEvent::Position(Vec2::new(1.5, 2.0)),
+            },
+            TapRecord {
+                tick: 2,
+                entity: Entity::from_raw(4),
+                event: TapEvent::Despawn,
+            },
+        ];
+        let bytes: Vec<u8> = records.iter().flat_map(encode).collect();
+        assert_eq!(decode_tap(&bytes).unwrap(), records);
+        assert_eq!(decode_tap(&[]).unwrap(), vec![]);
+        // a truncated record is an error, not a partial result
+        assert!(decode_tap(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn the_tap_records_the_spawn_and_moves_of_the_players() {
+        let mut app = server_app_with(|app| {
+            let mut tap = ReplicationTap::default();
+            tap.enabled = true;
+            app.insert_resource(tap);
+        });
+        let client_id = ClientId::Netcode(1);
+        let player = connect(&mut app, client_id).unwrap();
+        press(&mut app, client_id, Inputs::Right);
+        step(&mut app, 5);
+
+        let records = decode_tap(app.world().resource::<ReplicationTap>().bytes()).unwrap();
+        let events: Vec<_> = records
+            .iter()
+            .filter(|record| record.entity == player)
+            .map(|record| &record.event)
+            .collect();
+        assert!(matches!(events[0], TapEvent::Spawn { position: Some(_) }));
+        let xs: Vec<_> = events[1..]
+            .iter()
+            .map(|event| match event {
+                TapEvent::Position(position) => position.x,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert!(!xs.is_empty());
+        assert!(xs.windows(2).all(|pair| pair[0] < pair[1]));
+        // the ticks never go back
+        assert!(records.windows(2).all(|pair| pair[0].tick <= pair[1].tick));
+    }
+}
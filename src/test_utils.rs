@@ -0,0 +1,285 @@
+use std::net::SocketAddr;
+
+use bevy::ecs::system::RunSystemOnce;
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::utils::Duration;
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::client::{
+    Authentication, ClientCommands, ClientConfig, ClientPlugins, ClientTransport, Confirmed,
+    NetworkingState,
+};
+use lightyear::prelude::server::{ServerCommands, ServerConfig, ServerPlugins, ServerTransport};
+use lightyear::prelude::*;
+
+use crate::protocol::*;
+use crate::replay::Replay;
+use crate::server::{ExampleServerPlugin, Global};
+use crate::shared::SharedPlugin;
+
+/// Duration of a frame, and of a fixed tick, in the test apps
+pub(crate) const FRAME_DURATION: Duration = Duration::from_millis(16);
+/// Number of frames after which `Stepper::new` gives up on connecting the clients
+const MAX_CONNECTION_FRAMES: usize = 200;
+
+fn shared_config() -> SharedConfig {
+    SharedConfig {
+        tick: TickConfig::new(FRAME_DURATION),
+        mode: Mode::Separate,
+        ..default()
+    }
+}
+
+/// A server app without any transport. The clients are connected with `connect`, which emits
+/// their `ConnectEvent` directly, like the simulated clients of the stress test
+pub(crate) fn server_app() -> App {
+    server_app_with(|_| {})
+}
+
+/// Same as `server_app`, but `configure` can insert resources before the plugins are added
+pub(crate) fn server_app_with(configure: impl FnOnce(&mut App)) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, StatesPlugin));
+    configure(&mut app);
+    app.add_plugins(ServerPlugins::new(ServerConfig {
+        shared: shared_config(),
+        ..default()
+    }));
+    app.add_plugins((SharedPlugin, ExampleServerPlugin));
+    app.finish();
+    app.cleanup();
+    app
+}
+
+/// Connect a client with the given id and run a frame, so that it gets a player if it is admitted.
+/// Returns its player entity, if any.
+/// The ids are chosen by the test, so they are the same from run to run
+pub(crate) fn connect(app: &mut App, client_id: ClientId) -> Option<Entity> {
+    app.world_mut().send_event(server::ConnectEvent { client_id });
+    app.update();
+    player_of(app, client_id)
+}
+
+/// The player entity of a client on the server
+pub(crate) fn player_of(app: &App, client_id: ClientId) -> Option<Entity> {
+    app.world()
+        .resource::<Global>()
+        .client_id_to_entity_id
+        .get(&client_id)
+        .copied()
+}
+
+/// Hold `input` on the player of a client, as if the client had sent it
+pub(crate) fn press(app: &mut App, client_id: ClientId, input: Inputs) {
+    let player = player_of(app, client_id).expect("the client has a player");
+    app.world_mut()
+        .get_mut::<ActionState<Inputs>>(player)
+        .expect("players have an ActionState")
+        .press(&input);
+}
+
+/// Stop holding `input` on the player of a client
+pub(crate) fn release(app: &mut App, client_id: ClientId, input: Inputs) {
+    let player = player_of(app, client_id).expect("the client has a player");
+    app.world_mut()
+        .get_mut::<ActionState<Inputs>>(player)
+        .expect("players have an ActionState")
+        .release(&input);
+}
+
+/// Run `frames` frames, waiting `FRAME_DURATION` before each so that the fixed schedules run once
+/// per frame
+pub(crate) fn step(app: &mut App, frames: usize) {
+    for _ in 0..frames {
+        std::thread::sleep(FRAME_DURATION);
+        app.update();
+    }
+}
+
+/// Play `replay` on the server: its clients connect as `ClientId::Netcode(1)`, `Netcode(2)`, ...,
+/// then each tick of the replay is run as one frame, holding the inputs of the events up to that
+/// tick. The inputs are pressed again every frame, like a client sends them every tick.
+/// `inspect` is called after every frame. Returns the ids of the clients
+pub(crate) fn play_replay(
+    app: &mut App,
+    replay: &Replay,
+    mut inspect: impl FnMut(&App),
+) -> Vec<ClientId> {
+    let client_ids: Vec<ClientId> = (1..=replay.num_clients() as u64)
+        .map(ClientId::Netcode)
+        .collect();
+    for client_id in client_ids.iter() {
+        connect(app, *client_id).expect("the replay clients are admitted");
+    }
+    let mut held = vec![InputSnapshot::default(); client_ids.len()];
+    let mut events = replay.events.iter().peekable();
+    for tick in 0..=replay.last_tick() {
+        while let Some(event) = events.next_if(|event| event.tick == tick) {
+            held[event.client] = event.snapshot;
+        }
+        for (client_id, snapshot) in client_ids.iter().zip(held.iter()) {
+            for input in Inputs::ALL {
+                if snapshot.is_pressed(input) {
+                    press(app, *client_id, input);
+                } else {
+                    release(app, *client_id, input);
+                }
+            }
+        }
+        step(app, 1);
+        inspect(app);
+    }
+    client_ids
+}
+
+/// A server app and client apps connected to it through in-memory channels.
+/// The client apps only have the lightyear plugins and the `SharedPlugin`: they receive the
+/// replication but don't run the rendering and input systems of the `ExampleClientPlugin`
+pub(crate) struct Stepper {
+    pub server_app: App,
+    pub client_apps: Vec<(ClientId, App)>,
+}
+
+impl Stepper {
+    /// Start the server and connect one client for each id.
+    /// Panics if the clients are not connected after `MAX_CONNECTION_FRAMES` frames
+    pub(crate) fn new(client_ids: &[u64]) -> Self {
+        Self::with(client_ids, |_| {})
+    }
+
+    /// Same as `new`, but `configure` can insert resources in the server and client apps before
+    /// the plugins are added
+    pub(crate) fn with(client_ids: &[u64], configure: impl Fn(&mut App)) -> Self {
+        let mut channels = Vec::new();
+        let mut client_apps = Vec::new();
+        for (i, id) in client_ids.iter().enumerate() {
+            let address = SocketAddr::from(([127, 0, 0, 1], 1000 + i as u16));
+            let (to_server_send, to_server_recv) = crossbeam_channel::unbounded();
+            let (to_client_send, to_client_recv) = crossbeam_channel::unbounded();
+            channels.push((address, to_server_recv, to_client_send));
+            let mut client_app = App::new();
+            client_app.add_plugins((MinimalPlugins, StatesPlugin, InputPlugin));
+            configure(&mut client_app);
+            client_app.add_plugins(ClientPlugins::new(ClientConfig {
+                shared: shared_config(),
+                net: client::NetConfig::Netcode {
+                    auth: Authentication::Manual {
+                        server_addr: address,
+                        client_id: *id,
+                        private_key: [0; 32],
+                        protocol_id: 0,
+                    },
+                    config: client::NetcodeConfig::default(),
+                    io: client::IoConfig::from_transport(ClientTransport::LocalChannel {
+                        recv: to_client_recv,
+                        send: to_server_send,
+                    }),
+                },
+                ..default()
+            }));
+            client_app.add_plugins(SharedPlugin);
+            client_app.finish();
+            client_app.cleanup();
+            client_apps.push((ClientId::Netcode(*id), client_app));
+        }
+
+        let mut server_app = App::new();
+        server_app.add_plugins((MinimalPlugins, StatesPlugin));
+        configure(&mut server_app);
+        server_app.add_plugins(ServerPlugins::new(ServerConfig {
+            shared: shared_config(),
+            net: vec![server::NetConfig::Netcode {
+                config: server::NetcodeConfig::default(),
+                io: server::IoConfig::from_transport(ServerTransport::Channels { channels }),
+            }],
+            ..default()
+        }));
+        server_app.add_plugins((SharedPlugin, ExampleServerPlugin));
+        server_app.finish();
+        server_app.cleanup();
+
+        let mut stepper = Self {
+            server_app,
+            client_apps,
+        };
+        stepper
+            .server_app
+            .world_mut()
+            .run_system_once(|mut commands: Commands| commands.start_server());
+        for (_, client_app) in stepper.client_apps.iter_mut() {
+            client_app
+                .world_mut()
+                .run_system_once(|mut commands: Commands| commands.connect_client());
+        }
+        for _ in 0..MAX_CONNECTION_FRAMES {
+            if stepper.all_connected() {
+                return stepper;
+            }
+            stepper.frame_step();
+        }
+        panic!("the clients could not connect to the server");
+    }
+
+    fn all_connected(&self) -> bool {
+        let server_players = &self.server_app.world().resource::<Global>().client_id_to_entity_id;
+        self.client_apps.iter().all(|(client_id, client_app)| {
+            client_app.world().resource::<State<NetworkingState>>().get()
+                == &NetworkingState::Connected
+                && server_players.contains_key(client_id)
+        })
+    }
+
+    pub(crate) fn client_app(&mut self, client_id: ClientId) -> &mut App {
+        self.client_apps
+            .iter_mut()
+            .find(|(id, _)| *id == client_id)
+            .map(|(_, app)| app)
+            .expect("the client is part of the stepper")
+    }
+
+    /// Run one frame on the server, then on every client
+    pub(crate) fn frame_step(&mut self) {
+        std::thread::sleep(FRAME_DURATION);
+        self.server_app.update();
+        for (_, client_app) in self.client_apps.iter_mut() {
+            client_app.update();
+        }
+    }
+
+    pub(crate) fn frame_steps(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.frame_step();
+        }
+    }
+
+    /// Assert that the client `observer` received the value of `C` that the server has on the
+    /// player of `player`.
+    /// The client entity is found through the replicated `PlayerId`, since the entity map of
+    /// lightyear's `ConnectionManager` is not public
+    pub(crate) fn assert_replicated<C: Component + Clone + PartialEq + std::fmt::Debug>(
+        &mut self,
+        observer: ClientId,
+        player: ClientId,
+    ) {
+        let server_entity = player_of(&self.server_app, player).expect("the player exists");
+        let expected = self
+            .server_app
+            .world()
+            .get::<C>(server_entity)
+            .expect("the server player has the component")
+            .clone();
+        let client_app = self.client_app(observer);
+        let mut players =
+            client_app
+                .world_mut()
+                .query_filtered::<(&PlayerId, Option<&C>), With<Confirmed>>();
+        let Some((_, actual)) = players
+            .iter(client_app.world())
+            .find(|(player_id, _)| player_id.0 == player)
+        else {
+            panic!("the player of {} was not replicated to {}", player, observer);
+        };
+        assert_eq!(actual, Some(&expected), "{} sees a different value", observer);
+    }
+}